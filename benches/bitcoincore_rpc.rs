@@ -2,6 +2,7 @@ use criterion::{criterion_group, criterion_main, Criterion};
 
 use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::hashes::hex::FromHex;
+use bitcoin_indexer::{node::rest::RestRpc, Rpc};
 use bitcoincore_rpc::RpcApi;
 
 fn get_rpc() -> bitcoincore_rpc::Client {
@@ -27,6 +28,12 @@ fn get_block(c: &mut Criterion) {
 
         b.iter(|| rpc.get_block(&hash).unwrap())
     });
+    c.bench_function("rest_block_bin", |b| {
+        let rpc = RestRpc::new("http://localhost:8332");
+        let hash = BlockHash::from_hex(BLOCK_HASH).unwrap();
+
+        b.iter(|| rpc.get_block_by_id(&hash).unwrap())
+    });
     c.bench_function("getrawtransaction", |b| {
         let rpc = get_rpc();
         let hash =
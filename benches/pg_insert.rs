@@ -0,0 +1,136 @@
+//! Bulk mode inserts: multi-value `INSERT`s vs binary `COPY`
+//!
+//! Only formatting is measured, unless `BENCH_DATABASE_URL` is set; then
+//! also writing to a `bench_pg_insert` schema there (rolled back every time).
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use bitcoin::{
+    blockdata::{
+        block::{Block, BlockHeader},
+        constants::genesis_block,
+        opcodes,
+        script::Builder,
+        transaction::{OutPoint, Transaction, TxIn, TxOut},
+    },
+    Network,
+};
+use bitcoin_indexer::{db::pg::bench, BlockData, WithHeightAndId};
+
+const BLOCKS: usize = 10;
+const TXS_PER_BLOCK: usize = 1000;
+
+fn p2wpkh(seed: usize) -> bitcoin::Script {
+    let mut hash = [0u8; 20];
+    hash[..8].copy_from_slice(&(seed as u64).to_le_bytes());
+    Builder::new()
+        .push_opcode(opcodes::all::OP_PUSHBYTES_0)
+        .push_slice(&hash)
+        .into_script()
+}
+
+fn tx(input: Vec<OutPoint>, seed: usize) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: 0,
+        input: input
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: Default::default(),
+                sequence: 0xffff_ffff,
+                witness: vec![vec![seed as u8; 72], vec![2; 33]],
+            })
+            .collect(),
+        output: (0..2)
+            .map(|i| TxOut {
+                value: 1000 + i,
+                script_pubkey: p2wpkh(seed * 2 + i as usize),
+            })
+            .collect(),
+    }
+}
+
+/// Chain of blocks full of 2-in 2-out segwit txs spending each other
+fn synthetic_blocks() -> Vec<BlockData> {
+    let mut prev = genesis_block(Network::Regtest);
+    let mut spendable = vec![];
+    let mut blocks = vec![];
+    for height in 1..=BLOCKS {
+        let coinbase = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(height as i64).into_script(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            ..tx(vec![], height)
+        };
+        let mut txdata = vec![coinbase];
+        for i in 0..TXS_PER_BLOCK {
+            let input = if spendable.len() >= 2 {
+                spendable.drain(..2).collect()
+            } else {
+                vec![OutPoint::new(prev.txdata[0].txid(), 0)]
+            };
+            let tx = tx(input, height * TXS_PER_BLOCK + i);
+            let txid = tx.txid();
+            spendable.extend((0..2).map(|vout| OutPoint::new(txid, vout)));
+            txdata.push(tx);
+        }
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Default::default(),
+                time: prev.header.time + 600,
+                bits: prev.header.bits,
+                nonce: 0,
+            },
+            txdata,
+        };
+        blocks.push(WithHeightAndId {
+            height: height as u32,
+            id: block.block_hash(),
+            data: Box::new(block.clone()),
+        });
+        prev = block;
+    }
+    blocks
+}
+
+fn pg_insert(c: &mut Criterion) {
+    let blocks = std::sync::Arc::new(synthetic_blocks());
+    for &copy in &[false, true] {
+        let name = if copy { "copy" } else { "insert" };
+        let size = bench::fmt_bulk_inserts(&blocks, Network::Regtest, copy)
+            .unwrap()
+            .len();
+        println!(
+            "{}: {} bytes for {} txs",
+            name,
+            size,
+            BLOCKS * TXS_PER_BLOCK
+        );
+
+        let fmt_blocks = blocks.clone();
+        c.bench_function(&format!("fmt_{}", name), move |b| {
+            b.iter(|| bench::fmt_bulk_inserts(&fmt_blocks, Network::Regtest, copy).unwrap())
+        });
+
+        if let Ok(url) = std::env::var("BENCH_DATABASE_URL") {
+            let mut conn = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+            bench::create_schema(&mut conn, "bench_pg_insert").unwrap();
+            let inserts = bench::fmt_bulk_inserts(&blocks, Network::Regtest, copy).unwrap();
+            c.bench_function(&format!("write_{}", name), move |b| {
+                b.iter(|| bench::write_and_rollback(&mut conn, &inserts).unwrap())
+            });
+        }
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = pg_insert
+}
+criterion_main!(benches);
@@ -5,59 +5,158 @@ use bitcoin_indexer::{
 };
 use bitcoincore_rpc::RpcApi;
 use log::trace;
-use std::{collections::HashSet, env};
+use std::{collections::HashSet, env, time::Duration};
 
 use common_failures::quick_main;
 
-fn run() -> Result<()> {
-    env_logger::init();
-    dotenv::dotenv()?;
-    let db_url = env::var("DATABASE_URL")?;
-    let node_url = env::var("NODE_RPC_URL")?;
+/// How often to scan the whole mempool
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Same, but when ZMQ notifications are coming; then it's only a fallback
+#[cfg(feature = "zmq")]
+const POLL_INTERVAL_WITH_ZMQ: Duration = Duration::from_secs(60);
 
-    let rpc_info = bitcoin_indexer::RpcInfo::from_url(&node_url)?;
+struct MempoolIndexer {
+    rpc: bitcoin_indexer::node::jsonrpc::JsonRpcClient,
+    db: db::pg::MempoolStore,
+    done: HashSet<bitcoin::Txid>,
+}
 
-    let rpc = rpc_info.to_rpc_client()?;
-    let network =
-        bitcoin_indexer::util::bitcoin::network_from_str(&rpc.get_blockchain_info()?.chain)?;
-    trace!("Creating mempool store");
-    let mut db = db::pg::MempoolStore::new(db_url, network)?;
+impl MempoolIndexer {
+    /// Insert a tx, unless already done; `tx` is fetched from the node if not given
+    fn insert(&mut self, tx_id: bitcoin::Txid, tx: Option<bitcoin::Transaction>) -> Result<()> {
+        if self.done.contains(&tx_id) {
+            return Ok(());
+        }
 
-    let mut done = HashSet::new();
+        let tx = tx.or_else(|| self.rpc.get_by_id(&tx_id).ok());
+        trace!("Inserting mempool tx {}", tx_id);
+        self.db.insert(&WithId {
+            id: tx_id,
+            data: tx,
+        })?;
+        self.done.insert(tx_id);
+        Ok(())
+    }
 
-    loop {
+    /// Go through the whole mempool of the node
+    fn scan(&mut self) -> Result<()> {
         // TODO: FIXME: Just use LRU instead
         let mut inserted = 0;
         let mut failed = 0;
 
-        if done.len() > 500_000 {
-            done.clear();
+        if self.done.len() > 500_000 {
+            self.done.clear();
         }
         trace!("Checking mempool");
-        for tx_id in rpc.get_raw_mempool()? {
-            if done.contains(&tx_id) {
+        for tx_id in self.rpc.get_raw_mempool()? {
+            if self.done.contains(&tx_id) {
                 continue;
             }
 
-            let tx: Option<bitcoin::Transaction> = rpc.get_by_id(&tx_id).ok();
-            trace!("Inserting mempool tx {}", tx_id);
-            match db.insert(&WithId {
-                id: tx_id,
-                data: tx,
-            }) {
+            match self.insert(tx_id, None) {
                 Err(e) => {
                     eprintln!("{}", e);
                     failed += 1;
                 }
                 Ok(()) => {
-                    done.insert(tx_id);
                     inserted += 1;
                 }
             }
         }
         eprintln!("Scanned mempool; success: {}; failed: {}", inserted, failed);
-        std::thread::sleep(std::time::Duration::from_secs(5));
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "zmq"))]
+fn run_loop(mut indexer: MempoolIndexer, zmq_url: Option<String>) -> Result<()> {
+    if zmq_url.is_some() {
+        bail!("NODE_ZMQ_URL is set, but this binary was built without `zmq` feature");
     }
+    loop {
+        indexer.scan()?;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Insert txs as ZMQ notifications arrive, scanning the whole mempool
+/// only now and then, or when some notifications were missed
+#[cfg(feature = "zmq")]
+fn run_loop(mut indexer: MempoolIndexer, zmq_url: Option<String>) -> Result<()> {
+    use bitcoin_indexer::node::zmq::{
+        Notification, SequenceEvent, ZmqSubscriber, TOPIC_RAWTX, TOPIC_SEQUENCE,
+    };
+    use std::{collections::HashMap, time::Instant};
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let subscriber = match zmq_url {
+        Some(url) => Some(ZmqSubscriber::new(
+            &url,
+            &[TOPIC_RAWTX, TOPIC_SEQUENCE],
+            move |notification| {
+                let _ = tx.send(notification);
+            },
+        )?),
+        None => None,
+    };
+    let poll_interval = if subscriber.is_some() {
+        POLL_INTERVAL_WITH_ZMQ
+    } else {
+        POLL_INTERVAL
+    };
+
+    // `rawtx` is published for txs in new blocks too, so only the ones
+    // reported by `sequence` as added to mempool are inserted
+    let mut recent_txs = HashMap::new();
+    loop {
+        indexer.scan()?;
+
+        let deadline = Instant::now() + poll_interval;
+        loop {
+            match rx.recv_deadline(deadline) {
+                Ok(Notification::RawTx(tx)) => {
+                    if recent_txs.len() > 10_000 {
+                        recent_txs.clear();
+                    }
+                    recent_txs.insert(tx.txid(), *tx);
+                }
+                Ok(Notification::Sequence(SequenceEvent::TxAdded { txid, .. })) => {
+                    if let Err(e) = indexer.insert(txid, recent_txs.remove(&txid)) {
+                        eprintln!("{}", e);
+                    }
+                }
+                Ok(Notification::Missed { .. }) => break,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    env_logger::init();
+    dotenv::dotenv()?;
+    let db_url = env::var("DATABASE_URL")?;
+    let node_url = env::var("NODE_RPC_URL")?;
+    let zmq_url = env::var("NODE_ZMQ_URL").ok();
+
+    // mempools differ between nodes; just follow the first one
+    let rpc_info = bitcoin_indexer::RpcInfo::from_url_list(&node_url)?.remove(0);
+
+    let rpc = rpc_info.to_rpc_client()?;
+    let network =
+        bitcoin_indexer::util::bitcoin::network_from_str(&rpc.get_blockchain_info()?.chain)?;
+    trace!("Creating mempool store");
+    let db = db::pg::MempoolStore::new(db_url, network)?;
+
+    run_loop(
+        MempoolIndexer {
+            rpc,
+            db,
+            done: HashSet::new(),
+        },
+        zmq_url,
+    )
 }
 
 quick_main!(run);
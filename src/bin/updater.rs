@@ -1,4 +1,9 @@
-use bitcoin_indexer::{db, node::fetcher, prelude::*, util::reversed};
+use bitcoin_indexer::{
+    db,
+    node::{fetcher, pool::RpcPool},
+    prelude::*,
+    util::reversed,
+};
 use itertools::Itertools;
 use std::{borrow::Borrow, env, sync::Arc};
 
@@ -10,7 +15,6 @@ fn run() -> Result<()> {
     let db_url = env::var("DATABASE_URL")?;
     let node_url = env::var("NODE_RPC_URL")?;
 
-    let rpc_info = bitcoin_indexer::RpcInfo::from_url(&node_url)?;
     let mut db = db::pg::establish_connection(&db_url);
     db.execute(
         "ALTER TABLE blocks ADD COLUMN IF NOT EXISTS merkle_root BYTEA",
@@ -21,10 +25,10 @@ fn run() -> Result<()> {
         &[],
     )?;
 
-    let rpc = rpc_info.to_rpc_client()?;
-    let fetcher = fetcher::Fetcher::new(Arc::new(rpc), None, None)?;
+    let rpc = RpcPool::from_url_list(&node_url)?;
+    let mut fetcher = fetcher::Fetcher::new(Arc::new(rpc), None, None)?;
 
-    for batch in &fetcher.chunks(1000) {
+    for batch in &fetcher.by_ref().chunks(1000) {
         let mut transaction = db.transaction()?;
         for (i, item) in batch.enumerate() {
             if i == 0 {
@@ -53,6 +57,9 @@ fn run() -> Result<()> {
         }
         transaction.commit()?;
     }
+    if let Some(e) = fetcher.take_error() {
+        return Err(e);
+    }
 
     db.execute("ALTER TABLE blocks ALTER COLUMN time SET NOT NULL", &[])?;
     db.execute(
@@ -6,6 +6,14 @@ pub mod schema;
 use crate::{prelude::*, types::*, TxHash};
 use std::collections::BTreeMap;
 
+/// Progress of a backward (tip to genesis) sync
+pub struct BackwardSync {
+    /// Next block to insert
+    pub next: WithHeightAndId<BlockHash>,
+    /// Lowest height to index
+    pub until: BlockHeight,
+}
+
 pub trait IndexerStore {
     /// Get the height of the stored chainhead
     fn get_head_height(&mut self) -> Result<Option<BlockHeight>>;
@@ -17,6 +25,35 @@ pub trait IndexerStore {
 
     /// Insert a new block: either extending current `head` or starting a reorg
     fn insert(&mut self, info: crate::BlockData) -> Result<()>;
+
+    /// Write out all the pending blocks and stop any background work
+    ///
+    /// Returns the height of the last block stored. The store should
+    /// only be dropped afterwards.
+    fn flush(&mut self) -> Result<Option<BlockHeight>>;
+
+    /// Start indexing backwards from the `tip` block, down to `until` height
+    ///
+    /// Only possible on an empty store. Until the block at `until` is
+    /// inserted, blocks have to be inserted in descending height order,
+    /// following `prev_blockhash` links.
+    fn start_backward_sync(
+        &mut self,
+        tip: WithHeightAndId<BlockHash>,
+        until: BlockHeight,
+    ) -> Result<()>;
+
+    /// Get the progress of an unfinished backward sync, if any
+    fn get_backward_sync(&mut self) -> Result<Option<BackwardSync>>;
+
+    /// Re-apply an already indexed block, fixing what is stored at its height
+    ///
+    /// A different (or missing) block at the same height is replaced, and
+    /// any missing rows of the block are inserted again. Applying a block
+    /// that is stored intact changes nothing.
+    ///
+    /// Returns `true` if anything had to be fixed.
+    fn repair(&mut self, block: crate::BlockData) -> Result<bool>;
 }
 
 pub trait MempoolStore {
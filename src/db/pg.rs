@@ -10,7 +10,9 @@
 //!
 //! * we keep track of `mode` and sometimes do thing differently depending on it
 //! * schema is being managed to build most indices only after all the initial data has been indexed
-//! * we resort to building raw SQL queries because multi-value `INSERT`s are absolutely the fastest insert method
+//! * in bulk modes, data is written with binary `COPY`, the fastest insert method there is
+//! * otherwise we resort to building raw SQL queries because multi-value `INSERT`s are the fastest
+//!   insert method that can handle conflicts
 //! * this is generally OK, because all the data here is trusted
 //!
 //! ### Data consistency
@@ -19,11 +21,11 @@
 //! Indexer guarantees that reorgs are atomic - one will never observe chain shrinking / in the middle of a reorg.
 //! We heavily rely on transactions.
 //!
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
 use super::*;
 use crate::{BlockHash, BlockHeight};
-use bitcoin::hash_types::Txid;
+use bitcoin::{hash_types::Txid, util::uint::Uint256};
 use fallible_iterator::FallibleIterator;
 use hex::ToHex;
 use itertools::Itertools;
@@ -241,6 +243,156 @@ impl<'a> Drop for MultiValueSqlFormatter<'a> {
     }
 }
 
+/// One statement of an atomic batch insert
+pub(crate) enum BatchQuery {
+    /// SQL, possibly multiple statements
+    Sql(String),
+    /// `COPY ... FROM STDIN` and the data to copy
+    Copy {
+        statement: &'static str,
+        data: Vec<u8>,
+    },
+}
+
+/// Queries of an atomic batch insert, along with tables they write to
+pub(crate) type TableQueries = Vec<(&'static str, BatchQuery)>;
+
+impl BatchQuery {
+    pub(crate) fn execute(&self, conn: &mut impl pg::GenericClient) -> Result<()> {
+        match self {
+            BatchQuery::Sql(s) => conn.batch_execute(s)?,
+            BatchQuery::Copy { statement, data } => {
+                let mut writer = conn.copy_in(*statement)?;
+                std::io::Write::write_all(&mut writer, data)?;
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `COPY ... FROM STDIN (FORMAT binary)` data formatter
+///
+/// Used instead of `MultiValueSqlFormatter` in bulk modes, as there are no
+/// conflicts to handle, and it saves formatting and parsing all the values
+/// (especially hex-encoded `bytea`s) on both sides.
+///
+/// See https://www.postgresql.org/docs/current/sql-copy.html#id-1.9.3.55.9.4
+pub(crate) struct BinaryCopyFormatter {
+    table: &'static str,
+    statement: &'static str,
+    data: Vec<u8>,
+    rows: usize,
+}
+
+impl BinaryCopyFormatter {
+    pub(crate) fn new(table: &'static str, statement: &'static str) -> Self {
+        let mut data = b"PGCOPY\n\xff\r\n\0".to_vec();
+        // flags, header extension length
+        data.extend_from_slice(&0i32.to_be_bytes());
+        data.extend_from_slice(&0i32.to_be_bytes());
+        Self {
+            table,
+            statement,
+            data,
+            rows: 0,
+        }
+    }
+
+    pub(crate) fn fmt_with(&mut self, f: impl FnOnce(&mut BinaryCopyRow)) {
+        let start = self.data.len();
+        self.data.extend_from_slice(&0i16.to_be_bytes());
+        let mut row = BinaryCopyRow {
+            data: &mut self.data,
+            fields: 0,
+        };
+        f(&mut row);
+        let fields = row.fields;
+        self.data[start..start + 2].copy_from_slice(&fields.to_be_bytes());
+        self.rows += 1;
+    }
+
+    /// `None` if there's nothing to copy
+    pub(crate) fn finish(mut self) -> Option<(&'static str, BatchQuery)> {
+        if self.rows == 0 {
+            return None;
+        }
+        self.data.extend_from_slice(&(-1i16).to_be_bytes());
+        Some((
+            self.table,
+            BatchQuery::Copy {
+                statement: self.statement,
+                data: self.data,
+            },
+        ))
+    }
+}
+
+/// Fields of a single row, in the order of `COPY` statement columns
+pub(crate) struct BinaryCopyRow<'a> {
+    data: &'a mut Vec<u8>,
+    fields: i16,
+}
+
+impl<'a> BinaryCopyRow<'a> {
+    fn field(&mut self, value: &[u8]) -> &mut Self {
+        self.data
+            .extend_from_slice(&(value.len() as i32).to_be_bytes());
+        self.data.extend_from_slice(value);
+        self.fields += 1;
+        self
+    }
+
+    pub(crate) fn null(&mut self) -> &mut Self {
+        self.data.extend_from_slice(&(-1i32).to_be_bytes());
+        self.fields += 1;
+        self
+    }
+
+    pub(crate) fn bytea(&mut self, value: &[u8]) -> &mut Self {
+        self.field(value)
+    }
+
+    fn hash_id(&mut self, hash: &Sha256dHash) -> &mut Self {
+        self.field(&hash.as_inner()[..SQL_HASH_ID_SIZE])
+    }
+
+    fn hash_rest(&mut self, hash: &Sha256dHash) -> &mut Self {
+        self.field(&hash.as_inner()[SQL_HASH_ID_SIZE..])
+    }
+
+    pub(crate) fn int(&mut self, value: i32) -> &mut Self {
+        self.field(&value.to_be_bytes())
+    }
+
+    pub(crate) fn bigint(&mut self, value: i64) -> &mut Self {
+        self.field(&value.to_be_bytes())
+    }
+
+    pub(crate) fn boolean(&mut self, value: bool) -> &mut Self {
+        self.field(&[value as u8])
+    }
+
+    pub(crate) fn text(&mut self, value: &str) -> &mut Self {
+        self.field(value.as_bytes())
+    }
+
+    /// Non-negative integer `NUMERIC`
+    pub(crate) fn numeric(&mut self, value: Uint256) -> &mut Self {
+        let digits = numeric_digits(value);
+        let mut field = Vec::with_capacity(8 + 2 * digits.len());
+        // digit count, weight (exponent of the first digit), sign, display scale
+        field.extend_from_slice(&(digits.len() as i16).to_be_bytes());
+        field.extend_from_slice(&(digits.len().saturating_sub(1) as i16).to_be_bytes());
+        field.extend_from_slice(&0u16.to_be_bytes());
+        field.extend_from_slice(&0u16.to_be_bytes());
+        for digit in digits {
+            field.extend_from_slice(&digit.to_be_bytes());
+        }
+        self.field(&field)
+    }
+}
+
 struct OutputFormatter<'a> {
     output: MultiValueSqlFormatter<'a>,
     network: bitcoin::Network,
@@ -251,7 +403,7 @@ impl<'a> OutputFormatter<'a> {
         Self {
             output: MultiValueSqlFormatter::new_on_conflict_do_nothing_auto(
                 output_s,
-                "INSERT INTO output(tx_hash_id, tx_idx, value, address)VALUES",
+                "INSERT INTO output(tx_hash_id, tx_idx, value, address, script_type, script_pubkey)VALUES",
                 mode,
             ),
             network,
@@ -264,14 +416,17 @@ impl<'a> OutputFormatter<'a> {
             s.write_str("('\\x").unwrap();
             write_hash_id_hex(s, tx_id).unwrap();
             s.write_fmt(format_args!(
-                "'::bytea,{},{},{})",
+                "'::bytea,{},{},{},'{}'::script_type,'\\x",
                 vout,
                 output.value,
                 crate::util::bitcoin::address_from_script(&output.script_pubkey, network)
                     .map(|a| format!("'{}'", a))
-                    .unwrap_or_else(|| "NULL".into())
+                    .unwrap_or_else(|| "NULL".into()),
+                crate::util::bitcoin::ScriptType::from_script(&output.script_pubkey)
             ))
             .unwrap();
+            write_hex(s, output.script_pubkey.as_bytes()).unwrap();
+            s.write_str("'::bytea)").unwrap();
         });
     }
 }
@@ -285,21 +440,35 @@ impl<'a> InputFormatter<'a> {
         Self {
             input: MultiValueSqlFormatter::new_on_conflict_do_nothing_auto(
                 input_s,
-                "INSERT INTO input(output_tx_hash_id,output_tx_idx,tx_hash_id,has_witness)VALUES",
+                "INSERT INTO input(output_tx_hash_id,output_tx_idx,tx_hash_id,has_witness,vin,sequence,script_sig,witness)VALUES",
                 mode,
             ),
         }
     }
 
-    fn fmt(&mut self, tx_id: &Sha256dHash, input: &bitcoin::TxIn) {
+    fn fmt(&mut self, tx_id: &Sha256dHash, input: &bitcoin::TxIn, vin: u32) {
         self.input.fmt_with(move |s| {
             s.write_str("('\\x").unwrap();
             write_hash_id_hex(s, &input.previous_output.txid.as_hash()).unwrap();
             s.write_fmt(format_args!("'::bytea,{},'\\x", input.previous_output.vout))
                 .unwrap();
             write_hash_id_hex(s, &tx_id).unwrap();
-            s.write_fmt(format_args!("'::bytea,{})", !input.witness.is_empty()))
-                .unwrap();
+            s.write_fmt(format_args!(
+                "'::bytea,{},{},{},'\\x",
+                !input.witness.is_empty(),
+                vin,
+                input.sequence
+            ))
+            .unwrap();
+            write_hex(s, input.script_sig.as_bytes()).unwrap();
+            s.write_str("'::bytea,").unwrap();
+            if input.witness.is_empty() {
+                s.write_str("NULL)").unwrap();
+            } else {
+                s.write_str("'\\x").unwrap();
+                write_hex(s, &bitcoin::consensus::serialize(&input.witness)).unwrap();
+                s.write_str("'::bytea)").unwrap();
+            }
         });
     }
 }
@@ -313,19 +482,19 @@ impl<'a> BlockTxFormatter<'a> {
         Self {
             block_tx: MultiValueSqlFormatter::new_on_conflict_do_nothing_auto(
                 block_tx_s,
-                "INSERT INTO block_tx(block_hash_id, tx_hash_id)VALUES",
+                "INSERT INTO block_tx(block_hash_id, tx_hash_id, position)VALUES",
                 mode,
             ),
         }
     }
 
-    fn fmt(&mut self, block: &BlockData, tx_id: &Sha256dHash) {
+    fn fmt(&mut self, block: &BlockData, tx_id: &Sha256dHash, position: usize) {
         self.block_tx.fmt_with(move |s| {
             s.write_str("('\\x").unwrap();
             write_hash_id_hex(s, &block.id.as_hash()).unwrap();
             s.write_str("'::bytea,'\\x").unwrap();
             write_hash_id_hex(s, &tx_id).unwrap();
-            s.write_str("'::bytea)").unwrap();
+            write!(s, "'::bytea,{})", position).unwrap();
         });
     }
 }
@@ -354,12 +523,12 @@ impl<'a> TxFormatter<'a> {
             tx: if mode.is_bulk() {
                 MultiValueSqlFormatter::new_no_conflict_check(
                     tx_s,
-                    "INSERT INTO tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height) VALUES",
+                    "INSERT INTO tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height, version, size, vsize, input_count, output_count, segwit) VALUES",
                 )
             } else {
                 MultiValueSqlFormatter::new_tx_on_conflict_update_current_height(
                     tx_s,
-                    "INSERT INTO tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height) VALUES",
+                    "INSERT INTO tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height, version, size, vsize, input_count, output_count, segwit) VALUES",
                 )
             },
             output_fmt: OutputFormatter::new(output_s, mode, network),
@@ -383,7 +552,7 @@ impl<'a> TxFormatter<'a> {
         Self {
             tx: MultiValueSqlFormatter::new_on_conflict_do_nothing(
                 tx_s,
-                "INSERT INTO tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height, mempool_ts) VALUES",
+                "INSERT INTO tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height, version, size, vsize, input_count, output_count, segwit, mempool_ts) VALUES",
             ),
             output_fmt: OutputFormatter::new(output_s, mode, network),
             input_fmt: InputFormatter::new(input_s, mode),
@@ -397,7 +566,7 @@ impl<'a> TxFormatter<'a> {
         block_height: Option<BlockHeight>,
         tx: &bitcoin::Transaction,
         tx_id: &Sha256dHash,
-        fee: u64,
+        fee: Option<u64>,
     ) {
         let from_mempool = self.from_mempool;
         self.tx.fmt_with(|s| {
@@ -406,17 +575,23 @@ impl<'a> TxFormatter<'a> {
 
             s.write_str("'::bytea,'\\x").unwrap();
             write_hash_rest_hex(s, &tx_id).unwrap();
-            let weight = tx.get_weight();
+            let sizes = TxSizes::of(tx);
 
             s.write_fmt(format_args!(
-                "'::bytea,{},{},{},{},{}",
-                weight,
-                fee,
+                "'::bytea,{},{},{},{},{},{},{},{},{},{},{}",
+                sizes.weight,
+                fee.map(|f| f.to_string()).unwrap_or_else(|| "NULL".into()),
                 tx.lock_time,
                 tx.is_coin_base(),
                 block_height
                     .map(|h| h.to_string())
                     .unwrap_or_else(|| "NULL".into()),
+                tx.version,
+                sizes.size,
+                sizes.vsize,
+                tx.input.len(),
+                tx.output.len(),
+                sizes.segwit,
             ))
             .unwrap();
             if from_mempool {
@@ -433,21 +608,7 @@ impl<'a> TxFormatter<'a> {
         tx_id: &TxHash,
     ) {
         let is_coinbase = tx.is_coin_base();
-
-        let fee = if tx.is_coin_base() {
-            0
-        } else {
-            let input_value_sum = tx.input.iter().fold(0, |acc, input| {
-                let p = HashIdOutPoint {
-                    tx_hash_id: hash_to_hash_id(&input.previous_output.txid.as_hash()),
-                    vout: input.previous_output.vout,
-                };
-                acc + self.inputs_utxo_map[&p].value
-            });
-            let output_value_sum = tx.output.iter().fold(0, |acc, output| acc + output.value);
-            assert!(output_value_sum <= input_value_sum);
-            input_value_sum - output_value_sum
-        };
+        let fee = tx_fee(tx, &self.inputs_utxo_map);
 
         self.fmt_one(block_height, tx, &tx_id, fee);
 
@@ -456,20 +617,45 @@ impl<'a> TxFormatter<'a> {
         }
 
         if !is_coinbase {
-            for input in &tx.input {
-                self.input_fmt.fmt(&tx_id, input);
+            for (vin, input) in tx.input.iter().enumerate() {
+                self.input_fmt.fmt(&tx_id, input, vin as u32);
             }
         }
     }
 }
 
+/// Fee of `tx`, or `None` if any of the spent outputs is not indexed (yet)
+fn tx_fee(tx: &bitcoin::Transaction, inputs_utxo_map: &UtxoDetailsMap) -> Option<u64> {
+    if tx.is_coin_base() {
+        return Some(0);
+    }
+    let input_value_sum: Option<u64> = tx
+        .input
+        .iter()
+        .map(|input| {
+            let p = HashIdOutPoint {
+                tx_hash_id: hash_to_hash_id(&input.previous_output.txid.as_hash()),
+                vout: input.previous_output.vout,
+            };
+            inputs_utxo_map.get(&p).map(|utxo| utxo.value)
+        })
+        .sum();
+    let output_value_sum = tx.output.iter().fold(0, |acc, output| acc + output.value);
+    input_value_sum.map(|input_value_sum| {
+        assert!(output_value_sum <= input_value_sum);
+        input_value_sum - output_value_sum
+    })
+}
+
 struct BlockFormatter<'a> {
-    event: MultiValueSqlFormatter<'a>,
+    /// `None` if events are not to be written (eg. during backward sync)
+    event: Option<MultiValueSqlFormatter<'a>>,
     block: MultiValueSqlFormatter<'a>,
 
     tx_fmt: TxFormatter<'a>,
     block_tx_fmt: BlockTxFormatter<'a>,
     tx_ids: TxIdMap,
+    chain_stats: ChainStatsMap,
 }
 
 impl<'a> BlockFormatter<'a> {
@@ -484,16 +670,22 @@ impl<'a> BlockFormatter<'a> {
         network: bitcoin::Network,
         inputs_utxo_map: UtxoDetailsMap,
         tx_ids: TxIdMap,
+        chain_stats: ChainStatsMap,
+        with_events: bool,
     ) -> Self {
         BlockFormatter {
-            event: MultiValueSqlFormatter::new_on_conflict_do_nothing_auto(
-                event_s,
-                "INSERT INTO event (block_hash_id) VALUES",
-                mode
-            ),
+            event: if with_events {
+                Some(MultiValueSqlFormatter::new_on_conflict_do_nothing_auto(
+                    event_s,
+                    "INSERT INTO event (block_hash_id) VALUES",
+                    mode
+                ))
+            } else {
+                None
+            },
             block: MultiValueSqlFormatter::new_on_conflict_do_nothing_auto(
                 block_s,
-                "INSERT INTO block (hash_id, hash_rest, prev_hash_id, merkle_root, height, time) VALUES",
+                "INSERT INTO block (hash_id, hash_rest, prev_hash_id, merkle_root, height, time, version, bits, nonce, size, stripped_size, weight, tx_count, median_time_past, chainwork) VALUES",
                 mode
             ),
             tx_fmt: TxFormatter::new_for_in_block(
@@ -509,16 +701,24 @@ impl<'a> BlockFormatter<'a> {
                 mode
             ),
             tx_ids,
+            chain_stats,
         }
     }
 
     fn fmt_one(&mut self, block: &BlockData) {
-        self.event.fmt_with(|s| {
-            s.write_str("('\\x").unwrap();
-            write_hash_id_hex(s, &block.id.as_hash()).unwrap();
-            s.write_str("'::bytea)").unwrap();
-        });
+        if let Some(event) = self.event.as_mut() {
+            event.fmt_with(|s| {
+                s.write_str("('\\x").unwrap();
+                write_hash_id_hex(s, &block.id.as_hash()).unwrap();
+                s.write_str("'::bytea)").unwrap();
+            });
+        }
 
+        let stats = self
+            .chain_stats
+            .get(&block.height)
+            .copied()
+            .unwrap_or_default();
         self.block.fmt_with(|s| {
             s.write_str("('\\x").unwrap();
             write_hash_id_hex(s, &block.id.as_hash()).unwrap();
@@ -532,11 +732,30 @@ impl<'a> BlockFormatter<'a> {
             s.write_str("'::bytea,'\\x").unwrap();
             write_hash_hex(s, &block.data.header.merkle_root.as_hash()).unwrap();
 
+            let header = &block.data.header;
+            let sizes = BlockSizes::of(&block.data);
             s.write_fmt(format_args!(
-                "'::bytea,{},{})",
-                block.height, block.data.header.time
+                "'::bytea,{},{},{},{},{},{},{},{},{},",
+                block.height,
+                header.time,
+                header.version,
+                header.bits,
+                header.nonce,
+                sizes.size,
+                sizes.stripped_size,
+                sizes.weight,
+                block.data.txdata.len(),
             ))
             .unwrap();
+            match stats.median_time_past {
+                Some(time) => s.write_fmt(format_args!("{},", time)).unwrap(),
+                None => s.write_str("NULL,").unwrap(),
+            }
+            match stats.chainwork {
+                Some(chainwork) => write_numeric(s, chainwork).unwrap(),
+                None => s.write_str("NULL").unwrap(),
+            }
+            s.write_str(")").unwrap();
         });
     }
 
@@ -546,7 +765,7 @@ impl<'a> BlockFormatter<'a> {
         for (tx_i, tx) in block.data.txdata.iter().enumerate() {
             let tx_id = &self.tx_ids[&(block.height, tx_i)];
             self.tx_fmt.fmt(Some(block.height), tx, &tx_id.as_hash());
-            self.block_tx_fmt.fmt(block, &tx_id.as_hash());
+            self.block_tx_fmt.fmt(block, &tx_id.as_hash(), tx_i);
         }
     }
 }
@@ -644,9 +863,19 @@ type UtxoDetailsMap = HashMap<HashIdOutPoint, UtxoSetEntry>;
 #[derive(Default)]
 struct UtxoSetCache {
     entries: UtxoDetailsMap,
+    /// Blocks below some height were never indexed, so outputs
+    /// might be missing from the db too
+    partial_history: bool,
 }
 
 impl UtxoSetCache {
+    fn new(partial_history: bool) -> Self {
+        Self {
+            partial_history,
+            ..default()
+        }
+    }
+
     fn insert(&mut self, point: HashIdOutPoint, value: u64) {
         self.entries.insert(point, UtxoSetEntry { value });
     }
@@ -759,12 +988,227 @@ impl UtxoSetCache {
                 )
             },
         )?;
-        assert_eq!(missing_len, out.len());
+        if !self.partial_history {
+            assert_eq!(missing_len, out.len());
+        }
 
         Ok(out)
     }
 }
 
+/// Serialized sizes of a block
+struct BlockSizes {
+    size: usize,
+    /// Without witnesses
+    stripped_size: usize,
+    weight: usize,
+}
+
+impl BlockSizes {
+    fn of(block: &bitcoin::Block) -> Self {
+        let size = block.get_size();
+        let weight = block.get_weight();
+        Self {
+            size,
+            // weight is stripped size * 3 + size
+            stripped_size: (weight - size) / 3,
+            weight,
+        }
+    }
+}
+
+/// Serialized sizes of a tx
+struct TxSizes {
+    size: usize,
+    /// `weight / 4`, rounded up: the size fee rates are given per
+    vsize: usize,
+    weight: usize,
+    /// Has any witness data (so `size` includes it)
+    segwit: bool,
+}
+
+impl TxSizes {
+    fn of(tx: &bitcoin::Transaction) -> Self {
+        let weight = tx.get_weight();
+        Self {
+            size: tx.get_size(),
+            vsize: weight.div_ceil(4),
+            weight,
+            segwit: tx.input.iter().any(|input| !input.witness.is_empty()),
+        }
+    }
+}
+
+/// Number of blocks median-time-past is the median of (the block and its ancestors)
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Values of a block that depend on its ancestors
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct ChainStats {
+    /// `None` if some of the ancestors are not known
+    pub(crate) median_time_past: Option<u32>,
+    /// `None` if some of the ancestors are not known
+    pub(crate) chainwork: Option<Uint256>,
+}
+
+type ChainStatsMap = HashMap<BlockHeight, ChainStats>;
+
+/// Recent block times and chainwork, for `ChainStats` of the blocks on top
+#[derive(Default)]
+pub(crate) struct ChainStatsCache {
+    /// Last block the stats were computed for
+    tip: Option<BlockHash>,
+    /// Times of up to `MEDIAN_TIME_SPAN` blocks up to `tip`, oldest first
+    times: std::collections::VecDeque<u32>,
+    /// Total work up to `tip`
+    pub(crate) chainwork: Option<Uint256>,
+}
+
+impl ChainStatsCache {
+    /// Stats of new blocks, in ascending height order
+    ///
+    /// Ancestors of a block that is not on top of the previous one
+    /// (eg. after a restart or a reorg) are read from the db.
+    fn process_blocks(
+        &mut self,
+        conn: &mut impl pg::GenericClient,
+        blocks: &[crate::BlockData],
+    ) -> Result<ChainStatsMap> {
+        let mut stats = ChainStatsMap::default();
+        for block in blocks {
+            if self.tip != Some(block.data.header.prev_blockhash) {
+                self.read_ancestors(conn, block)?;
+            }
+            self.tip = Some(block.id);
+            stats.insert(
+                block.height,
+                self.push(
+                    block.height,
+                    block.data.header.time,
+                    Some(work_from_bits(block.data.header.bits)),
+                ),
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Stats of the block on top of the ones pushed before
+    ///
+    /// `work` of `None` makes chainwork unknown from now on.
+    pub(crate) fn push(
+        &mut self,
+        height: BlockHeight,
+        time: u32,
+        work: Option<Uint256>,
+    ) -> ChainStats {
+        self.times.push_back(time);
+        if self.times.len() > MEDIAN_TIME_SPAN {
+            self.times.pop_front();
+        }
+        self.chainwork = match (self.chainwork, work) {
+            (Some(chainwork), Some(work)) => Some(chainwork + work),
+            _ => None,
+        };
+
+        ChainStats {
+            // blocks close to genesis have fewer ancestors to take it from
+            median_time_past: if self.times.len() >= MEDIAN_TIME_SPAN.min(height as usize + 1) {
+                let mut times: Vec<_> = self.times.iter().copied().collect();
+                times.sort_unstable();
+                Some(times[times.len() / 2])
+            } else {
+                None
+            },
+            chainwork: self.chainwork,
+        }
+    }
+
+    fn read_ancestors(
+        &mut self,
+        conn: &mut impl pg::GenericClient,
+        block: &crate::BlockData,
+    ) -> Result<()> {
+        *self = Self::default();
+        if block.height == 0 {
+            self.chainwork = Some(Uint256::default());
+            return Ok(());
+        }
+        let rows = conn.query(
+            "SELECT hash_id, time, chainwork::text FROM block
+            WHERE NOT extinct AND height >= $1 AND height < $2 ORDER BY height ASC",
+            &[
+                &(block
+                    .height
+                    .saturating_sub(MEDIAN_TIME_SPAN as BlockHeight - 1)
+                    as BlockHeightSigned),
+                &(block.height as BlockHeightSigned),
+            ],
+        )?;
+        let prev_hash_id = hash_to_hash_id(&block.data.header.prev_blockhash.as_hash());
+        match rows.last() {
+            Some(prev) if prev.get::<_, Vec<u8>>(0) == prev_hash_id => {
+                self.times = rows.iter().map(|row| row.get::<_, i64>(1) as u32).collect();
+                self.chainwork = prev
+                    .get::<_, Option<String>>(2)
+                    .map(|chainwork| parse_uint256(&chainwork))
+                    .transpose()?;
+            }
+            // eg. blocks below were never indexed
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Expected number of hashes needed for a block with `bits` target,
+/// as in `BlockHeader::work`
+pub(crate) fn work_from_bits(bits: u32) -> Uint256 {
+    let target = bitcoin::BlockHeader::u256_from_compact_target(bits);
+    let mut target_plus_one = target;
+    target_plus_one.increment();
+    // 2**256 / (target + 1), without overflowing
+    let mut work = !target / target_plus_one;
+    work.increment();
+    work
+}
+
+/// Digits of `n` in base 10000, most significant first, as `NUMERIC` keeps them
+fn numeric_digits(mut n: Uint256) -> Vec<i16> {
+    let base = Uint256::from_u64(10_000).expect("fits");
+    let mut digits = vec![];
+    while n != Uint256::default() {
+        digits.push((n % base).low_u64() as i16);
+        n = n / base;
+    }
+    digits.reverse();
+    digits
+}
+
+pub(crate) fn write_numeric<W: std::fmt::Write>(w: &mut W, n: Uint256) -> std::fmt::Result {
+    let digits = numeric_digits(n);
+    match digits.split_first() {
+        None => w.write_str("0"),
+        Some((first, rest)) => {
+            write!(w, "{}", first)?;
+            for digit in rest {
+                write!(w, "{:04}", digit)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn parse_uint256(s: &str) -> Result<Uint256> {
+    let mut n = Uint256::default();
+    for c in s.chars() {
+        match c.to_digit(10) {
+            Some(digit) => n = n.mul_u32(10) + Uint256::from_u64(u64::from(digit)).expect("fits"),
+            None => bail!("Not a decimal number: {}", s),
+        }
+    }
+    Ok(n)
+}
+
 /// Convenient (arguably) function for reporting times of operations
 fn trace_time<T>(
     body: impl FnOnce() -> Result<T>,
@@ -783,12 +1227,12 @@ fn commit_atomic_bulk_insert_sql(
     name: &str,
     len: usize,
     batch_id: u64,
-    queries: impl Iterator<Item = String>,
+    queries: impl Iterator<Item = BatchQuery>,
 ) -> Result<()> {
     let start = Instant::now();
-    for (i, s) in queries.enumerate() {
+    for (i, q) in queries.enumerate() {
         trace_time(
-            || Ok(transaction.batch_execute(&s)?),
+            || q.execute(&mut transaction),
             |duration, _| {
                 debug!(
                     "Executed query {} of batch {} in {}ms",
@@ -810,6 +1254,152 @@ fn commit_atomic_bulk_insert_sql(
     Ok(())
 }
 
+/// Tables written on connections of their own with parallel writers
+///
+/// The rest (`event`, `block` and `indexer_state`) decide what's in the
+/// chain, so they are written together, and committed last.
+const PARALLEL_WRITER_TABLES: &[&str] = &["block_tx", "tx", "output", "input"];
+
+/// Prefix of ids of transactions prepared by parallel writers
+pub(crate) const PREPARED_TRANSACTION_PREFIX: &str = "bitcoin_indexer_";
+
+/// Run `queries` in a new transaction, and prepare it to be committed as `gid`
+fn prepare_transaction(conn: &mut pg::Client, gid: &str, queries: &[BatchQuery]) -> Result<()> {
+    conn.batch_execute("BEGIN")?;
+    for q in queries {
+        q.execute(conn)?;
+    }
+    conn.batch_execute(&format!("PREPARE TRANSACTION '{}'", gid))?;
+    Ok(())
+}
+
+/// Like `commit_atomic_bulk_insert_sql`, but with `PARALLEL_WRITER_TABLES`
+/// written in parallel, each on its own connection from `table_conns`
+///
+/// Two-phase commit keeps it atomic: all transactions are prepared first,
+/// and only once all of them were, and that is recorded in `indexer_commit`,
+/// they are committed, the one with `event` and `block` last. So the batch
+/// shows up only when all of its data is there. Prepared transaction ids
+/// start with `batch_name`.
+///
+/// After a crash, `resolve_prepared_transactions` finishes the job.
+pub(crate) fn commit_parallel_bulk_insert(
+    conn: &mut pg::Client,
+    table_conns: &mut [(&'static str, pg::Client)],
+    batch_name: &str,
+    queries: TableQueries,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut chain_queries = vec![];
+    let mut table_queries: Vec<Vec<BatchQuery>> = table_conns.iter().map(|_| vec![]).collect();
+    for (table, q) in queries {
+        match table_conns.iter().position(|(t, _)| *t == table) {
+            Some(i) => table_queries[i].push(q),
+            None => chain_queries.push(q),
+        }
+    }
+
+    let chain_gid = format!("{}chain", batch_name);
+    let table_gids: Vec<_> = table_conns
+        .iter()
+        .map(|(table, _)| format!("{}{}", batch_name, table))
+        .collect();
+    let res = std::thread::scope(|scope| {
+        let joins: Vec<_> = table_conns
+            .iter_mut()
+            .zip(&table_gids)
+            .zip(&table_queries)
+            .map(|(((_, table_conn), gid), queries)| {
+                scope.spawn(move || prepare_transaction(table_conn, gid, queries))
+            })
+            .collect();
+        let chain_res = prepare_transaction(conn, &chain_gid, &chain_queries);
+        joins
+            .into_iter()
+            .map(|join| join.join().expect("writer doesn't panic"))
+            .chain(std::iter::once(chain_res))
+            .collect::<Result<()>>()
+    });
+    if let Err(e) = res {
+        // don't leave anything holding locks
+        for table_conn in table_conns.iter_mut().map(|(_, c)| c) {
+            let _ = table_conn.batch_execute("ROLLBACK");
+        }
+        let _ = conn.batch_execute("ROLLBACK");
+        if let Err(e) = resolve_prepared_transactions(conn) {
+            error!("Couldn't roll back prepared transactions: {}", e);
+        }
+        return Err(e);
+    }
+    trace!(
+        "Prepared batch {} in {}ms",
+        batch_name,
+        start.elapsed().as_millis()
+    );
+
+    conn.execute(
+        "INSERT INTO indexer_commit (batch) VALUES ($1)",
+        &[&batch_name],
+    )?;
+    for gid in table_gids.iter().chain(std::iter::once(&chain_gid)) {
+        conn.batch_execute(&format!("COMMIT PREPARED '{}'", gid))?;
+    }
+    conn.execute("DELETE FROM indexer_commit", &[])?;
+    trace!(
+        "Committed batch {} in {}ms",
+        batch_name,
+        start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Ids of transactions prepared by parallel writers, and not finished yet
+fn prepared_transactions(conn: &mut pg::Client) -> Result<Vec<String>> {
+    Ok(conn
+        .query(
+            "SELECT gid FROM pg_prepared_xacts WHERE database = current_database() AND starts_with(gid, $1)",
+            &[&PREPARED_TRANSACTION_PREFIX],
+        )?
+        .iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+/// Finish two-phase commits interrupted by a crash
+///
+/// Batches recorded in `indexer_commit` had all their transactions prepared,
+/// so these are committed; any other prepared transactions are rolled back.
+///
+/// Prepared transactions keep their locks, so this has to go before anything
+/// else touches the tables.
+pub(crate) fn resolve_prepared_transactions(conn: &mut pg::Client) -> Result<()> {
+    let mut gids = prepared_transactions(conn)?;
+    if gids.is_empty() {
+        return Ok(());
+    }
+    let committing: Vec<String> = conn
+        .query("SELECT batch FROM indexer_commit", &[])?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    // chain tables last
+    gids.sort_by_key(|gid| gid.ends_with("chain"));
+    for gid in gids {
+        if committing
+            .iter()
+            .any(|batch| gid.starts_with(batch.as_str()))
+        {
+            info!("Committing interrupted batch: {}", gid);
+            conn.batch_execute(&format!("COMMIT PREPARED '{}'", gid))?;
+        } else {
+            info!("Rolling back interrupted batch: {}", gid);
+            conn.batch_execute(&format!("ROLLBACK PREPARED '{}'", gid))?;
+        }
+    }
+    conn.execute("DELETE FROM indexer_commit", &[])?;
+    Ok(())
+}
+
 type BlocksInFlight = HashSet<BlockHash>;
 
 /// Asynchronous block data insertion worker
@@ -924,12 +1514,40 @@ fn tx_id_map_from_blocks(
     )
 }
 
+/// Reject blocks that don't match their ids or txs
+fn verify_blocks(blocks: &[crate::BlockData], tx_ids: &TxIdMap) -> Result<()> {
+    trace_time(
+        || {
+            blocks.par_iter().try_for_each(|block| {
+                let txids = block.data.txdata.iter().enumerate().map(|(tx_i, tx)| {
+                    // `tx_ids` has workarounds for duplicated coinbase txids
+                    if tx_i == 0 {
+                        tx.txid()
+                    } else {
+                        tx_ids[&(block.height, tx_i)]
+                    }
+                });
+                crate::verify::verify_block(block, txids)
+            })
+        },
+        |duration, _| {
+            debug!(
+                "Verified {} blocks in {}ms",
+                blocks.len(),
+                duration.as_millis()
+            )
+        },
+    )
+}
+
 fn fmt_insert_blockdata_sql(
     blocks: &[crate::BlockData],
     inputs_utxo_map: UtxoDetailsMap,
     tx_ids: TxIdMap,
+    chain_stats: ChainStatsMap,
     mode: Mode,
     network: bitcoin::Network,
+    with_events: bool,
 ) -> Result<Vec<String>> {
     let mut event_q = String::new();
     let mut block_q = String::new();
@@ -949,6 +1567,8 @@ fn fmt_insert_blockdata_sql(
         network,
         inputs_utxo_map,
         tx_ids,
+        chain_stats,
+        with_events,
     );
 
     trace_time(
@@ -964,12 +1584,224 @@ fn fmt_insert_blockdata_sql(
 
     Ok(vec![event_q, block_q, block_tx_q, tx_q, output_q, input_q])
 }
+
+/// Like `fmt_insert_blockdata_sql`, but as `COPY`, so only for bulk modes
+///
+/// Without `with_witness`, `input.witness` is left `NULL`.
+fn fmt_copy_blockdata(
+    blocks: &[crate::BlockData],
+    inputs_utxo_map: UtxoDetailsMap,
+    tx_ids: TxIdMap,
+    chain_stats: ChainStatsMap,
+    network: bitcoin::Network,
+    with_events: bool,
+    with_witness: bool,
+) -> Result<TableQueries> {
+    let mut event_c = BinaryCopyFormatter::new(
+        "event",
+        "COPY event (block_hash_id) FROM STDIN (FORMAT binary)",
+    );
+    let mut block_c = BinaryCopyFormatter::new(
+        "block",
+        "COPY block (hash_id, hash_rest, prev_hash_id, merkle_root, height, time, version, bits, nonce, size, stripped_size, weight, tx_count, median_time_past, chainwork) FROM STDIN (FORMAT binary)",
+    );
+    let mut block_tx_c = BinaryCopyFormatter::new(
+        "block_tx",
+        "COPY block_tx (block_hash_id, tx_hash_id, position) FROM STDIN (FORMAT binary)",
+    );
+    let mut tx_c = BinaryCopyFormatter::new(
+        "tx",
+        "COPY tx (hash_id, hash_rest, weight, fee, locktime, coinbase, current_height, version, size, vsize, input_count, output_count, segwit) FROM STDIN (FORMAT binary)",
+    );
+    let mut output_c = BinaryCopyFormatter::new(
+        "output",
+        "COPY output (tx_hash_id, tx_idx, value, address, script_type, script_pubkey) FROM STDIN (FORMAT binary)",
+    );
+    let mut input_c = BinaryCopyFormatter::new(
+        "input",
+        "COPY input (output_tx_hash_id, output_tx_idx, tx_hash_id, has_witness, vin, sequence, script_sig, witness) FROM STDIN (FORMAT binary)",
+    );
+
+    trace_time(
+        || {
+            for block in blocks {
+                let block_id = block.id.as_hash();
+                if with_events {
+                    event_c.fmt_with(|r| {
+                        r.hash_id(&block_id);
+                    });
+                }
+                let header = &block.data.header;
+                let sizes = BlockSizes::of(&block.data);
+                let stats = chain_stats.get(&block.height).copied().unwrap_or_default();
+                block_c.fmt_with(|r| {
+                    r.hash_id(&block_id)
+                        .hash_rest(&block_id)
+                        .hash_id(&header.prev_blockhash.as_hash())
+                        .bytea(&header.merkle_root.as_hash().into_inner())
+                        .int(block.height as i32)
+                        .bigint(i64::from(header.time))
+                        .int(header.version)
+                        .bigint(i64::from(header.bits))
+                        .bigint(i64::from(header.nonce))
+                        .int(sizes.size as i32)
+                        .int(sizes.stripped_size as i32)
+                        .int(sizes.weight as i32)
+                        .int(block.data.txdata.len() as i32);
+                    match stats.median_time_past {
+                        Some(time) => r.bigint(i64::from(time)),
+                        None => r.null(),
+                    };
+                    match stats.chainwork {
+                        Some(chainwork) => r.numeric(chainwork),
+                        None => r.null(),
+                    };
+                });
+
+                for (tx_i, tx) in block.data.txdata.iter().enumerate() {
+                    let tx_id = tx_ids[&(block.height, tx_i)].as_hash();
+                    let sizes = TxSizes::of(tx);
+                    tx_c.fmt_with(|r| {
+                        r.hash_id(&tx_id).hash_rest(&tx_id).int(sizes.weight as i32);
+                        match tx_fee(tx, &inputs_utxo_map) {
+                            Some(fee) => r.bigint(fee as i64),
+                            None => r.null(),
+                        };
+                        r.bigint(i64::from(tx.lock_time))
+                            .boolean(tx.is_coin_base())
+                            .int(block.height as i32)
+                            .int(tx.version)
+                            .int(sizes.size as i32)
+                            .int(sizes.vsize as i32)
+                            .int(tx.input.len() as i32)
+                            .int(tx.output.len() as i32)
+                            .boolean(sizes.segwit);
+                    });
+
+                    for (idx, output) in tx.output.iter().enumerate() {
+                        output_c.fmt_with(|r| {
+                            r.hash_id(&tx_id)
+                                .int(idx as i32)
+                                .bigint(output.value as i64);
+                            match crate::util::bitcoin::address_from_script(
+                                &output.script_pubkey,
+                                network,
+                            ) {
+                                Some(address) => r.text(&address.to_string()),
+                                None => r.null(),
+                            };
+                            // enums are sent as their labels
+                            r.text(
+                                crate::util::bitcoin::ScriptType::from_script(
+                                    &output.script_pubkey,
+                                )
+                                .as_str(),
+                            )
+                            .bytea(output.script_pubkey.as_bytes());
+                        });
+                    }
+
+                    if !tx.is_coin_base() {
+                        for (vin, input) in tx.input.iter().enumerate() {
+                            input_c.fmt_with(|r| {
+                                r.hash_id(&input.previous_output.txid.as_hash())
+                                    .int(input.previous_output.vout as i32)
+                                    .hash_id(&tx_id)
+                                    .boolean(!input.witness.is_empty())
+                                    .int(vin as i32)
+                                    .bigint(i64::from(input.sequence))
+                                    .bytea(input.script_sig.as_bytes());
+                                if with_witness && !input.witness.is_empty() {
+                                    r.bytea(&bitcoin::consensus::serialize(&input.witness));
+                                } else {
+                                    r.null();
+                                }
+                            });
+                        }
+                    }
+
+                    block_tx_c.fmt_with(|r| {
+                        r.hash_id(&block_id).hash_id(&tx_id).int(tx_i as i32);
+                    });
+                }
+            }
+            Ok(())
+        },
+        |duration, _| debug!("Formatted copy data in {}ms", duration.as_millis()),
+    )?;
+
+    Ok(vec![event_c, block_c, block_tx_c, tx_c, output_c, input_c]
+        .into_iter()
+        .filter_map(BinaryCopyFormatter::finish)
+        .collect())
+}
+
+/// Format inserts of `blocks`: `COPY` in bulk modes, otherwise SQL
+///
+/// `skip_witness` applies only to bulk modes.
+#[allow(clippy::too_many_arguments)]
+fn fmt_insert_blockdata(
+    blocks: &[crate::BlockData],
+    inputs_utxo_map: UtxoDetailsMap,
+    tx_ids: TxIdMap,
+    chain_stats: ChainStatsMap,
+    mode: Mode,
+    network: bitcoin::Network,
+    with_events: bool,
+    skip_witness: bool,
+) -> Result<TableQueries> {
+    if mode.is_bulk() {
+        fmt_copy_blockdata(
+            blocks,
+            inputs_utxo_map,
+            tx_ids,
+            chain_stats,
+            network,
+            with_events,
+            !skip_witness,
+        )
+    } else {
+        // in the order `fmt_insert_blockdata_sql` returns them
+        let tables = ["event", "block", "block_tx", "tx", "output", "input"];
+        let queries = fmt_insert_blockdata_sql(
+            blocks,
+            inputs_utxo_map,
+            tx_ids,
+            chain_stats,
+            mode,
+            network,
+            with_events,
+        )?;
+        Ok(tables
+            .iter()
+            .copied()
+            .zip(queries.into_iter().map(BatchQuery::Sql))
+            .collect())
+    }
+}
 impl AsyncBlockInsertWorker {
+    /// In `backward` sync blocks come in descending height order,
+    /// so utxos are not tracked, no events are written, and
+    /// backward sync progress is committed along each batch.
+    ///
+    /// With `verify`, a block failing integrity checks stops the workers
+    /// with an error, before anything of its batch is written.
+    ///
+    /// With `parallel_writers`, in bulk modes, batches are written
+    /// with `commit_parallel_bulk_insert`.
+    ///
+    /// With `skip_witness`, in bulk modes, input witnesses are not written.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         url: String,
         in_flight: Arc<Mutex<BlocksInFlight>>,
         mode: Mode,
         network: bitcoin::Network,
+        backward: bool,
+        partial_history: bool,
+        verify: bool,
+        parallel_writers: bool,
+        skip_witness: bool,
     ) -> Self {
         // We use only rendezvous (0-size) channels, to allow passing
         // work and parallelism, but without doing any buffering of
@@ -978,11 +1810,16 @@ impl AsyncBlockInsertWorker {
         // incrased memory usage.
         let (utxo_fetching_tx, utxo_fetching_rx) =
             crossbeam_channel::bounded::<(u64, Vec<crate::BlockData>)>(0);
-        let (query_fmt_tx, query_fmt_rx) =
-            crossbeam_channel::bounded::<(u64, Vec<crate::BlockData>, UtxoDetailsMap, TxIdMap)>(0);
+        let (query_fmt_tx, query_fmt_rx) = crossbeam_channel::bounded::<(
+            u64,
+            Vec<crate::BlockData>,
+            UtxoDetailsMap,
+            TxIdMap,
+            ChainStatsMap,
+        )>(0);
         let (writer_tx, writer_rx) = crossbeam_channel::bounded::<(
             u64,
-            Vec<String>,
+            TableQueries,
             HashSet<BlockHash>,
             BlockHeight,
             usize,
@@ -992,16 +1829,29 @@ impl AsyncBlockInsertWorker {
             let url = url.clone();
             let mut conn = establish_connection(&url);
             fn_log_err("pg_utxo_fetching", move || {
-                let mut utxo_set_cache = UtxoSetCache::default();
+                let mut utxo_set_cache = UtxoSetCache::new(partial_history);
+                let mut chain_stats_cache = ChainStatsCache::default();
 
                 while let Ok((batch_id, blocks)) = utxo_fetching_rx.recv() {
                     let tx_ids: TxIdMap = tx_id_map_from_blocks(&blocks, network)?;
+                    if verify {
+                        verify_blocks(&blocks, &tx_ids)?;
+                    }
 
-                    let inputs_utxo_map =
-                        utxo_set_cache.process_blocks(&mut conn, &blocks, &tx_ids)?;
+                    // spent outputs are not indexed yet; fees are filled in
+                    // after backward sync is complete
+                    // same for stats depending on ancestors
+                    let (inputs_utxo_map, chain_stats) = if backward {
+                        (UtxoDetailsMap::default(), ChainStatsMap::default())
+                    } else {
+                        (
+                            utxo_set_cache.process_blocks(&mut conn, &blocks, &tx_ids)?,
+                            chain_stats_cache.process_blocks(&mut conn, &blocks)?,
+                        )
+                    };
 
                     query_fmt_tx
-                        .send((batch_id, blocks, inputs_utxo_map, tx_ids))
+                        .send((batch_id, blocks, inputs_utxo_map, tx_ids, chain_stats))
                         .expect("Send not fail");
                 }
                 Ok(())
@@ -1010,9 +1860,30 @@ impl AsyncBlockInsertWorker {
 
         let query_fmt_thread = std::thread::spawn({
             fn_log_err("pg_query_fmt", move || {
-                while let Ok((batch_id, blocks, inputs_utxo_map, tx_ids)) = query_fmt_rx.recv() {
-                    let insert_queries =
-                        fmt_insert_blockdata_sql(&blocks, inputs_utxo_map, tx_ids, mode, network)?;
+                while let Ok((batch_id, blocks, inputs_utxo_map, tx_ids, chain_stats)) =
+                    query_fmt_rx.recv()
+                {
+                    let mut insert_queries = fmt_insert_blockdata(
+                        &blocks,
+                        inputs_utxo_map,
+                        tx_ids,
+                        chain_stats,
+                        mode,
+                        network,
+                        !backward,
+                        skip_witness,
+                    )?;
+
+                    if backward {
+                        let lowest = blocks.last().expect("at least one block");
+                        let mut q = format!(
+                            "UPDATE indexer_state SET backward_next_height = {}, backward_next_hash = '\\x",
+                            i64::from(lowest.height) - 1
+                        );
+                        write_hash_hex(&mut q, &lowest.data.header.prev_blockhash.as_hash())?;
+                        q.push_str("'::bytea;");
+                        insert_queries.push(("indexer_state", BatchQuery::Sql(q)));
+                    }
 
                     let tx_len = blocks.iter().map(|b| b.data.txdata.len()).sum();
 
@@ -1042,19 +1913,42 @@ impl AsyncBlockInsertWorker {
         let writer_thread = std::thread::spawn({
             let url = url.clone();
             let mut conn = establish_connection(&url);
+            // no foreign keys to check across tables only in bulk modes
+            let mut table_conns: Vec<_> = if parallel_writers && mode.is_bulk() {
+                PARALLEL_WRITER_TABLES
+                    .iter()
+                    .map(|table| (*table, establish_connection(&url)))
+                    .collect()
+            } else {
+                vec![]
+            };
+            // prepared transaction ids must not repeat after a restart
+            let run_id = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("time after epoch")
+                .as_millis();
             fn_log_err("pg_writer", move || {
                 let mut prev_time = std::time::Instant::now();
                 while let Ok((batch_id, queries, block_ids, max_block_height, tx_len)) =
                     writer_rx.recv()
                 {
-                    let transaction = conn.transaction()?;
-                    commit_atomic_bulk_insert_sql(
-                        transaction,
-                        "all block data",
-                        block_ids.len(),
-                        batch_id,
-                        queries.into_iter(),
-                    )?;
+                    if table_conns.is_empty() {
+                        let transaction = conn.transaction()?;
+                        commit_atomic_bulk_insert_sql(
+                            transaction,
+                            "all block data",
+                            block_ids.len(),
+                            batch_id,
+                            queries.into_iter().map(|(_, q)| q),
+                        )?;
+                    } else {
+                        commit_parallel_bulk_insert(
+                            &mut conn,
+                            &mut table_conns,
+                            &format!("{}{}_{}_", PREPARED_TRANSACTION_PREFIX, run_id, batch_id),
+                            queries,
+                        )?;
+                    }
 
                     let current_time = std::time::Instant::now();
                     let duration = current_time.duration_since(prev_time);
@@ -1094,20 +1988,39 @@ impl AsyncBlockInsertWorker {
     }
 }
 
-impl Drop for AsyncBlockInsertWorker {
-    fn drop(&mut self) {
+impl AsyncBlockInsertWorker {
+    /// Let all the work in progress finish, and join worker threads
+    ///
+    /// Returns the first error any of the workers finished with.
+    fn join(&mut self) -> Result<()> {
         drop(self.tx.take());
 
         let joins = vec![
-            self.utxo_fetching_thread.take().unwrap(),
-            self.query_fmt_thread.take().unwrap(),
-            self.writer_thread.take().unwrap(),
+            ("pg_utxo_fetching", self.utxo_fetching_thread.take()),
+            ("pg_query_fmt", self.query_fmt_thread.take()),
+            ("pg_writer", self.writer_thread.take()),
         ];
 
-        for join in joins {
-            join.join()
-                .expect("Couldn't join on thread")
-                .expect("Worker thread panicked");
+        let mut res = Ok(());
+        for (name, join) in joins {
+            let thread_res = match join {
+                Some(join) => join
+                    .join()
+                    .unwrap_or_else(|_| Err(failure::format_err!("{} panicked", name))),
+                None => Ok(()),
+            };
+            if res.is_ok() {
+                res = thread_res;
+            }
+        }
+        res
+    }
+}
+
+impl Drop for AsyncBlockInsertWorker {
+    fn drop(&mut self) {
+        if let Err(e) = self.join() {
+            error!("DB pipeline finished with an error: {}", e);
         }
     }
 }
@@ -1133,23 +2046,53 @@ pub struct IndexerStore {
     // during the reorg, all reorg blocks are being gathered here
     // until they overtake the current `chain_block_count`
     pending_reorg: BTreeMap<BlockHeight, BlockData>,
+
+    // unfinished backward sync, if any
+    backward: Option<super::BackwardSync>,
+    // lowest height ever indexed; non-zero only after backward sync
+    history_start: BlockHeight,
+    // check blocks integrity before writing them
+    verify_blocks: bool,
+    // write tables in parallel in bulk modes
+    parallel_writers: bool,
+    // don't write input witnesses in bulk modes
+    skip_witness: bool,
 }
 
 impl Drop for IndexerStore {
     fn drop(&mut self) {
-        self.stop_workers();
+        if let Err(e) = self.stop_workers() {
+            error!("{}", e);
+        }
     }
 }
 
 impl IndexerStore {
+    /// With `verify_blocks`, blocks that don't match their ids or txs are
+    /// rejected, failing the insert.
+    ///
+    /// With `parallel_writers`, in bulk modes, tables are written on separate
+    /// connections in parallel, using two-phase commit. It needs
+    /// `max_prepared_transactions` set in Postgres config.
+    ///
+    /// With `skip_witness`, in bulk modes, `input.witness` is left `NULL`.
     pub fn new(
         url: String,
         node_chain_head_height: BlockHeight,
         network: bitcoin::Network,
+        verify_blocks: bool,
+        parallel_writers: bool,
+        skip_witness: bool,
     ) -> Result<Self> {
         let mut connection = establish_connection(&url);
+        resolve_prepared_transactions(&mut connection)?;
         Self::init(&mut connection)?;
+        if parallel_writers {
+            Self::check_prepared_transactions_enabled(&mut connection)?;
+        }
         let mode = Self::read_indexer_state(&mut connection)?;
+        let (history_start, backward, backward_written) =
+            Self::read_backward_sync_state(&mut connection)?;
         let chain_block_count = Self::read_db_chain_block_count(&mut connection)?;
         let chain_current_block_count = Self::read_db_chain_current_block_count(&mut connection)?;
 
@@ -1170,11 +2113,20 @@ impl IndexerStore {
             pending_reorg: BTreeMap::default(),
             in_flight: Arc::new(Mutex::new(BlocksInFlight::new())),
             chain_block_count,
+            backward,
+            history_start,
+            verify_blocks,
+            parallel_writers,
+            skip_witness,
         };
         if s.mode == Mode::FreshBulk {
             s.self_test()?;
         }
         s.set_schema_to_mode(s.mode)?;
+        if backward_written {
+            // all blocks were written, but we stopped before finishing up
+            s.finish_backward_sync()?;
+        }
         s.start_workers();
         Ok(s)
     }
@@ -1267,17 +2219,76 @@ impl IndexerStore {
         }
     }
 
+    /// Read backward sync state
+    ///
+    /// Returns the lowest height ever indexed, progress of the backward sync
+    /// if it is still in progress, and whether all its blocks were already written,
+    /// but the sync was not finished.
+    fn read_backward_sync_state(
+        conn: &mut pg::Client,
+    ) -> Result<(BlockHeight, Option<super::BackwardSync>, bool)> {
+        let row = conn.query_one(
+            "SELECT backward_until, backward_next_height, backward_next_hash FROM indexer_state",
+            &[],
+        )?;
+        let until = row.get::<_, Option<BlockHeightSigned>>(0);
+        let next_height = row.get::<_, Option<BlockHeightSigned>>(1);
+        let next_hash = row.get::<_, Option<Vec<u8>>>(2);
+
+        let history_start = until.map(|h| h as BlockHeight).unwrap_or(0);
+        Ok(match (until, next_height, next_hash) {
+            (Some(until), Some(next_height), Some(_)) if next_height < until => {
+                (history_start, None, true)
+            }
+            (Some(until), Some(next_height), Some(next_hash)) => (
+                history_start,
+                Some(super::BackwardSync {
+                    next: WithHeightAndId {
+                        height: next_height as BlockHeight,
+                        id: BlockHash::from_slice(&next_hash)?,
+                        data: (),
+                    },
+                    until: until as BlockHeight,
+                }),
+                false,
+            ),
+            _ => (history_start, None, false),
+        })
+    }
+
+    fn check_prepared_transactions_enabled(conn: &mut pg::Client) -> Result<()> {
+        let max: String = conn
+            .query_one("SHOW max_prepared_transactions", &[])?
+            .get(0);
+        let needed = PARALLEL_WRITER_TABLES.len() + 1;
+        if max.parse::<usize>()? < needed {
+            bail!(
+                "Parallel writers need `max_prepared_transactions` of at least {} (it's {})",
+                needed,
+                max
+            );
+        }
+        Ok(())
+    }
+
     fn init(conn: &mut pg::Client) -> Result<()> {
         info!("Creating initial db schema");
         conn.batch_execute(include_str!("pg/init.sql"))?;
         Ok(())
     }
 
-    fn stop_workers(&mut self) {
+    fn stop_workers(&mut self) -> Result<()> {
         debug!("Stopping DB pipeline workers");
-        self.pipeline.take();
+        if let Some(mut pipeline) = self.pipeline.take() {
+            if let Err(e) = pipeline.join() {
+                // nothing in flight is going to be written anymore
+                self.in_flight.lock().unwrap().clear();
+                return Err(e);
+            }
+        }
         debug!("Stopped DB pipeline workers");
         assert!(self.in_flight.lock().unwrap().is_empty());
+        Ok(())
     }
 
     fn are_workers_stopped(&self) -> bool {
@@ -1291,6 +2302,11 @@ impl IndexerStore {
             self.in_flight.clone(),
             self.mode,
             self.network,
+            self.backward.is_some(),
+            self.history_start > 0,
+            self.verify_blocks,
+            self.parallel_writers,
+            self.skip_witness,
         ))
     }
 
@@ -1298,16 +2314,17 @@ impl IndexerStore {
         if !self.are_workers_stopped() {
             self.flush_batch()?;
             if !self.in_flight.lock().unwrap().is_empty() {
-                self.flush_workers_unconditionally();
+                self.flush_workers_unconditionally()?;
             }
         }
 
         Ok(())
     }
 
-    fn flush_workers_unconditionally(&mut self) {
-        self.stop_workers();
+    fn flush_workers_unconditionally(&mut self) -> Result<()> {
+        self.stop_workers()?;
         self.start_workers();
+        Ok(())
     }
 
     // Flush all batch of work to the workers
@@ -1328,14 +2345,24 @@ impl IndexerStore {
         }
         drop(in_flight);
 
-        self.pipeline
+        let sent = self
+            .pipeline
             .as_ref()
             .expect("workers running")
             .tx
             .as_ref()
             .expect("tx not null")
-            .send((self.batch_id, batch))
-            .expect("Send should not fail");
+            .send((self.batch_id, batch));
+        if let Err(crossbeam_channel::SendError((_, batch))) = sent {
+            let mut in_flight = self.in_flight.lock().expect("locking works");
+            for block in &batch {
+                in_flight.remove(&block.id);
+            }
+            drop(in_flight);
+            // workers finished early; report why
+            self.stop_workers()?;
+            bail!("DB pipeline workers stopped unexpectedly");
+        }
         trace!("Batch flushed");
         self.batch_txs_total = 0;
         self.batch_id += 1;
@@ -1345,6 +2372,10 @@ impl IndexerStore {
     pub fn wipe(url: &str) -> Result<()> {
         info!("Wiping db schema");
         let mut connection = establish_connection(&url);
+        // they would keep the tables locked
+        for gid in prepared_transactions(&mut connection)? {
+            connection.batch_execute(&format!("ROLLBACK PREPARED '{}'", gid))?;
+        }
         connection.batch_execute(include_str!("pg/wipe.sql"))?;
         Ok(())
     }
@@ -1361,6 +2392,11 @@ impl IndexerStore {
     fn set_schema_to_mode(&mut self, mode: Mode) -> Result<()> {
         info!("Adjusting schema to mode: {}", mode);
         self.connection.batch_execute(mode.to_sql_query_str())?;
+        // spent outputs below `history_start` are never going to be there
+        if mode == Mode::Normal && self.history_start == 0 {
+            self.connection
+                .batch_execute(include_str!("pg/mode_normal_input_fk.sql"))?;
+        }
         Ok(())
     }
 
@@ -1415,7 +2451,7 @@ impl IndexerStore {
             // workers expect state of tables not to change while they are running
             // they need to be stopped
             self.flush_batch()?;
-            self.stop_workers();
+            self.stop_workers()?;
 
             let db_hash = Self::read_db_block_hash_by_height(&mut self.connection, block.height)?
                 .expect("Block at this height should already by indexed");
@@ -1577,26 +2613,337 @@ impl IndexerStore {
 
         let blocks = std::mem::replace(&mut self.batch, vec![]);
 
-        let mut utxo_set_cache = UtxoSetCache::default();
+        let mut utxo_set_cache = UtxoSetCache::new(self.history_start > 0);
         let tx_ids: TxIdMap = tx_id_map_from_blocks(&blocks, self.network)?;
+        if self.verify_blocks {
+            verify_blocks(&blocks, &tx_ids)?;
+        }
         let inputs_utxo_map = utxo_set_cache.process_blocks(&mut transaction, &blocks, &tx_ids)?;
+        let chain_stats = ChainStatsCache::default().process_blocks(&mut transaction, &blocks)?;
 
         let block_count = blocks.iter().count();
-        let insert_queries =
-            fmt_insert_blockdata_sql(&blocks, inputs_utxo_map, tx_ids, self.mode, self.network)?;
+        let insert_queries = fmt_insert_blockdata(
+            &blocks,
+            inputs_utxo_map,
+            tx_ids,
+            chain_stats,
+            self.mode,
+            self.network,
+            true,
+            self.skip_witness,
+        )?;
 
         commit_atomic_bulk_insert_sql(
             transaction,
             "all block data",
             block_count,
             0,
-            insert_queries.into_iter(),
+            insert_queries.into_iter().map(|(_, q)| q),
         )?;
 
         self.start_workers();
 
         Ok(())
     }
+
+    fn insert_backward(&mut self, block: crate::BlockData) -> Result<()> {
+        debug_assert!(!self.is_in_reorg());
+        debug_assert!(!self.are_workers_stopped());
+
+        let backward = self.backward.as_mut().expect("backward sync in progress");
+
+        trace!(
+            "Inserting backward block {}H {} down to {}H",
+            block.height,
+            block.id,
+            backward.until
+        );
+
+        if block.height != backward.next.height || block.id != backward.next.id {
+            bail!(
+                "Backward sync expected block {}H {}, got {}H {}",
+                backward.next.height,
+                backward.next.id,
+                block.height,
+                block.id
+            );
+        }
+
+        let height = block.height;
+        let until = backward.until;
+        backward.next = WithHeightAndId {
+            height: height.saturating_sub(1),
+            id: block.data.header.prev_blockhash,
+            data: (),
+        };
+
+        self.batch_txs_total += block.data.txdata.len() as u64;
+        self.batch.push(block);
+        self.chain_block_count = self.chain_block_count.max(height + 1);
+
+        if height == until {
+            self.flush_batch()?;
+            self.stop_workers()?;
+            self.finish_backward_sync()?;
+            self.start_workers();
+        } else if self.batch_txs_total > 100_000 {
+            self.flush_batch()?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill in everything that could not be written during backward sync
+    ///
+    /// All blocks have to be written already.
+    fn finish_backward_sync(&mut self) -> Result<()> {
+        debug_assert!(self.are_workers_stopped());
+
+        info!("Backward sync complete; filling in tx fees, block stats and events");
+        self.backward = None;
+        // indices are needed for the queries below
+        self.set_mode(Mode::Normal)?;
+
+        let mut transaction = self.connection.transaction()?;
+        trace_time(
+            || {
+                transaction.execute(
+                    "UPDATE tx SET fee = spent.value - (SELECT SUM(value) FROM output WHERE output.tx_hash_id = tx.hash_id)
+                    FROM (
+                        SELECT input.tx_hash_id, SUM(output.value) AS value
+                        FROM input LEFT JOIN output ON output.tx_hash_id = input.output_tx_hash_id AND output.tx_idx = input.output_tx_idx
+                        GROUP BY input.tx_hash_id
+                        HAVING COUNT(output.value) = COUNT(*)
+                    ) AS spent
+                    WHERE tx.hash_id = spent.tx_hash_id AND tx.fee IS NULL;",
+                    &[],
+                )?;
+                Ok(())
+            },
+            |duration, _| info!("Filled in tx fees in {}ms", duration.as_millis()),
+        )?;
+        trace_time(
+            || Self::fill_in_chain_stats(&mut transaction),
+            |duration, _| {
+                info!(
+                    "Filled in median time past and chainwork in {}ms",
+                    duration.as_millis()
+                )
+            },
+        )?;
+        transaction.execute(
+            "INSERT INTO event (block_hash_id) SELECT hash_id FROM block WHERE NOT extinct ORDER BY height ASC;",
+            &[],
+        )?;
+        transaction.execute(
+            "UPDATE indexer_state SET backward_next_height = NULL, backward_next_hash = NULL;",
+            &[],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Compute `ChainStats` of all the blocks, up from the lowest one
+    fn fill_in_chain_stats(conn: &mut impl pg::GenericClient) -> Result<()> {
+        let mut chain_stats_cache = ChainStatsCache::default();
+        let mut stats_c = BinaryCopyFormatter::new(
+            "block_chain_stats",
+            "COPY block_chain_stats (hash_id, median_time_past, chainwork) FROM STDIN (FORMAT binary)",
+        );
+        let rows = conn.query(
+            "SELECT hash_id, height, time, bits FROM block WHERE NOT extinct ORDER BY height ASC",
+            &[],
+        )?;
+        for (i, row) in rows.iter().enumerate() {
+            let height = row.get::<_, BlockHeightSigned>(1) as BlockHeight;
+            if i == 0 && height == 0 {
+                chain_stats_cache.chainwork = Some(Uint256::default());
+            }
+            let stats = chain_stats_cache.push(
+                height,
+                row.get::<_, i64>(2) as u32,
+                row.get::<_, Option<i64>>(3)
+                    .map(|bits| work_from_bits(bits as u32)),
+            );
+            stats_c.fmt_with(|r| {
+                r.bytea(row.get(0));
+                match stats.median_time_past {
+                    Some(time) => r.bigint(i64::from(time)),
+                    None => r.null(),
+                };
+                match stats.chainwork {
+                    Some(chainwork) => r.numeric(chainwork),
+                    None => r.null(),
+                };
+            });
+        }
+
+        conn.batch_execute(
+            "CREATE TEMP TABLE block_chain_stats (hash_id BYTEA NOT NULL, median_time_past BIGINT, chainwork NUMERIC) ON COMMIT DROP;",
+        )?;
+        if let Some((_, copy)) = stats_c.finish() {
+            copy.execute(conn)?;
+        }
+        conn.execute(
+            "UPDATE block SET median_time_past = stats.median_time_past, chainwork = stats.chainwork
+            FROM block_chain_stats AS stats WHERE block.hash_id = stats.hash_id;",
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// Fix the block stored at `block`'s height, and all its rows, atomically
+    ///
+    /// Relies on unique indices of the normal mode to skip rows that are
+    /// already there.
+    fn repair_block(&mut self, block: crate::BlockData) -> Result<bool> {
+        if self.backward.is_some() || self.is_in_reorg() {
+            bail!("Can't repair blocks during backward sync or a reorg");
+        }
+        if self.mode.is_bulk() {
+            bail!("Repair needs the db in normal mode; let the initial sync reach the chain-head first");
+        }
+        if self.chain_block_count <= block.height {
+            bail!(
+                "Block {}H is not indexed yet; nothing to repair",
+                block.height
+            );
+        }
+
+        // nothing can be in flight while we look at the tables
+        self.flush_workers()?;
+
+        let mut transaction = self.connection.transaction()?;
+        let block_hash_id = hash_to_hash_id(&block.id.as_hash());
+        let height = block.height as BlockHeightSigned;
+
+        let stored = Self::read_db_block_hash_by_height_trans(&mut transaction, block.height)?;
+        let replaced = stored != Some(block.id);
+        if replaced {
+            match stored {
+                Some(stored) => {
+                    info!(
+                        "Node block != db block at {}H; {} != {} - replacing",
+                        block.height, block.id, stored
+                    );
+                    let stored_hash_id = hash_to_hash_id(&stored.as_hash());
+                    transaction.execute(
+                        "INSERT INTO event (block_hash_id, revert) VALUES ($1, true);",
+                        &[&stored_hash_id],
+                    )?;
+                    transaction.execute(
+                        "UPDATE block SET extinct = true WHERE hash_id = $1;",
+                        &[&stored_hash_id],
+                    )?;
+                }
+                None => info!(
+                    "Missing db block at {}H; inserting {}",
+                    block.height, block.id
+                ),
+            }
+            transaction.execute(
+                "UPDATE tx SET current_height = NULL WHERE current_height = $1;",
+                &[&height],
+            )?;
+            // it might have been indexed already, and went extinct since
+            transaction.execute(
+                "UPDATE block SET extinct = false WHERE hash_id = $1;",
+                &[&block_hash_id],
+            )?;
+            transaction.execute(
+                "INSERT INTO event (block_hash_id) VALUES ($1);",
+                &[&block_hash_id],
+            )?;
+        }
+
+        let header = &block.data.header;
+        let header_fixed = transaction.execute(
+            "UPDATE block SET prev_hash_id = $2, merkle_root = $3, time = $4, version = $5, bits = $6, nonce = $7
+            WHERE hash_id = $1 AND (prev_hash_id, merkle_root, time, version, bits, nonce) IS DISTINCT FROM ($2, $3, $4, $5, $6, $7);",
+            &[
+                &block_hash_id,
+                &hash_to_hash_id(&header.prev_blockhash.as_hash()),
+                &header.merkle_root.as_hash().into_inner().to_vec(),
+                &i64::from(header.time),
+                &header.version,
+                &i64::from(header.bits),
+                &i64::from(header.nonce),
+            ],
+        )? > 0;
+        if header_fixed {
+            info!("Fixed header of db block {}H {}", block.height, block.id);
+        }
+
+        let txs = &block.data.txdata;
+        let expected = (
+            txs.len() as i64,
+            txs.iter().map(|tx| tx.output.len() as i64).sum::<i64>(),
+            txs.iter()
+                .filter(|tx| !tx.is_coin_base())
+                .map(|tx| tx.input.len() as i64)
+                .sum::<i64>(),
+        );
+        let row = transaction.query_one(
+            "SELECT
+            (SELECT COUNT(*) FROM block_tx JOIN tx ON tx.hash_id = block_tx.tx_hash_id WHERE block_tx.block_hash_id = $1),
+            (SELECT COUNT(*) FROM block_tx JOIN output ON output.tx_hash_id = block_tx.tx_hash_id WHERE block_tx.block_hash_id = $1),
+            (SELECT COUNT(*) FROM block_tx JOIN input ON input.tx_hash_id = block_tx.tx_hash_id WHERE block_tx.block_hash_id = $1);",
+            &[&block_hash_id],
+        )?;
+        let stored_counts = (row.get::<_, i64>(0), row.get(1), row.get(2));
+
+        if !replaced && stored_counts == expected {
+            transaction.commit()?;
+            return Ok(header_fixed);
+        }
+
+        if !replaced {
+            info!(
+                "Db block {}H {} has {}/{}/{} of {}/{}/{} txs/outputs/inputs - inserting missing ones",
+                block.height,
+                block.id,
+                stored_counts.0,
+                stored_counts.1,
+                stored_counts.2,
+                expected.0,
+                expected.1,
+                expected.2
+            );
+        }
+
+        let blocks = [block];
+        let tx_ids: TxIdMap = tx_id_map_from_blocks(&blocks, self.network)?;
+        if self.verify_blocks {
+            verify_blocks(&blocks, &tx_ids)?;
+        }
+        // spent outputs might be damaged too; fees of such txs stay unknown
+        let inputs_utxo_map =
+            UtxoSetCache::new(true).process_blocks(&mut transaction, &blocks, &tx_ids)?;
+        let chain_stats = ChainStatsCache::default().process_blocks(&mut transaction, &blocks)?;
+        let insert_queries = fmt_insert_blockdata(
+            &blocks,
+            inputs_utxo_map,
+            tx_ids,
+            chain_stats,
+            Mode::Normal,
+            self.network,
+            false,
+            false,
+        )?;
+        commit_atomic_bulk_insert_sql(
+            transaction,
+            "repaired block data",
+            1,
+            0,
+            insert_queries.into_iter().map(|(_, q)| q),
+        )?;
+
+        if replaced {
+            // utxos cached by the workers might be gone with the replaced block
+            self.flush_workers_unconditionally()?;
+        }
+        Ok(true)
+    }
 }
 
 /*
@@ -1705,7 +3052,9 @@ impl super::IndexerStore for IndexerStore {
     }
 
     fn insert(&mut self, block: crate::BlockData) -> Result<()> {
-        if self.is_in_reorg() {
+        if self.backward.is_some() {
+            self.insert_backward(block)?;
+        } else if self.is_in_reorg() {
             self.insert_when_in_reorg(block)?;
         } else {
             self.insert_when_at_tip(block)?;
@@ -1713,6 +3062,66 @@ impl super::IndexerStore for IndexerStore {
 
         Ok(())
     }
+
+    fn flush(&mut self) -> Result<Option<BlockHeight>> {
+        if self.is_in_reorg() {
+            // reorgs are only ever written as a whole
+            warn!(
+                "Discarding {} blocks of an unfinished reorg",
+                self.pending_reorg.len()
+            );
+            self.pending_reorg.clear();
+        } else {
+            self.flush_batch()?;
+        }
+        self.stop_workers()?;
+
+        self.get_head_height()
+    }
+
+    fn start_backward_sync(
+        &mut self,
+        tip: WithHeightAndId<BlockHash>,
+        until: BlockHeight,
+    ) -> Result<()> {
+        if self.mode != Mode::FreshBulk || self.backward.is_some() {
+            bail!("Backward sync can only be started on an empty db");
+        }
+        assert!(until <= tip.height);
+
+        info!(
+            "Starting backward sync from {}H {} down to {}H",
+            tip.height, tip.id, until
+        );
+        self.stop_workers()?;
+        self.connection.execute(
+            "UPDATE indexer_state SET backward_until = $1, backward_next_height = $2, backward_next_hash = $3",
+            &[
+                &(until as BlockHeightSigned),
+                &(tip.height as BlockHeightSigned),
+                &tip.id[..].to_vec(),
+            ],
+        )?;
+        self.history_start = until;
+        self.backward = Some(super::BackwardSync { next: tip, until });
+        self.start_workers();
+        Ok(())
+    }
+
+    fn get_backward_sync(&mut self) -> Result<Option<super::BackwardSync>> {
+        Ok(self.backward.as_ref().map(|backward| super::BackwardSync {
+            next: WithHeightAndId {
+                height: backward.next.height,
+                id: backward.next.id,
+                data: (),
+            },
+            until: backward.until,
+        }))
+    }
+
+    fn repair(&mut self, block: crate::BlockData) -> Result<bool> {
+        self.repair_block(block)
+    }
 }
 
 impl crate::event_source::EventSource for postgres::Client {
@@ -1842,3 +3251,109 @@ impl super::MempoolStore for MempoolStore {
         Ok(())
     }
 }
+
+/// Formatting and writing of block data, for `benches/pg_insert.rs`
+#[doc(hidden)]
+pub mod bench {
+    use super::*;
+
+    /// Inserts of some blocks, formatted one way or the other
+    pub struct Inserts(Vec<BatchQuery>);
+
+    impl Inserts {
+        /// Total size of the queries and data
+        pub fn len(&self) -> usize {
+            self.0
+                .iter()
+                .map(|q| match q {
+                    BatchQuery::Sql(s) => s.len(),
+                    BatchQuery::Copy { data, .. } => data.len(),
+                })
+                .sum()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    /// Format inserts of consecutive `blocks` like in bulk mode
+    ///
+    /// With `copy`, as `COPY`, otherwise as multi-value `INSERT`s.
+    pub fn fmt_bulk_inserts(
+        blocks: &[crate::BlockData],
+        network: bitcoin::Network,
+        copy: bool,
+    ) -> Result<Inserts> {
+        let tx_ids = tx_id_map_from_blocks(blocks, network)?;
+        // outputs spent from before `blocks` get no fee, as if not indexed
+        let mut utxo_set_cache = UtxoSetCache::new(true);
+        utxo_set_cache.insert_new_utxos_from_blocks(blocks, &tx_ids);
+        let (inputs_utxo_map, _) = utxo_set_cache.consume_spent_utxos_from_blocks(blocks);
+        // chainwork as if `blocks` started the chain
+        let mut chain_stats_cache = ChainStatsCache {
+            chainwork: Some(Uint256::default()),
+            ..default()
+        };
+        let chain_stats = blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.height,
+                    chain_stats_cache.push(
+                        block.height,
+                        block.data.header.time,
+                        Some(work_from_bits(block.data.header.bits)),
+                    ),
+                )
+            })
+            .collect();
+        Ok(Inserts(if copy {
+            fmt_copy_blockdata(
+                blocks,
+                inputs_utxo_map,
+                tx_ids,
+                chain_stats,
+                network,
+                true,
+                true,
+            )?
+            .into_iter()
+            .map(|(_, q)| q)
+            .collect()
+        } else {
+            fmt_insert_blockdata_sql(
+                blocks,
+                inputs_utxo_map,
+                tx_ids,
+                chain_stats,
+                Mode::FreshBulk,
+                network,
+                true,
+            )?
+            .into_iter()
+            .map(BatchQuery::Sql)
+            .collect()
+        }))
+    }
+
+    /// (Re)create schema `name` with empty tables, and switch `conn` to it
+    pub fn create_schema(conn: &mut postgres::Client, name: &str) -> Result<()> {
+        conn.batch_execute(&format!(
+            "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}; SET search_path TO {0};",
+            name
+        ))?;
+        conn.batch_execute(Mode::FreshBulk.to_sql_query_str())?;
+        Ok(())
+    }
+
+    /// Write `inserts` in a transaction, and roll it back
+    pub fn write_and_rollback(conn: &mut postgres::Client, inserts: &Inserts) -> Result<()> {
+        let mut transaction = conn.transaction()?;
+        for q in &inserts.0 {
+            q.execute(&mut transaction)?;
+        }
+        transaction.rollback()?;
+        Ok(())
+    }
+}
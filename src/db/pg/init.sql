@@ -8,6 +8,15 @@
 CREATE TABLE IF NOT EXISTS indexer_state (
   bulk_mode BOOLEAN NOT NULL
 );
+-- backward sync: lowest height indexed (history below is missing),
+-- and the next block to insert while it's in progress
+ALTER TABLE indexer_state ADD COLUMN IF NOT EXISTS backward_until INT;
+ALTER TABLE indexer_state ADD COLUMN IF NOT EXISTS backward_next_height INT;
+ALTER TABLE indexer_state ADD COLUMN IF NOT EXISTS backward_next_hash BYTEA;
+-- parallel writers: batch whose prepared transactions are all to be committed
+CREATE TABLE IF NOT EXISTS indexer_commit (
+  batch TEXT NOT NULL
+);
 
 -- events: append only
 -- you can follow them one by one,
@@ -26,13 +35,32 @@ CREATE INDEX IF NOT EXISTS event_block_hash_id ON event (block_hash_id);
 -- blocks: insert only
 CREATE TABLE IF NOT EXISTS block (
   time BIGINT NOT NULL, -- time from the block itself
+  median_time_past BIGINT, -- NULL if not known (yet), eg. during backward sync
+  bits BIGINT NOT NULL,
+  nonce BIGINT NOT NULL,
   height INT NOT NULL,
+  version INT NOT NULL,
+  size INT NOT NULL,
+  stripped_size INT NOT NULL,
+  weight INT NOT NULL,
+  tx_count INT NOT NULL,
   extinct BOOLEAN NOT NULL DEFAULT FALSE, -- this is the only mutable column in this table
   hash_id BYTEA NOT NULL UNIQUE PRIMARY KEY, -- the hash is split in two to save when referencing in other columns
   hash_rest BYTEA NOT NULL,
   prev_hash_id BYTEA NOT NULL,
-  merkle_root BYTEA NOT NULL
+  merkle_root BYTEA NOT NULL,
+  chainwork NUMERIC -- total work up to this block; NULL if not known (yet), like `median_time_past`
 );
+-- NULL for blocks indexed before these were added
+ALTER TABLE block ADD COLUMN IF NOT EXISTS median_time_past BIGINT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS bits BIGINT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS nonce BIGINT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS version INT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS size INT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS stripped_size INT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS weight INT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS tx_count INT;
+ALTER TABLE block ADD COLUMN IF NOT EXISTS chainwork NUMERIC;
 
 -- We always want these two, as a lot of logic is based
 -- on `block` table, and it's the smallest table overall,
@@ -46,34 +74,76 @@ CREATE INDEX IF NOT EXISTS block_extinct ON block (extinct) WHERE extinct = true
 -- block -> tx: insert only
 -- mapping between blocks and txes they include
 CREATE TABLE IF NOT EXISTS block_tx (
+  position INT NOT NULL, -- index of the tx in the block
   block_hash_id BYTEA NOT NULL,
   tx_hash_id BYTEA NOT NULL
 );
+-- NULL for txs indexed before it was added
+ALTER TABLE block_tx ADD COLUMN IF NOT EXISTS position INT;
 
 -- txs: insert only
 CREATE TABLE IF NOT EXISTS tx (
   mempool_ts TIMESTAMP DEFAULT NULL, -- NULL if it was indexed from an indexed block
-  fee BIGINT NOT NULL,
+  fee BIGINT, -- NULL if not known (yet), eg. during backward sync
   locktime BIGINT NOT NULL,
   current_height INT, -- Warning: mutable! But useful enough to keep it: especialy useful for mempool queries
   weight INT NOT NULL,
+  version INT NOT NULL,
+  size INT NOT NULL,
+  vsize INT NOT NULL,
+  input_count INT NOT NULL,
+  output_count INT NOT NULL,
   coinbase BOOLEAN NOT NULL,
+  segwit BOOLEAN NOT NULL, -- has any witness data
   hash_id BYTEA NOT NULL,
   hash_rest BYTEA NOT NULL
 );
+ALTER TABLE tx ALTER COLUMN fee DROP NOT NULL;
+-- NULL for txs indexed before these were added
+ALTER TABLE tx ADD COLUMN IF NOT EXISTS version INT;
+ALTER TABLE tx ADD COLUMN IF NOT EXISTS size INT;
+ALTER TABLE tx ADD COLUMN IF NOT EXISTS vsize INT;
+ALTER TABLE tx ADD COLUMN IF NOT EXISTS input_count INT;
+ALTER TABLE tx ADD COLUMN IF NOT EXISTS output_count INT;
+ALTER TABLE tx ADD COLUMN IF NOT EXISTS segwit BOOLEAN;
+
+-- kind of output script (`ScriptType`)
+DO $$
+BEGIN
+  IF to_regtype('script_type') IS NULL THEN
+    CREATE TYPE script_type AS ENUM (
+      'p2pk', 'p2pkh', 'p2sh', 'p2wpkh', 'p2wsh', 'p2tr', 'multisig', 'nulldata', 'nonstandard'
+    );
+  END IF;
+END;
+$$;
 
 -- outputs: insert only
 CREATE TABLE IF NOT EXISTS output (
   value BIGINT NOT NULL,
   tx_idx INT NOT NULL,
+  script_type script_type NOT NULL,
   tx_hash_id BYTEA NOT NULL,
-  address TEXT
+  address TEXT, -- NULL if the script has no address form
+  script_pubkey BYTEA NOT NULL
 );
+-- NULL for outputs indexed before these were added
+ALTER TABLE output ADD COLUMN IF NOT EXISTS script_type script_type;
+ALTER TABLE output ADD COLUMN IF NOT EXISTS script_pubkey BYTEA;
 
 -- input: insert only
 CREATE TABLE IF NOT EXISTS input (
+  sequence BIGINT NOT NULL,
+  vin INT NOT NULL, -- position in the tx
   output_tx_idx INT NOT NULL,
   has_witness BOOLEAN NOT NULL,
   output_tx_hash_id BYTEA NOT NULL, -- output id this tx input spends
-  tx_hash_id BYTEA NOT NULL -- tx id this input is from
+  tx_hash_id BYTEA NOT NULL, -- tx id this input is from
+  script_sig BYTEA NOT NULL,
+  witness BYTEA -- serialized witness stack; NULL if empty, or skipped with `--skip-witness`
 );
+-- NULL for inputs indexed before these were added
+ALTER TABLE input ADD COLUMN IF NOT EXISTS sequence BIGINT;
+ALTER TABLE input ADD COLUMN IF NOT EXISTS vin INT;
+ALTER TABLE input ADD COLUMN IF NOT EXISTS script_sig BYTEA;
+ALTER TABLE input ADD COLUMN IF NOT EXISTS witness BYTEA;
@@ -26,6 +26,7 @@ BEGIN
   END IF;
 END $$;
 DROP INDEX IF EXISTS block_tx_tx_hash_id_block_hash_id;
+DROP INDEX IF EXISTS block_tx_block_hash_id_position;
 
 DO $$
 BEGIN
@@ -57,6 +58,7 @@ END $$;
 
 DROP INDEX IF EXISTS tx_coinbase_eq_true;
 DROP INDEX IF EXISTS tx_current_height;
+DROP INDEX IF EXISTS tx_fee_rate;
 
 --- output
 
@@ -72,6 +74,8 @@ BEGIN
 END $$;
 DROP INDEX IF EXISTS output_address;
 DROP INDEX IF EXISTS output_value;
+DROP INDEX IF EXISTS output_script_type;
+DROP INDEX IF EXISTS output_script_pubkey;
 
 DO $$
 BEGIN
@@ -92,6 +96,7 @@ BEGIN
   END IF;
 END $$;
 DROP INDEX IF EXISTS input_tx_hash_id;
+DROP INDEX IF EXISTS input_output;
 
 DO $$
 BEGIN
@@ -4,6 +4,7 @@ DROP TABLE IF EXISTS tx CASCADE;
 DROP TABLE IF EXISTS block_tx CASCADE;
 DROP TABLE IF EXISTS block CASCADE;
 DROP TABLE IF EXISTS event CASCADE;
+DROP TYPE IF EXISTS script_type CASCADE;
 -- but not this one!
 -- DROP TABLE IF EXISTS indexer_state CASCADE;
 
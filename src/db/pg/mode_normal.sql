@@ -33,6 +33,7 @@ BEGIN
   END IF;
 END $$;
 CREATE UNIQUE INDEX IF NOT EXISTS block_tx_tx_hash_id_block_hash_id ON block_tx (tx_hash_id, block_hash_id);
+CREATE UNIQUE INDEX IF NOT EXISTS block_tx_block_hash_id_position ON block_tx (block_hash_id, position);
 
 DO $$
 BEGIN
@@ -73,6 +74,8 @@ $$;
 CREATE INDEX IF NOT EXISTS tx_coinbase_eq_true ON tx (coinbase) WHERE coinbase = true;
 CREATE INDEX IF NOT EXISTS tx_mempool_ts ON tx USING brin (mempool_ts);
 CREATE INDEX IF NOT EXISTS tx_current_height ON tx USING brin (current_height, mempool_ts);
+-- same expression as `fee_rate` in the views below, so ordering by it can use the index
+CREATE INDEX IF NOT EXISTS tx_fee_rate ON tx ((fee::double precision / vsize));
 
 --- output
 DO $$
@@ -85,6 +88,9 @@ BEGIN
 END $$;
 CREATE INDEX IF NOT EXISTS output_address ON output USING hash (address);
 CREATE INDEX IF NOT EXISTS output_value ON output (value);
+CREATE INDEX IF NOT EXISTS output_script_type ON output (script_type);
+-- to look up outputs by script, eg. ones with no address
+CREATE INDEX IF NOT EXISTS output_script_pubkey ON output USING hash (script_pubkey);
 
 DO $$
 BEGIN
@@ -104,10 +110,16 @@ BEGIN
   IF NOT EXISTS (
     SELECT constraint_name FROM information_schema.table_constraints WHERE table_name = 'input' AND constraint_type = 'PRIMARY KEY'
   ) THEN
-    ALTER TABLE input ADD PRIMARY KEY (output_tx_hash_id, output_tx_idx, tx_hash_id);
+    IF EXISTS (SELECT 1 FROM input WHERE vin IS NULL) THEN
+      -- some inputs were indexed before `vin` was added
+      ALTER TABLE input ADD PRIMARY KEY (output_tx_hash_id, output_tx_idx, tx_hash_id);
+      CREATE INDEX IF NOT EXISTS input_tx_hash_id ON input (tx_hash_id);
+    ELSE
+      ALTER TABLE input ADD PRIMARY KEY (tx_hash_id, vin);
+      CREATE INDEX IF NOT EXISTS input_output ON input (output_tx_hash_id, output_tx_idx);
+    END IF;
   END IF;
 END $$;
-CREATE INDEX IF NOT EXISTS input_tx_hash_id ON input (tx_hash_id);
 
 DO $$
 BEGIN
@@ -121,17 +133,8 @@ BEGIN
 END;
 $$;
 
-DO $$
-BEGIN
-  IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'fk_input_output') THEN
-    ALTER TABLE input
-    ADD CONSTRAINT fk_input_output FOREIGN KEY (output_tx_hash_id, output_tx_idx)
-      REFERENCES output(tx_hash_id, tx_idx)
-      ON DELETE CASCADE
-      DEFERRABLE INITIALLY DEFERRED;
-  END IF;
-END;
-$$;
+-- `fk_input_output` is in `mode_normal_input_fk.sql`, as it can't be
+-- there if history was only partially indexed
 
 --
 -- Utilities
@@ -164,6 +167,14 @@ CREATE OR REPLACE FUNCTION hash_to_hash_id(hash bytea) RETURNS bytea AS
 'SELECT reverse_bytes(substring(hash, 17, 32))'
 LANGUAGE SQL IMMUTABLE;
 
+-- views selecting `*` can't be replaced once their tables gained columns,
+-- so they are recreated from scratch
+DROP VIEW IF EXISTS tx_with_block;
+DROP VIEW IF EXISTS tx_maybe_with_block;
+DROP VIEW IF EXISTS tx_with_hash_in_mempool;
+DROP VIEW IF EXISTS tx_in_mempool;
+DROP VIEW IF EXISTS tx_with_hash;
+DROP VIEW IF EXISTS block_with_hash;
 
 CREATE OR REPLACE VIEW tx_with_hash AS
   SELECT *,
@@ -181,6 +192,7 @@ CREATE OR REPLACE VIEW block_with_hash AS
 CREATE OR REPLACE VIEW tx_maybe_with_block AS
   SELECT tx.*,
   reverse_bytes(tx.hash_id || tx.hash_rest) AS hash,
+  tx.fee::double precision / tx.vsize AS fee_rate, -- sat/vB
   block.hash_id AS block_hash_id,
   block.hash_rest AS block_hash_rest,
   block.height AS block_height,
@@ -217,14 +229,16 @@ CREATE OR REPLACE VIEW tx_hash_ids_in_mempool AS
 
 CREATE OR REPLACE VIEW tx_in_mempool AS
   SELECT
-    *
+    *,
+    fee::double precision / vsize AS fee_rate -- sat/vB
   FROM tx
   WHERE
     hash_id IN (SELECT * FROM tx_hash_ids_in_mempool);
 
 CREATE OR REPLACE VIEW tx_with_hash_in_mempool AS
   SELECT
-    *
+    *,
+    fee::double precision / vsize AS fee_rate -- sat/vB
   FROM tx_with_hash
   WHERE
     hash_id IN (SELECT * FROM tx_hash_ids_in_mempool);
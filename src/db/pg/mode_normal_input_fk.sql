@@ -0,0 +1,14 @@
+-- normal mode schema, only for dbs with full history: every input
+-- spends an output that is indexed too
+
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'fk_input_output') THEN
+    ALTER TABLE input
+    ADD CONSTRAINT fk_input_output FOREIGN KEY (output_tx_hash_id, output_tx_idx)
+      REFERENCES output(tx_hash_id, tx_idx)
+      ON DELETE CASCADE
+      DEFERRABLE INITIALLY DEFERRED;
+  END IF;
+END;
+$$;
@@ -4,7 +4,9 @@ DROP TABLE IF EXISTS tx CASCADE;
 DROP TABLE IF EXISTS block_tx CASCADE;
 DROP TABLE IF EXISTS block CASCADE;
 DROP TABLE IF EXISTS event CASCADE;
+DROP TYPE IF EXISTS script_type CASCADE;
 DROP TABLE IF EXISTS indexer_state CASCADE;
+DROP TABLE IF EXISTS indexer_commit CASCADE;
 
 DROP FUNCTION IF EXISTS reverse_bytes_iter CASCADE;
 DROP FUNCTION IF EXISTS hash_from_parts CASCADE;
@@ -1,50 +1,80 @@
 use diesel::sql_types::*;
 
+/// `script_type` enum
+#[derive(SqlType)]
+#[postgres(type_name = "script_type")]
+pub struct ScriptType;
+
 table! {
     block (hash_id) {
         time -> BigInt,
+        median_time_past -> Nullable<BigInt>,
+        bits -> Nullable<BigInt>,
+        nonce -> Nullable<BigInt>,
         height -> Integer,
+        version -> Nullable<Integer>,
+        size -> Nullable<Integer>,
+        stripped_size -> Nullable<Integer>,
+        weight -> Nullable<Integer>,
+        tx_count -> Nullable<Integer>,
         extinct -> Bool,
         hash_id -> Binary,
         hash_rest -> Binary,
         prev_hash_id -> Binary,
         merkle_root -> Binary,
+        chainwork -> Nullable<Numeric>,
     }
 }
 
 table! {
     block_tx (block_hash_id) {
+        position -> Nullable<Integer>,
         block_hash_id -> Binary,
         tx_hash_id -> Binary,
     }
 }
 
 table! {
-    input (output_tx_hash_id) {
+    input (tx_hash_id, vin) {
+        sequence -> Nullable<BigInt>,
+        vin -> Nullable<Integer>,
         output_tx_idx -> Integer,
         has_witness -> Bool,
         output_tx_hash_id -> Binary,
         tx_hash_id -> Binary,
+        script_sig -> Nullable<Binary>,
+        witness -> Nullable<Binary>,
     }
 }
 
 table! {
+    use diesel::sql_types::*;
+    use super::ScriptType;
+
     output (tx_hash_id, tx_idx) {
         value -> BigInt,
         tx_idx -> Integer,
+        script_type -> Nullable<ScriptType>,
         tx_hash_id -> Binary,
         address -> Nullable<Text>,
+        script_pubkey -> Nullable<Binary>,
     }
 }
 
 table! {
     tx (hash_id) {
         mempool_ts -> Nullable<Timestamp>,
-        fee -> BigInt,
+        fee -> Nullable<BigInt>,
         locktime -> BigInt,
-        current_height -> Integer,
+        current_height -> Nullable<Integer>,
         weight -> Integer,
+        version -> Nullable<Integer>,
+        size -> Nullable<Integer>,
+        vsize -> Nullable<Integer>,
+        input_count -> Nullable<Integer>,
+        output_count -> Nullable<Integer>,
         coinbase -> Bool,
+        segwit -> Nullable<Bool>,
         hash_id -> Binary,
         hash_rest -> Binary,
     }
@@ -8,13 +8,18 @@ pub mod node;
 pub mod opts;
 pub mod prelude;
 pub mod util;
+pub mod verify;
 use prelude::*;
 
 pub mod types;
 pub use crate::{BlockData, BlockHeight, WithHeightAndId};
 pub use types::*;
 
-use std::fmt::{Debug, Display};
+use bitcoin::util::uint::Uint256;
+use std::{
+    fmt::{Debug, Display},
+    time::Duration,
+};
 
 /// `Block` specialized over types from `Rpc`
 type RpcBlock<R> = WithHeightAndId<<R as Rpc>::Id, <R as Rpc>::Data>;
@@ -23,52 +28,434 @@ type RpcBlock<R> = WithHeightAndId<<R as Rpc>::Id, <R as Rpc>::Data>;
 struct RpcBlockWithPrevId<R: Rpc> {
     block: RpcBlock<R>,
     prev_block_id: R::Id,
+    /// `block.data.data_size()`, counted against prefetcher's byte budget
+    data_size: usize,
+}
+
+/// Approximate size of block data, for limiting how much of it is held in memory
+pub trait DataSize {
+    fn data_size(&self) -> usize;
+}
+
+/// Serialized size; good enough, as all that matters is staying in the right ballpark
+impl DataSize for Box<bitcoin::Block> {
+    fn data_size(&self) -> usize {
+        self.get_size()
+    }
+}
+
+impl DataSize for usize {
+    fn data_size(&self) -> usize {
+        std::mem::size_of::<usize>()
+    }
+}
+
+impl DataSize for () {
+    fn data_size(&self) -> usize {
+        0
+    }
 }
 
 /// An minimum interface for node rpc that prefetcher can work with
 pub trait Rpc: Send + Sync {
-    type Data: Send;
+    type Data: Send + DataSize;
     type Id: Send + Eq + PartialEq + Display + Debug + Clone;
     const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64;
     const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64;
 
+    /// How long to wait before asking again, when the node has no new blocks
+    ///
+    /// `RECOMMENDED_HEAD_RETRY_DELAY_MS`, unless it depends on the node
+    /// behind `self` (eg. for `node::any::AnyRpc`).
+    fn head_retry_delay_ms(&self) -> u64 {
+        Self::RECOMMENDED_HEAD_RETRY_DELAY_MS
+    }
+
+    /// How long to wait before retrying a failed call
+    ///
+    /// `RECOMMENDED_ERROR_RETRY_DELAY_MS`; see `head_retry_delay_ms`.
+    fn error_retry_delay_ms(&self) -> u64 {
+        Self::RECOMMENDED_ERROR_RETRY_DELAY_MS
+    }
+
     fn get_block_count(&self) -> Result<BlockHeight>;
 
+    /// Block count, along with the total work of the chain, if known
+    ///
+    /// Lets `node::pool::RpcPool` follow the chain with the most work,
+    /// not just the longest one.
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        Ok((self.get_block_count()?, None))
+    }
+
     fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>>;
 
     /// Get the block by id, along with id of the previous block
     fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>>;
+
+    /// How many heights should a worker claim at once during fast sync
+    ///
+    /// Makes sense to be more than 1 only if the batch calls below are
+    /// cheaper than the single-block ones.
+    const RECOMMENDED_BATCH_SIZE: usize = 1;
+
+    /// Get ids of up to `count` blocks, starting at `start` height
+    ///
+    /// Stops at the first height the node has no block at, so the result
+    /// can be shorter than `count`.
+    fn get_block_ids_by_heights(&self, start: BlockHeight, count: usize) -> Result<Vec<Self::Id>> {
+        let mut ids = Vec::with_capacity(count);
+        for height in (start..).take(count) {
+            match self.get_block_id_by_height(height)? {
+                Some(id) => ids.push(id),
+                None => break,
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Get multiple blocks by ids, along with ids of their previous blocks
+    #[allow(clippy::type_complexity)]
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        ids.iter().map(|id| self.get_block_by_id(id)).collect()
+    }
+}
+
+/// `Rpc`, for nodes talked to asynchronously
+///
+/// See `node::async_prefetcher` for a `Stream` of blocks using it,
+/// and `node::async_prefetcher::BlockingRpc` to use any `Rpc` as one.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncRpc: Send + Sync {
+    type Data: Send;
+    type Id: Send + Sync + Eq + PartialEq + Display + Debug + Clone;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64;
+    /// See `Rpc::RECOMMENDED_BATCH_SIZE`
+    const RECOMMENDED_BATCH_SIZE: usize = 1;
+
+    /// See `Rpc::head_retry_delay_ms`
+    fn head_retry_delay_ms(&self) -> u64 {
+        Self::RECOMMENDED_HEAD_RETRY_DELAY_MS
+    }
+
+    /// See `Rpc::error_retry_delay_ms`
+    fn error_retry_delay_ms(&self) -> u64 {
+        Self::RECOMMENDED_ERROR_RETRY_DELAY_MS
+    }
+
+    async fn get_block_count(&self) -> Result<BlockHeight>;
+
+    async fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>>;
+
+    /// Get the block by id, along with id of the previous block
+    async fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>>;
+
+    /// See `Rpc::get_block_ids_by_heights`
+    async fn get_block_ids_by_heights(
+        &self,
+        start: BlockHeight,
+        count: usize,
+    ) -> Result<Vec<Self::Id>> {
+        let mut ids = Vec::with_capacity(count);
+        for height in (start..).take(count) {
+            match self.get_block_id_by_height(height).await? {
+                Some(id) => ids.push(id),
+                None => break,
+            }
+        }
+        Ok(ids)
+    }
+
+    /// See `Rpc::get_blocks_by_ids`
+    #[allow(clippy::type_complexity)]
+    async fn get_blocks_by_ids(
+        &self,
+        ids: &[Self::Id],
+    ) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let mut blocks = Vec::with_capacity(ids.len());
+        for id in ids {
+            blocks.push(self.get_block_by_id(id).await?);
+        }
+        Ok(blocks)
+    }
+}
+
+/// Which node interface to talk to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcKind {
+    /// JSON-RPC (`http://`)
+    JsonRpc,
+    /// Binary REST interface (`rest+http://`)
+    Rest,
+    /// Esplora HTTP API (`esplora+https://`)
+    Esplora,
+    /// Answers recorded with `node::replay::RecordingRpc` (`replay:<path>`)
+    Replay,
+    /// Node's `blk*.dat` files (`blk:<blocks dir>`)
+    BlkFiles,
+    /// Bitcoin P2P protocol (`p2p://host:port`)
+    P2p,
+}
+
+/// Default timeout for connecting to nodes over http
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default timeout for reading node's http responses
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Parse a node url timeout parameter, given in seconds
+fn timeout_param(key: &str, value: &str) -> Result<Duration> {
+    match value.parse() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => bail!("Invalid node url `{}` parameter: {}", key, value),
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct RpcInfo {
     pub url: String,
     pub auth: bitcoincore_rpc::Auth,
+    pub kind: RpcKind,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    /// Network of the node, for interfaces that can't ask it;
+    /// `None` if not given in the url
+    pub network: Option<bitcoin::Network>,
 }
 
 impl RpcInfo {
+    /// Parse node URL
+    ///
+    /// `rest+http://` and `rest+https://` schemes select Core's REST interface,
+    /// `esplora+http://` and `esplora+https://` an Esplora API.
+    ///
+    /// `conf:<path>` reads the url and auth from node's `bitcoin.conf`.
+    ///
+    /// `replay:<path>` serves answers from a recording instead of a node.
+    ///
+    /// `blk:<path>` reads blocks from node's `blocks` directory; its network
+    /// is detected from the files, unless given with `network=<chain>`
+    /// (`main`, `test`, `signet` or `regtest`).
+    ///
+    /// `p2p://<host>[:<port>]` talks to a node over P2P protocol; the network
+    /// is given with `network=<chain>` (default: `main`), and the port
+    /// defaults to that network's one. Timeouts can be set as for other urls.
+    ///
+    /// Supported query parameters:
+    ///
+    /// * `cookie=<path>` - use a cookie file for auth
+    /// * `password_file=<path>` - read the password of the user in the url from a file
+    ///   (eg. for `rpcauth` users)
+    /// * `connect_timeout=<secs>`, `read_timeout=<secs>`
     pub fn from_url(url_str: &str) -> Result<Self> {
+        let (kind, url_str) = if let Some(rest) = url_str.strip_prefix("rest+") {
+            (RpcKind::Rest, rest)
+        } else if let Some(esplora) = url_str.strip_prefix("esplora+") {
+            (RpcKind::Esplora, esplora)
+        } else {
+            (RpcKind::JsonRpc, url_str)
+        };
         let mut url = url::Url::parse(url_str)?;
-        let auth = match (url.username() == "", url.password()) {
-            (false, Some(p)) => {
-                bitcoincore_rpc::Auth::UserPass(url.username().to_owned(), p.to_owned())
+
+        if url.scheme() == "replay" {
+            if kind != RpcKind::JsonRpc || url.query().is_some() {
+                bail!("Replay url takes only a path: {}", url_str);
+            }
+            return Ok(Self {
+                url: url.path().to_owned(),
+                auth: bitcoincore_rpc::Auth::None,
+                kind: RpcKind::Replay,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                network: None,
+            });
+        }
+
+        if url.scheme() == "blk" {
+            if kind != RpcKind::JsonRpc {
+                bail!("Blk files url can't have a prefix: {}", url_str);
+            }
+            let mut network = None;
+            for (key, value) in url.query_pairs() {
+                match &*key {
+                    "network" => network = Some(util::bitcoin::network_from_str(&value)?),
+                    _ => bail!("Unknown blk files url parameter `{}`", key),
+                }
+            }
+            return Ok(Self {
+                url: url.path().to_owned(),
+                auth: bitcoincore_rpc::Auth::None,
+                kind: RpcKind::BlkFiles,
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                read_timeout: DEFAULT_READ_TIMEOUT,
+                network,
+            });
+        }
+
+        if url.scheme() == "p2p" {
+            if kind != RpcKind::JsonRpc || !url.username().is_empty() || url.password().is_some() {
+                bail!("P2P url takes no prefix nor auth: {}", url_str);
+            }
+            let mut network = bitcoin::Network::Bitcoin;
+            let mut connect_timeout = DEFAULT_CONNECT_TIMEOUT;
+            let mut read_timeout = DEFAULT_READ_TIMEOUT;
+            for (key, value) in url.query_pairs() {
+                match &*key {
+                    "network" => network = util::bitcoin::network_from_str(&value)?,
+                    "connect_timeout" => connect_timeout = timeout_param(&key, &value)?,
+                    "read_timeout" => read_timeout = timeout_param(&key, &value)?,
+                    _ => bail!("Unknown P2P url parameter `{}`", key),
+                }
+            }
+            let host = match url.host_str() {
+                Some(host) if !host.is_empty() => host,
+                _ => bail!("P2P url needs a host: {}", url_str),
+            };
+            let port = url
+                .port()
+                .unwrap_or_else(|| node::p2p::default_port(network));
+            return Ok(Self {
+                url: format!("{}:{}", host, port),
+                auth: bitcoincore_rpc::Auth::None,
+                kind: RpcKind::P2p,
+                connect_timeout,
+                read_timeout,
+                network: Some(network),
+            });
+        }
+
+        let mut cookie = None;
+        let mut password_file = None;
+        let mut connect_timeout = DEFAULT_CONNECT_TIMEOUT;
+        let mut read_timeout = DEFAULT_READ_TIMEOUT;
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "cookie" => cookie = Some(std::path::PathBuf::from(&*value)),
+                "password_file" => password_file = Some(std::path::PathBuf::from(&*value)),
+                "connect_timeout" => connect_timeout = timeout_param(&key, &value)?,
+                "read_timeout" => read_timeout = timeout_param(&key, &value)?,
+                _ => bail!("Unknown node url parameter `{}`", key),
+            }
+        }
+        url.set_query(None);
+
+        let auth = match (url.username(), url.password(), cookie, password_file) {
+            ("", None, None, None) => bitcoincore_rpc::Auth::None,
+            ("", None, Some(cookie), None) => bitcoincore_rpc::Auth::CookieFile(cookie),
+            (user, Some(pass), None, None) if !user.is_empty() => {
+                bitcoincore_rpc::Auth::UserPass(user.to_owned(), pass.to_owned())
+            }
+            (user, None, None, Some(password_file)) if !user.is_empty() => {
+                match std::fs::read_to_string(&password_file) {
+                    Ok(pass) => {
+                        bitcoincore_rpc::Auth::UserPass(user.to_owned(), pass.trim().to_owned())
+                    }
+                    Err(e) => bail!("Couldn't read {}: {}", password_file.display(), e),
+                }
             }
-            (true, None) => bitcoincore_rpc::Auth::None,
             _ => bail!("Incorrect node auth parameters"),
         };
-        url.set_password(None).expect("url lib sane");
-        url.set_username("").expect("url lib sane");
+        if kind == RpcKind::Esplora && !matches!(auth, bitcoincore_rpc::Auth::None) {
+            bail!("Esplora API takes no auth parameters");
+        }
+
+        let (url, auth) = if url.scheme() == "conf" {
+            let path = std::path::Path::new(url.path());
+            let conf = node::bitcoin_conf::BitcoinConf::from_file(path)?;
+            if kind == RpcKind::Esplora {
+                bail!("Esplora API can't be configured from bitcoin.conf");
+            }
+            if kind == RpcKind::Rest && !conf.rest {
+                bail!("REST interface is not enabled in {}", path.display());
+            }
+            // auth in the url takes precedence
+            match auth {
+                bitcoincore_rpc::Auth::None => (conf.url(), conf.auth()),
+                auth => (conf.url(), auth),
+            }
+        } else {
+            url.set_password(None).expect("url lib sane");
+            url.set_username("").expect("url lib sane");
+            (url.to_string(), auth)
+        };
 
         Ok(Self {
-            url: url.to_string(),
+            url,
             auth,
+            kind,
+            connect_timeout,
+            read_timeout,
+            network: None,
         })
     }
-    pub fn to_rpc_client(&self) -> Result<bitcoincore_rpc::Client> {
-        Ok(bitcoincore_rpc::Client::new(
-            self.url.clone(),
+
+    /// Parse a comma-separated list of node URLs
+    pub fn from_url_list(urls: &str) -> Result<Vec<Self>> {
+        let infos = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(Self::from_url)
+            .collect::<Result<Vec<_>>>()?;
+        if infos.is_empty() {
+            bail!("No node url given");
+        }
+        Ok(infos)
+    }
+
+    pub fn to_rpc_client(&self) -> Result<node::jsonrpc::JsonRpcClient> {
+        if self.kind != RpcKind::JsonRpc {
+            bail!("{} is not a JSON-RPC node url", self.url);
+        }
+        Ok(node::jsonrpc::JsonRpcClient::new(
+            &self.url,
             self.auth.clone(),
-        )?)
+            self.connect_timeout,
+            self.read_timeout,
+        ))
+    }
+
+    /// Create a client for whichever interface the url selected
+    pub fn to_any_rpc(&self) -> Result<node::any::AnyRpc> {
+        Ok(match self.kind {
+            RpcKind::JsonRpc => node::any::AnyRpc::JsonRpc(self.to_rpc_client()?),
+            RpcKind::Rest => node::any::AnyRpc::Rest(node::rest::RestRpc::with_timeouts(
+                &self.url,
+                self.connect_timeout,
+                self.read_timeout,
+            )),
+            RpcKind::Esplora => {
+                node::any::AnyRpc::Esplora(node::esplora::EsploraRpc::with_timeouts(
+                    &self.url,
+                    self.connect_timeout,
+                    self.read_timeout,
+                ))
+            }
+            RpcKind::Replay => node::any::AnyRpc::Replay(node::replay::ReplayRpc::open(
+                std::path::Path::new(&self.url),
+            )?),
+            RpcKind::BlkFiles => {
+                let network = match self.network {
+                    Some(network) => network,
+                    None => node::blk_files::BlkFilesRpc::detect_network(&self.url)?,
+                };
+                node::any::AnyRpc::BlkFiles(node::blk_files::BlkFilesRpc::new(&self.url, network)?)
+            }
+            RpcKind::P2p => {
+                let addr = match std::net::ToSocketAddrs::to_socket_addrs(&*self.url)?.next() {
+                    Some(addr) => addr,
+                    None => bail!("Couldn't resolve {}", self.url),
+                };
+                let network = self.network.expect("p2p urls always have a network");
+                node::any::AnyRpc::P2p(node::p2p::P2pRpc::with_timeouts(
+                    addr,
+                    network,
+                    self.connect_timeout,
+                    self.read_timeout,
+                ))
+            }
+        })
     }
 }
 #[cfg(test)]
@@ -1,38 +1,67 @@
 #![allow(unused)] // need some cleanup
 
 use bitcoin_indexer::{
-    db, node::prefetcher, opts, prelude::*, types::*, util::BottleCheck, RpcInfo,
+    db,
+    node::{
+        any::AnyRpc, backward::BackwardFetcher, fetcher, headers::HeadersFirst, pool::RpcPool,
+        prefetcher, replay::RecordingRpc,
+    },
+    opts,
+    prelude::*,
+    types::*,
+    util::BottleCheck,
+    Rpc, RpcInfo,
+};
+use log::{error, info, warn};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
-use bitcoincore_rpc::RpcApi;
-use log::info;
-use std::{env, sync::Arc};
 
 use common_failures::{prelude::*, quick_main};
 
-struct Indexer {
+struct Indexer<R> {
     node_starting_chainhead_height: BlockHeight,
-    rpc: Arc<bitcoincore_rpc::Client>,
-    db: Box<dyn db::IndexerStore>,
+    rpc: Arc<R>,
+    db: Arc<Mutex<dyn db::IndexerStore + Send>>,
     bottlecheck_db: BottleCheck,
+    zmq_url: Option<String>,
 }
 
-impl Indexer {
-    fn new(config: Config) -> Result<Self> {
-        let rpc_info = bitcoin_indexer::RpcInfo::from_url(&config.node_url)?;
-        let rpc = rpc_info.to_rpc_client()?;
+impl<R> Indexer<R>
+where
+    R: Rpc<Id = BlockHash, Data = Box<bitcoin::Block>> + 'static,
+{
+    fn new(
+        config: Config,
+        rpc: R,
+        network: bitcoin::Network,
+        verify_blocks: bool,
+        parallel_writers: bool,
+        skip_witness: bool,
+    ) -> Result<Self> {
         let rpc = Arc::new(rpc);
-        let node_starting_chainhead_height = rpc.get_block_count()? as BlockHeight;
-        let network =
-            bitcoin_indexer::util::bitcoin::network_from_str(&rpc.get_blockchain_info()?.chain)?;
-        let mut db =
-            db::pg::IndexerStore::new(config.db_url, node_starting_chainhead_height, network)?;
+        let node_starting_chainhead_height = rpc.get_block_count()?;
+        let mut db = db::pg::IndexerStore::new(
+            config.db_url,
+            node_starting_chainhead_height,
+            network,
+            verify_blocks,
+            parallel_writers,
+            skip_witness,
+        )?;
         info!("Node chain-head at {}H", node_starting_chainhead_height);
 
         Ok(Self {
             rpc,
             node_starting_chainhead_height,
-            db: Box::new(db),
+            db: Arc::new(Mutex::new(db)),
             bottlecheck_db: BottleCheck::new("database".into()),
+            zmq_url: config.zmq_url,
         })
     }
 
@@ -48,42 +77,304 @@ impl Indexer {
             ..
         } = self;
 
-        bottlecheck_db.check(|| db.insert(block))?;
+        bottlecheck_db.check(|| db.lock().expect("lock works").insert(block))?;
         Ok(())
     }
 
-    fn run(&mut self) -> Result<()> {
-        let start: Option<WithHeightAndId<BlockHash, _>> =
-            if let Some(last_indexed_height) = self.db.get_head_height()? {
+    /// Repair blocks, if requested, or index them as usual
+    fn run_with_opts(&mut self, opts: &opts::Opts) -> Result<()> {
+        if opts.prefetch_budget_mb == 0 {
+            bail!("`--prefetch-budget-mb` must be more than 0");
+        }
+        match (opts.repair_from, opts.repair_to) {
+            (Some(start), end) => self.repair(start, end),
+            (None, Some(_)) => bail!("`--repair-to` needs `--repair-from`"),
+            (None, None) => self.run(
+                Duration::from_secs(opts.shutdown_timeout_secs),
+                opts.backward_until,
+                opts.prefetch_budget_mb * 1024 * 1024,
+            ),
+        }
+    }
+
+    /// Index blocks until a shutdown signal arrives
+    ///
+    /// With `backward_until` set, on an empty db, blocks are indexed
+    /// from the node's tip down to that height first.
+    ///
+    /// After a signal, blocks already passed to the db are written out,
+    /// unless it takes longer than `shutdown_timeout`.
+    ///
+    /// Fetched blocks waiting to be indexed take up to about `prefetch_budget` bytes.
+    fn run(
+        &mut self,
+        shutdown_timeout: Duration,
+        backward_until: Option<BlockHeight>,
+        prefetch_budget: usize,
+    ) -> Result<()> {
+        let signal = ShutdownSignal::install(shutdown_timeout)?;
+        if !self.run_backward(backward_until, &signal)? {
+            return Ok(());
+        }
+
+        let start: Option<WithHeightAndId<BlockHash, _>> = {
+            let mut db = self.db.lock().expect("lock works");
+            if let Some(last_indexed_height) = db.get_head_height()? {
                 info!("Last indexed block {}H", last_indexed_height);
 
-                assert!(last_indexed_height <= self.node_starting_chainhead_height);
-                let start_from_block = last_indexed_height.saturating_sub(100); // redo 100 last blocks, in case there was a reorg
+                // no need to redo any blocks in case there was a reorg
+                // while we were down; prefetcher will find the fork point
                 Some(WithHeightAndId {
-                    height: start_from_block,
-                    id: self
-                        .db
-                        .get_hash_by_height(start_from_block)?
+                    height: last_indexed_height,
+                    id: db
+                        .get_hash_by_height(last_indexed_height)?
                         .expect("Block hash should be there"),
                     data: (),
                 })
             } else {
                 None
-            };
+            }
+        };
+
+        let hash_lookup: prefetcher::HashLookup<BlockHash> = Box::new({
+            let db = self.db.clone();
+            move |height| db.lock().expect("lock works").get_hash_by_height(height)
+        });
+        let mut prefetcher = prefetcher::Prefetcher::new_with_hash_lookup(
+            self.rpc.clone(),
+            start,
+            Some(hash_lookup),
+        )?;
+        let _subscriber = subscribe_tip(self.zmq_url.as_deref(), prefetcher.tip_notify())?;
+        signal.set_target(prefetcher.shutdown_handle());
+        let byte_budget = prefetcher.byte_budget();
+        byte_budget.set_limit(prefetch_budget);
+        let concurrency = prefetcher.concurrency();
+        let buffer_status = move || format!("prefetched blocks: {}, {}", byte_budget, concurrency);
+        self.bottlecheck_db.set_status(buffer_status.clone());
+        let mut bottlecheck_fetcher = BottleCheck::new("block fetcher".into());
+        bottlecheck_fetcher.set_status(buffer_status);
+        for item in bottlecheck_fetcher.check_iter(&mut prefetcher) {
+            self.process_block(item)?;
+        }
+
+        let flushed = self.db.lock().expect("lock works").flush()?;
+        if let Some(e) = prefetcher.take_error() {
+            return Err(e);
+        }
+        match flushed {
+            Some(height) => info!("Shut down; last committed block {}H", height),
+            None => info!("Shut down; no blocks committed"),
+        }
+        Ok(())
+    }
 
-        let prefetcher = prefetcher::Prefetcher::new(self.rpc.clone(), start)?;
+    /// Index blocks backward, if requested on an empty db, or resumed
+    ///
+    /// Returns `false` if interrupted by a shutdown signal before completing.
+    fn run_backward(
+        &mut self,
+        until: Option<BlockHeight>,
+        signal: &ShutdownSignal,
+    ) -> Result<bool> {
+        let backward = {
+            let mut db = self.db.lock().expect("lock works");
+            match (db.get_backward_sync()?, until) {
+                (Some(backward), _) => {
+                    info!(
+                        "Resuming backward sync at {}H, down to {}H",
+                        backward.next.height, backward.until
+                    );
+                    backward
+                }
+                (None, Some(until)) if db.get_head_height()?.is_none() => {
+                    let tip_height = self.rpc.get_block_count()?;
+                    let tip_id = match self.rpc.get_block_id_by_height(tip_height)? {
+                        Some(id) => id,
+                        None => bail!("Node has no block at its chain-head {}H", tip_height),
+                    };
+                    let tip = WithHeightAndId {
+                        height: tip_height,
+                        id: tip_id,
+                        data: (),
+                    };
+                    db.start_backward_sync(tip, until.min(tip_height))?;
+                    db.get_backward_sync()?.expect("backward sync just started")
+                }
+                (None, Some(_)) => {
+                    warn!("Db is not empty; ignoring `--backward-until`");
+                    return Ok(true);
+                }
+                (None, None) => return Ok(true),
+            }
+        };
+
+        let fetcher = BackwardFetcher::new(self.rpc.clone(), backward.next, backward.until);
+        signal.set_target(fetcher.shutdown_handle());
         let mut bottlecheck_fetcher = BottleCheck::new("block fetcher".into());
-        for item in bottlecheck_fetcher.check_iter(prefetcher) {
+        for item in bottlecheck_fetcher.check_iter(fetcher) {
             self.process_block(item)?;
         }
 
+        let mut db = self.db.lock().expect("lock works");
+        if db.get_backward_sync()?.is_none() {
+            return Ok(true);
+        }
+        db.flush()?;
+        match db.get_backward_sync()? {
+            Some(backward) => info!(
+                "Shut down; backward sync will resume at {}H",
+                backward.next.height
+            ),
+            None => info!("Shut down; backward sync complete"),
+        }
+        Ok(false)
+    }
+
+    /// Re-fetch blocks from `start` to `end` (default: db chain-head), fixing the db
+    ///
+    /// Every block is repaired atomically, so an interrupted repair can
+    /// just be started again.
+    fn repair(&mut self, start: BlockHeight, end: Option<BlockHeight>) -> Result<()> {
+        let head = match self.db.lock().expect("lock works").get_head_height()? {
+            Some(head) => head,
+            None => bail!("Nothing indexed yet; nothing to repair"),
+        };
+        let end = end.unwrap_or(head);
+        if start > end {
+            bail!("Invalid repair range {}H..={}H", start, end);
+        }
+        if head < end {
+            bail!("Can't repair up to {}H; indexed only up to {}H", end, head);
+        }
+        if self.node_starting_chainhead_height < end {
+            bail!(
+                "Can't repair up to {}H; node chain-head is at {}H",
+                end,
+                self.node_starting_chainhead_height
+            );
+        }
+
+        info!("Repairing blocks {}H..={}H", start, end);
+        let fetch_start = match start.checked_sub(1) {
+            Some(height) => match self.rpc.get_block_id_by_height(height)? {
+                Some(id) => Some(WithHeightAndId {
+                    height,
+                    id,
+                    data: (),
+                }),
+                None => bail!("Node has no block at {}H", height),
+            },
+            None => None,
+        };
+
+        let mut fixed = 0;
+        let mut fetcher = fetcher::Fetcher::new(self.rpc.clone(), fetch_start, Some(end))?;
+        let mut bottlecheck_fetcher = BottleCheck::new("block fetcher".into());
+        for block in bottlecheck_fetcher.check_iter(&mut fetcher) {
+            let block_height = block.height;
+            if block_height % 1000 == 0 {
+                eprintln!("Block {}H: {}", block.height, block.id);
+            }
+            let Self {
+                ref mut db,
+                ref mut bottlecheck_db,
+                ..
+            } = self;
+            if bottlecheck_db.check(|| db.lock().expect("lock works").repair(block))? {
+                fixed += 1;
+            }
+        }
+
+        self.db.lock().expect("lock works").flush()?;
+        if let Some(e) = fetcher.take_error() {
+            return Err(e);
+        }
+        info!(
+            "Repair of {}H..={}H complete; {} blocks fixed",
+            start, end, fixed
+        );
         Ok(())
     }
 }
 
+/// Stops fetching blocks on SIGINT/SIGTERM, and aborts if it takes too long
+///
+/// A second signal aborts right away.
+#[derive(Clone, Default)]
+struct ShutdownSignal {
+    requested: Arc<AtomicBool>,
+    target: Arc<Mutex<Option<prefetcher::PrefetcherShutdown>>>,
+}
+
+impl ShutdownSignal {
+    fn install(timeout: Duration) -> Result<Self> {
+        let signal = Self::default();
+        let Self { requested, target } = signal.clone();
+        ctrlc::set_handler(move || {
+            if requested.swap(true, Ordering::SeqCst) {
+                error!("Shutdown signal received again; aborting");
+                std::process::exit(1);
+            }
+            warn!(
+                "Shutdown signal received; writing out pending blocks (up to {}s)",
+                timeout.as_secs()
+            );
+            if let Some(target) = target.lock().expect("lock works").as_ref() {
+                target.shutdown();
+            }
+            std::thread::spawn(move || {
+                std::thread::sleep(timeout);
+                error!("Shutdown took longer than {}s; aborting", timeout.as_secs());
+                std::process::exit(1);
+            });
+        })?;
+        Ok(signal)
+    }
+
+    /// Stop `target` on a signal, including one that already arrived
+    fn set_target(&self, target: prefetcher::PrefetcherShutdown) {
+        let mut lock = self.target.lock().expect("lock works");
+        if self.requested.load(Ordering::SeqCst) {
+            target.shutdown();
+        }
+        *lock = Some(target);
+    }
+}
+
+/// Wake up the block fetcher on ZMQ block notifications
+#[cfg(feature = "zmq")]
+fn subscribe_tip(
+    url: Option<&str>,
+    tip_notify: prefetcher::TipNotify,
+) -> Result<Option<bitcoin_indexer::node::zmq::ZmqSubscriber>> {
+    use bitcoin_indexer::node::zmq::{ZmqSubscriber, TOPIC_HASHBLOCK};
+
+    url.map(|url| {
+        ZmqSubscriber::new(url, &[TOPIC_HASHBLOCK], move |notification| {
+            if notification.is_block_related() {
+                tip_notify.notify();
+            }
+        })
+    })
+    .transpose()
+}
+
+#[cfg(not(feature = "zmq"))]
+fn subscribe_tip(
+    url: Option<&str>,
+    _tip_notify: prefetcher::TipNotify,
+) -> Result<Option<std::convert::Infallible>> {
+    if url.is_some() {
+        bail!("NODE_ZMQ_URL is set, but this binary was built without `zmq` feature");
+    }
+    Ok(None)
+}
+
 struct Config {
     db_url: String,
     node_url: String,
+    zmq_url: Option<String>,
 }
 
 impl Config {
@@ -91,6 +382,7 @@ impl Config {
         Ok(Self {
             db_url: env::var("DATABASE_URL")?,
             node_url: env::var("NODE_RPC_URL")?,
+            zmq_url: env::var("NODE_ZMQ_URL").ok(),
         })
     }
 }
@@ -108,10 +400,48 @@ fn run() -> Result<()> {
         return Ok(());
     }
 
-    let mut indexer = Indexer::new(config)?;
-    indexer.run()?;
+    let rpc = RpcPool::from_url_list(&config.node_url)?;
+    let network = rpc.get_network()?;
+    if opts.headers_first {
+        if !rpc.iter().all(AnyRpc::has_headers) {
+            bail!("`--headers-first` can't be used with blk files or P2P node urls");
+        }
+        run_indexer(config, &opts, HeadersFirst::new(rpc, network), network)
+    } else {
+        run_indexer(config, &opts, rpc, network)
+    }
+}
 
-    Ok(())
+/// Index (or repair) blocks from `rpc`, recording its answers if requested
+fn run_indexer<R>(
+    config: Config,
+    opts: &opts::Opts,
+    rpc: R,
+    network: bitcoin::Network,
+) -> Result<()>
+where
+    R: Rpc<Id = BlockHash, Data = Box<bitcoin::Block>> + 'static,
+{
+    match &opts.record_rpc {
+        Some(path) => Indexer::new(
+            config,
+            RecordingRpc::new(rpc, network, path)?,
+            network,
+            opts.verify_blocks,
+            opts.parallel_writers,
+            opts.skip_witness,
+        )?
+        .run_with_opts(opts),
+        None => Indexer::new(
+            config,
+            rpc,
+            network,
+            opts.verify_blocks,
+            opts.parallel_writers,
+            opts.skip_witness,
+        )?
+        .run_with_opts(opts),
+    }
 }
 
 quick_main!(run);
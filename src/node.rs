@@ -1,2 +1,17 @@
+pub mod any;
+#[cfg(feature = "async")]
+pub mod async_prefetcher;
+pub mod backward;
+pub mod bitcoin_conf;
+pub mod blk_files;
+pub mod esplora;
 pub mod fetcher;
+pub mod headers;
+pub mod jsonrpc;
+pub mod p2p;
+pub mod pool;
 pub mod prefetcher;
+pub mod replay;
+pub mod rest;
+#[cfg(feature = "zmq")]
+pub mod zmq;
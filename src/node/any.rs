@@ -0,0 +1,191 @@
+//! `Rpc` that can be any of the supported node interfaces
+//!
+//! `Rpc` has associated types and consts, so it can't be made into a trait
+//! object. This enum is what the binaries use to pick the block source
+//! at runtime, from `RpcInfo`.
+use super::{
+    blk_files::BlkFilesRpc, esplora::EsploraRpc, headers::HeaderRpc, jsonrpc::JsonRpcClient,
+    p2p::P2pRpc, pool::RpcPool, replay::ReplayRpc, rest::RestRpc,
+};
+use crate::{prelude::*, util::bitcoin::network_from_str, BlockHeight, Rpc, RpcInfo};
+use bitcoin::{hash_types::BlockHash, util::uint::Uint256};
+use bitcoincore_rpc::RpcApi;
+
+pub enum AnyRpc {
+    JsonRpc(JsonRpcClient),
+    Rest(RestRpc),
+    Esplora(EsploraRpc),
+    Replay(ReplayRpc),
+    BlkFiles(BlkFilesRpc),
+    P2p(P2pRpc),
+}
+
+impl AnyRpc {
+    /// Network the node is on
+    pub fn get_network(&self) -> Result<bitcoin::Network> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => network_from_str(&rpc.get_blockchain_info()?.chain),
+            AnyRpc::Rest(rpc) => network_from_str(&rpc.get_chain()?),
+            AnyRpc::Esplora(rpc) => rpc.get_network(),
+            AnyRpc::Replay(rpc) => Ok(rpc.network()),
+            AnyRpc::BlkFiles(rpc) => Ok(rpc.network()),
+            AnyRpc::P2p(rpc) => Ok(rpc.network()),
+        }
+    }
+
+    /// Can be used as a `HeaderRpc`
+    ///
+    /// Blocks read from node's own files are as valid as its headers, so
+    /// there's nothing to gain from fetching the headers first anyway.
+    /// `P2pRpc` keeps only the hashes of the headers it syncs.
+    pub fn has_headers(&self) -> bool {
+        !matches!(self, AnyRpc::BlkFiles(_) | AnyRpc::P2p(_))
+    }
+}
+
+impl Rpc for AnyRpc {
+    type Data = Box<bitcoin::Block>;
+    type Id = BlockHash;
+    // only where there's no instance to ask; see the methods below
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 2000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 100;
+
+    fn head_retry_delay_ms(&self) -> u64 {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.head_retry_delay_ms(),
+            AnyRpc::Rest(rpc) => rpc.head_retry_delay_ms(),
+            AnyRpc::Esplora(rpc) => rpc.head_retry_delay_ms(),
+            AnyRpc::Replay(rpc) => rpc.head_retry_delay_ms(),
+            AnyRpc::BlkFiles(rpc) => rpc.head_retry_delay_ms(),
+            AnyRpc::P2p(rpc) => rpc.head_retry_delay_ms(),
+        }
+    }
+
+    fn error_retry_delay_ms(&self) -> u64 {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.error_retry_delay_ms(),
+            AnyRpc::Rest(rpc) => rpc.error_retry_delay_ms(),
+            AnyRpc::Esplora(rpc) => rpc.error_retry_delay_ms(),
+            AnyRpc::Replay(rpc) => rpc.error_retry_delay_ms(),
+            AnyRpc::BlkFiles(rpc) => rpc.error_retry_delay_ms(),
+            AnyRpc::P2p(rpc) => rpc.error_retry_delay_ms(),
+        }
+    }
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => Rpc::get_block_count(rpc),
+            AnyRpc::Rest(rpc) => rpc.get_block_count(),
+            AnyRpc::Esplora(rpc) => rpc.get_block_count(),
+            AnyRpc::Replay(rpc) => rpc.get_block_count(),
+            AnyRpc::BlkFiles(rpc) => rpc.get_block_count(),
+            AnyRpc::P2p(rpc) => rpc.get_block_count(),
+        }
+    }
+
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.get_block_count_and_work(),
+            AnyRpc::Rest(rpc) => rpc.get_block_count_and_work(),
+            AnyRpc::Esplora(rpc) => rpc.get_block_count_and_work(),
+            AnyRpc::Replay(rpc) => rpc.get_block_count_and_work(),
+            AnyRpc::BlkFiles(rpc) => rpc.get_block_count_and_work(),
+            AnyRpc::P2p(rpc) => rpc.get_block_count_and_work(),
+        }
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.get_block_id_by_height(height),
+            AnyRpc::Rest(rpc) => rpc.get_block_id_by_height(height),
+            AnyRpc::Esplora(rpc) => rpc.get_block_id_by_height(height),
+            AnyRpc::Replay(rpc) => rpc.get_block_id_by_height(height),
+            AnyRpc::BlkFiles(rpc) => rpc.get_block_id_by_height(height),
+            AnyRpc::P2p(rpc) => rpc.get_block_id_by_height(height),
+        }
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.get_block_by_id(hash),
+            AnyRpc::Rest(rpc) => rpc.get_block_by_id(hash),
+            AnyRpc::Esplora(rpc) => rpc.get_block_by_id(hash),
+            AnyRpc::Replay(rpc) => rpc.get_block_by_id(hash),
+            AnyRpc::BlkFiles(rpc) => rpc.get_block_by_id(hash),
+            AnyRpc::P2p(rpc) => rpc.get_block_by_id(hash),
+        }
+    }
+
+    const RECOMMENDED_BATCH_SIZE: usize = JsonRpcClient::RECOMMENDED_BATCH_SIZE;
+
+    fn get_block_ids_by_heights(&self, start: BlockHeight, count: usize) -> Result<Vec<Self::Id>> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.get_block_ids_by_heights(start, count),
+            AnyRpc::Rest(rpc) => rpc.get_block_ids_by_heights(start, count),
+            AnyRpc::Esplora(rpc) => rpc.get_block_ids_by_heights(start, count),
+            AnyRpc::Replay(rpc) => rpc.get_block_ids_by_heights(start, count),
+            AnyRpc::BlkFiles(rpc) => rpc.get_block_ids_by_heights(start, count),
+            AnyRpc::P2p(rpc) => rpc.get_block_ids_by_heights(start, count),
+        }
+    }
+
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.get_blocks_by_ids(ids),
+            AnyRpc::Rest(rpc) => rpc.get_blocks_by_ids(ids),
+            AnyRpc::Esplora(rpc) => rpc.get_blocks_by_ids(ids),
+            AnyRpc::Replay(rpc) => rpc.get_blocks_by_ids(ids),
+            AnyRpc::BlkFiles(rpc) => rpc.get_blocks_by_ids(ids),
+            AnyRpc::P2p(rpc) => rpc.get_blocks_by_ids(ids),
+        }
+    }
+}
+
+impl HeaderRpc for AnyRpc {
+    fn get_headers_by_height(
+        &self,
+        start: BlockHeight,
+        count: usize,
+    ) -> Result<Vec<bitcoin::BlockHeader>> {
+        match self {
+            AnyRpc::JsonRpc(rpc) => rpc.get_headers_by_height(start, count),
+            AnyRpc::Rest(rpc) => rpc.get_headers_by_height(start, count),
+            AnyRpc::Esplora(rpc) => rpc.get_headers_by_height(start, count),
+            AnyRpc::Replay(rpc) => rpc.get_headers_by_height(start, count),
+            AnyRpc::BlkFiles(_) => bail!("Blk files can't fetch headers on their own"),
+            AnyRpc::P2p(_) => bail!("P2P node can't fetch headers by height"),
+        }
+    }
+}
+
+impl RpcPool<AnyRpc> {
+    /// Create a pool from a comma-separated list of node urls
+    pub fn from_url_list(urls: &str) -> Result<Self> {
+        Self::new(
+            RpcInfo::from_url_list(urls)?
+                .into_iter()
+                .map(|info| Ok((info.url.clone(), info.to_any_rpc()?)))
+                .collect::<Result<_>>()?,
+        )
+    }
+
+    /// Network the nodes are on; they all have to agree
+    pub fn get_network(&self) -> Result<bitcoin::Network> {
+        let mut network = None;
+        let mut last_err = None;
+        for rpc in self.iter() {
+            match rpc.get_network() {
+                Ok(n) if network.is_some_and(|network| network != n) => {
+                    bail!("Nodes in the pool are on different networks")
+                }
+                Ok(n) => network = Some(n),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match (network, last_err) {
+            (Some(network), _) => Ok(network),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!("pool is never empty"),
+        }
+    }
+}
@@ -0,0 +1,541 @@
+//! `Stream` of blocks, for tokio users
+//!
+//! The async counterpart of `Prefetcher`: blocks are fetched by tokio tasks
+//! instead of threads, and returned in order. On a reorg, the sequence is
+//! broken and continues from right above the fork point, in the same way.
+use super::prefetcher::track_reorgs;
+use crate::{prelude::*, AsyncRpc, BlockHeight, Rpc, WithHeightAndId};
+use common_failures::prelude::*;
+use futures::{future::BoxFuture, Future, Stream};
+use log::{debug, error, info, trace};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// Block specialized over types from `AsyncRpc`
+pub type AsyncRpcBlock<R> = WithHeightAndId<<R as AsyncRpc>::Id, <R as AsyncRpc>::Data>;
+
+/// `AsyncRpcBlock` along with an Id of a previous block
+struct AsyncRpcBlockWithPrevId<R: AsyncRpc> {
+    block: AsyncRpcBlock<R>,
+    prev_block_id: R::Id,
+}
+
+async fn retry<T, F>(mut f: impl FnMut() -> F) -> T
+where
+    F: Future<Output = Result<T>>,
+{
+    let delay_ms = 100;
+    let mut count = 0;
+    loop {
+        match f().await {
+            Err(e) => {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                if count % 1000 == 0 {
+                    eprintln!("{}; retrying ...", e.display_causes_and_backtrace());
+                }
+                count += 1;
+            }
+            Ok(t) => {
+                return t;
+            }
+        }
+    }
+}
+
+/// Any `Rpc` as an `AsyncRpc`, calling it on tokio's blocking threads
+pub struct BlockingRpc<R> {
+    rpc: Arc<R>,
+}
+
+impl<R> BlockingRpc<R>
+where
+    R: Rpc + 'static,
+{
+    pub fn new(rpc: Arc<R>) -> Self {
+        Self { rpc }
+    }
+
+    async fn call<T>(&self, f: impl FnOnce(&R) -> Result<T> + Send + 'static) -> Result<T>
+    where
+        T: Send + 'static,
+    {
+        let rpc = self.rpc.clone();
+        tokio::task::spawn_blocking(move || f(&rpc)).await?
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> AsyncRpc for BlockingRpc<R>
+where
+    R: Rpc + 'static,
+    R::Data: 'static,
+    R::Id: Sync + 'static,
+{
+    type Data = R::Data;
+    type Id = R::Id;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = R::RECOMMENDED_ERROR_RETRY_DELAY_MS;
+    const RECOMMENDED_BATCH_SIZE: usize = R::RECOMMENDED_BATCH_SIZE;
+
+    fn head_retry_delay_ms(&self) -> u64 {
+        self.rpc.head_retry_delay_ms()
+    }
+
+    fn error_retry_delay_ms(&self) -> u64 {
+        self.rpc.error_retry_delay_ms()
+    }
+
+    async fn get_block_count(&self) -> Result<BlockHeight> {
+        self.call(|rpc| rpc.get_block_count()).await
+    }
+
+    async fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        self.call(move |rpc| rpc.get_block_id_by_height(height))
+            .await
+    }
+
+    async fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let hash = hash.clone();
+        self.call(move |rpc| rpc.get_block_by_id(&hash)).await
+    }
+
+    async fn get_block_ids_by_heights(
+        &self,
+        start: BlockHeight,
+        count: usize,
+    ) -> Result<Vec<Self::Id>> {
+        self.call(move |rpc| rpc.get_block_ids_by_heights(start, count))
+            .await
+    }
+
+    async fn get_blocks_by_ids(
+        &self,
+        ids: &[Self::Id],
+    ) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let ids = ids.to_vec();
+        self.call(move |rpc| rpc.get_blocks_by_ids(&ids)).await
+    }
+}
+
+/// A `Stream` of blocks from an `AsyncRpc`
+///
+/// Returns blocks in order, like `Prefetcher`. It has to be created and
+/// polled within a tokio runtime; dropping it stops all the fetching tasks.
+///
+/// Like `Prefetcher` without a `HashLookup`, it only remembers the last
+/// `PREV_HASHES_WINDOW` blocks it returned. The fork point of a deeper
+/// reorg can't be found, so the stream ends there; see `take_error`.
+pub struct AsyncPrefetcher<R>
+where
+    R: AsyncRpc,
+{
+    /// `None` while `next` is in progress
+    inner: Option<Inner<R>>,
+    next: Option<NextFuture<R>>,
+}
+
+/// `Inner::next` in progress, giving `Inner` back along with the block
+type NextFuture<R> = BoxFuture<'static, (Inner<R>, Option<AsyncRpcBlock<R>>)>;
+
+// fields are never pinned
+impl<R> Unpin for AsyncPrefetcher<R> where R: AsyncRpc {}
+
+impl<R> AsyncPrefetcher<R>
+where
+    R: AsyncRpc + 'static,
+{
+    pub async fn new(rpc: Arc<R>, last_block: Option<WithHeightAndId<R::Id>>) -> Result<Self> {
+        let worker_num = num_cpus::get() * 2;
+
+        let end_of_fast_sync = retry(|| rpc.get_block_count()).await;
+        let mut prev_hashes = BTreeMap::default();
+        let start = if let Some(h_and_hash) = last_block {
+            let h = h_and_hash.height;
+            prev_hashes.insert(h, h_and_hash.id);
+            info!("Starting async block fetcher starting at {}H", h + 1);
+            h + 1
+        } else {
+            info!("Starting async block fetcher starting at genesis block");
+            0
+        };
+
+        let mut inner = Inner {
+            rx: None,
+            rpc,
+            workers: vec![],
+            worker_num,
+            cur_height: start,
+            out_of_order_items: default(),
+            prev_hashes,
+            end_of_fast_sync,
+            error: None,
+        };
+        inner.start_workers();
+
+        Ok(Self {
+            inner: Some(inner),
+            next: None,
+        })
+    }
+
+    /// Why the stream ended
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.inner.as_mut()?.error.take()
+    }
+}
+
+impl<R> Stream for AsyncPrefetcher<R>
+where
+    R: AsyncRpc + 'static,
+{
+    type Item = AsyncRpcBlock<R>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.next.is_none() {
+            let mut inner = this.inner.take().expect("inner available");
+            this.next = Some(Box::pin(async move {
+                let block = inner.next().await;
+                (inner, block)
+            }));
+        }
+
+        match this
+            .next
+            .as_mut()
+            .expect("next in progress")
+            .as_mut()
+            .poll(cx)
+        {
+            Poll::Ready((inner, block)) => {
+                this.next = None;
+                this.inner = Some(inner);
+                Poll::Ready(block)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// State of `AsyncPrefetcher`, moved in and out of the `next` future
+struct Inner<R>
+where
+    R: AsyncRpc,
+{
+    rx: Option<mpsc::Receiver<AsyncRpcBlockWithPrevId<R>>>,
+    workers: Vec<JoinHandle<()>>,
+    /// List of blocks that arrived out-of-order: before the block
+    /// we were actually waiting for.
+    out_of_order_items: HashMap<BlockHeight, AsyncRpcBlockWithPrevId<R>>,
+
+    cur_height: BlockHeight,
+    prev_hashes: BTreeMap<BlockHeight, R::Id>,
+    worker_num: usize,
+    rpc: Arc<R>,
+    end_of_fast_sync: BlockHeight,
+    /// Set when the fork point of a reorg couldn't be found
+    error: Option<Error>,
+}
+
+impl<R> Inner<R>
+where
+    R: AsyncRpc + 'static,
+{
+    fn start_workers(&mut self) {
+        let (tx, rx) = mpsc::channel(self.worker_num * 64);
+        self.rx = Some(rx);
+        let next_height = Arc::new(AtomicUsize::new(self.cur_height as usize));
+        let in_progress = Arc::new(Mutex::new(BTreeSet::new()));
+        // claiming ranges of blocks makes sense only when far from the tip
+        let batch_size = if self.worker_num > 1 {
+            R::RECOMMENDED_BATCH_SIZE.max(1)
+        } else {
+            1
+        };
+        assert!(self.workers.is_empty());
+        for _ in 0..self.worker_num {
+            let worker = Worker {
+                rpc: self.rpc.clone(),
+                next_height: next_height.clone(),
+                tx: tx.clone(),
+                in_progress: in_progress.clone(),
+                batch_size,
+            };
+            self.workers.push(tokio::spawn(worker.run()));
+        }
+    }
+
+    /// Does the node's chain contain the block we returned at `height`
+    ///
+    /// Fails if we don't remember what we returned there.
+    async fn is_in_node_chain(&mut self, height: BlockHeight) -> Result<bool> {
+        let known = match self.prev_hashes.get(&height) {
+            Some(known) => known,
+            None => bail!(
+                "Reorg deeper than the known blocks: can't tell if {}H is the fork point",
+                height
+            ),
+        };
+        let rpc = &self.rpc;
+        Ok(retry(|| rpc.get_block_id_by_height(height)).await.as_ref() == Some(known))
+    }
+
+    /// See `Prefetcher::find_fork_height`
+    async fn find_fork_height(&mut self) -> Result<Option<BlockHeight>> {
+        // the block at `cur_height` didn't connect
+        let mut mismatch = self.cur_height - 1;
+        let mut step = 1;
+        let mut matching = loop {
+            if mismatch == 0 {
+                return Ok(None);
+            }
+            let mut height = mismatch.saturating_sub(step);
+            // don't step over the lowest remembered block
+            if let Some(&lowest) = self.prev_hashes.keys().next() {
+                if lowest < mismatch {
+                    height = height.max(lowest);
+                }
+            }
+            if self.is_in_node_chain(height).await? {
+                break height;
+            }
+            mismatch = height;
+            step *= 2;
+        };
+        while matching + 1 < mismatch {
+            let height = matching + (mismatch - matching) / 2;
+            if self.is_in_node_chain(height).await? {
+                matching = height;
+            } else {
+                mismatch = height;
+            }
+        }
+        Ok(Some(matching))
+    }
+
+    /// Stop all workers (discarding their work), find the fork point,
+    /// and start workers again right above it
+    ///
+    /// If the fork point can't be found, the workers stay stopped.
+    async fn reset_on_reorg(&mut self) -> Result<()> {
+        self.stop_workers();
+        assert!(self.cur_height > 0);
+        let new_height = if let Some(fork_height) = self.find_fork_height().await? {
+            let fork_id = self.prev_hashes[&fork_height].clone();
+            self.prev_hashes.split_off(&fork_height);
+            self.prev_hashes.insert(fork_height, fork_id);
+            fork_height + 1
+        } else {
+            self.prev_hashes.clear();
+            0
+        };
+        info!(
+            "Reorg detected at {}H; continuing from {}H",
+            self.cur_height, new_height
+        );
+        self.cur_height = new_height;
+        self.start_workers();
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Option<AsyncRpcBlock<R>> {
+        // `None` once stopped on a reorg
+        self.rx.as_ref()?;
+        if self.end_of_fast_sync == self.cur_height {
+            debug!(
+                "AsyncPrefetcher: end of fast sync at {}H; switching to one worker",
+                self.cur_height
+            );
+            self.stop_workers();
+            self.worker_num = 1;
+            self.start_workers();
+        }
+
+        loop {
+            let item = match self.out_of_order_items.remove(&self.cur_height) {
+                Some(item) => item,
+                None => {
+                    trace!(
+                        "Waiting for the block from the workers at: {}H",
+                        self.cur_height
+                    );
+                    let item = self
+                        .rx
+                        .as_mut()
+                        .expect("rx available")
+                        .recv()
+                        .await
+                        .expect("Workers shouldn't disconnect");
+                    if item.block.height != self.cur_height {
+                        assert!(item.block.height > self.cur_height);
+                        self.out_of_order_items.insert(item.block.height, item);
+                        continue;
+                    }
+                    item
+                }
+            };
+
+            if track_reorgs(
+                &mut self.prev_hashes,
+                self.cur_height,
+                &item.block.id,
+                &item.prev_block_id,
+            ) {
+                if let Err(e) = self.reset_on_reorg().await {
+                    error!("Stopping block fetching: {}", e);
+                    self.error = Some(e);
+                    return None;
+                }
+                continue;
+            }
+            self.cur_height += 1;
+            return Some(item.block);
+        }
+    }
+}
+
+impl<R> Inner<R>
+where
+    R: AsyncRpc,
+{
+    fn stop_workers(&mut self) {
+        // blocks already sent are dropped along with `rx`, and
+        // aborted workers can't send any more of them
+        for worker in self.workers.drain(..) {
+            worker.abort();
+        }
+        self.rx = None;
+        self.out_of_order_items.clear();
+    }
+}
+
+impl<R> Drop for Inner<R>
+where
+    R: AsyncRpc,
+{
+    fn drop(&mut self) {
+        self.stop_workers();
+    }
+}
+
+/// One worker task, polling for data from the node
+struct Worker<R>
+where
+    R: AsyncRpc,
+{
+    rpc: Arc<R>,
+    next_height: Arc<AtomicUsize>,
+    tx: mpsc::Sender<AsyncRpcBlockWithPrevId<R>>,
+    /// Heights claimed by all the workers, and not fetched yet
+    in_progress: Arc<Mutex<BTreeSet<BlockHeight>>>,
+    /// Number of consecutive heights to claim at once
+    batch_size: usize,
+}
+
+impl<R> Worker<R>
+where
+    R: AsyncRpc,
+{
+    async fn run(self) {
+        loop {
+            let mut heights = self.get_heights_to_fetch();
+
+            let mut retry_count = 0;
+            while !heights.is_empty() {
+                match self.get_blocks_by_heights(heights.clone()).await {
+                    Err(e) => {
+                        trace!("Error from the node: {}", e);
+                        // let the worker fetching the lowest height retry first
+                        let ahead_minimum = heights.start
+                            - self
+                                .get_min_height_in_progress()
+                                .expect("at least current height");
+                        tokio::time::sleep(Duration::from_millis(
+                            self.rpc.error_retry_delay_ms() * (1 + u64::from(ahead_minimum)),
+                        ))
+                        .await;
+                        retry_count += 1;
+                        if retry_count % 10 == 0 {
+                            debug!("Worker retrying rpc error {} at {}H", e, heights.start);
+                        }
+                    }
+                    Ok(items) if items.is_empty() => {
+                        tokio::time::sleep(Duration::from_millis(self.rpc.head_retry_delay_ms()))
+                            .await;
+                    }
+                    Ok(items) => {
+                        // the rest of the range (if any) is not available yet
+                        for item in items {
+                            let height = item.block.height;
+                            if self.tx.send(item).await.is_err() {
+                                // stopped
+                                return;
+                            }
+                            self.mark_height_fetched(height);
+                            heights.start += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn get_heights_to_fetch(&self) -> Range<BlockHeight> {
+        let start = self
+            .next_height
+            .fetch_add(self.batch_size, Ordering::SeqCst) as BlockHeight;
+        let heights = start..start + self.batch_size as BlockHeight;
+        self.in_progress
+            .lock()
+            .expect("unlock works")
+            .extend(heights.clone());
+        heights
+    }
+
+    fn get_min_height_in_progress(&self) -> Option<BlockHeight> {
+        let in_progress = self.in_progress.lock().expect("unlock works");
+        in_progress.iter().next().cloned()
+    }
+
+    fn mark_height_fetched(&self, height: BlockHeight) {
+        assert!(self
+            .in_progress
+            .lock()
+            .expect("unlock works")
+            .remove(&height));
+    }
+
+    /// Fetch consecutive blocks from `heights`, stopping at the first missing one
+    async fn get_blocks_by_heights(
+        &self,
+        heights: Range<BlockHeight>,
+    ) -> Result<Vec<AsyncRpcBlockWithPrevId<R>>> {
+        let ids = self
+            .rpc
+            .get_block_ids_by_heights(heights.start, heights.len())
+            .await?;
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let blocks = self.rpc.get_blocks_by_ids(&ids).await?;
+        Ok(heights
+            .zip(ids.into_iter().zip(blocks))
+            .map_while(|(height, (id, block))| {
+                block.map(|(data, prev_block_id)| AsyncRpcBlockWithPrevId {
+                    block: WithHeightAndId { height, id, data },
+                    prev_block_id,
+                })
+            })
+            .collect())
+    }
+}
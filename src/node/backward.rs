@@ -0,0 +1,125 @@
+use log::warn;
+
+use super::prefetcher::{PrefetcherShutdown, TipNotify};
+use crate::{BlockHeight, Rpc, WithHeightAndId};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Fetches blocks from a given block down, following `prev` links
+///
+/// Blocks are returned in descending height order. Used for backward
+/// (tip to genesis) sync, where the most recent history is available first.
+pub struct BackwardFetcher<R>
+where
+    R: Rpc,
+{
+    rx: Option<crossbeam_channel::Receiver<WithHeightAndId<R::Id, R::Data>>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    shutdown: Arc<AtomicBool>,
+    // only used to interrupt retry delays on shutdown
+    wakeup: TipNotify,
+}
+
+impl<R> BackwardFetcher<R>
+where
+    R: Rpc + 'static,
+{
+    /// Fetch blocks from `start` down to `until` height (inclusive)
+    pub fn new(rpc: Arc<R>, start: WithHeightAndId<R::Id>, until: BlockHeight) -> Self {
+        assert!(until <= start.height);
+
+        let (tx, rx) = crossbeam_channel::bounded(64);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let wakeup = TipNotify::default();
+
+        let thread = std::thread::spawn({
+            let shutdown = shutdown.clone();
+            let wakeup = wakeup.clone();
+            move || {
+                let mut next = start;
+                while !shutdown.load(Ordering::SeqCst) {
+                    let generation = wakeup.generation();
+                    match rpc.get_block_by_id(&next.id) {
+                        Ok(Some((data, prev_id))) => {
+                            let height = next.height;
+                            let item = WithHeightAndId {
+                                height,
+                                id: next.id,
+                                data,
+                            };
+                            if tx.send(item).is_err() || height == until {
+                                return;
+                            }
+                            next = WithHeightAndId {
+                                height: height - 1,
+                                id: prev_id,
+                                data: (),
+                            };
+                            continue;
+                        }
+                        Ok(None) => {
+                            warn!("Node has no block {}H {}; retrying", next.height, next.id)
+                        }
+                        Err(e) => warn!(
+                            "Fetching block {}H {} failed: {}; retrying",
+                            next.height, next.id, e
+                        ),
+                    }
+                    wakeup.wait(
+                        generation,
+                        Duration::from_millis(rpc.error_retry_delay_ms()),
+                    );
+                }
+            }
+        });
+
+        Self {
+            rx: Some(rx),
+            thread: Some(thread),
+            shutdown,
+            wakeup,
+        }
+    }
+
+    /// Get a handle to stop this fetcher from another thread
+    pub fn shutdown_handle(&self) -> PrefetcherShutdown {
+        PrefetcherShutdown::new(self.shutdown.clone(), self.wakeup.clone())
+    }
+}
+
+impl<R> Iterator for BackwardFetcher<R>
+where
+    R: Rpc,
+{
+    type Item = WithHeightAndId<R::Id, R::Data>;
+
+    /// Returns `None` after the block at `until` height, or after a shutdown
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.rx.as_ref()?.recv().ok()
+    }
+}
+
+impl<R> Drop for BackwardFetcher<R>
+where
+    R: Rpc,
+{
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wakeup.notify();
+        // unblocks the fetching thread, if it is waiting to send
+        drop(self.rx.take());
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .expect("Couldn't join backward fetcher thread");
+        }
+    }
+}
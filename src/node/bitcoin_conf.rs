@@ -0,0 +1,133 @@
+//! Finding node's RPC settings in its `bitcoin.conf`
+use crate::{prelude::*, util::bitcoin::network_from_str};
+use bitcoincore_rpc::Auth;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Options that Core applies only to mainnet, when set outside of a network section
+const MAINNET_ONLY_IN_TOP_SECTION: &[&str] = &["rpcport"];
+
+/// RPC settings of a node, read from its `bitcoin.conf`
+#[derive(Clone, Debug)]
+pub struct BitcoinConf {
+    pub network: bitcoin::Network,
+    /// Base data directory (without the network subdirectory)
+    pub datadir: PathBuf,
+    pub rpc_host: String,
+    pub rpc_port: u16,
+    pub rpc_user: Option<String>,
+    pub rpc_password: Option<String>,
+    pub rpc_cookie_file: PathBuf,
+    /// Is the REST interface enabled
+    pub rest: bool,
+}
+
+impl BitcoinConf {
+    /// Read `bitcoin.conf` at `path`
+    ///
+    /// Without `datadir` set, the data directory is assumed to be where the file is.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => bail!("Couldn't read {}: {}", path.display(), e),
+        };
+        Self::parse(&content, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    /// Parse `bitcoin.conf` contents
+    pub fn parse(content: &str, default_datadir: &Path) -> Result<Self> {
+        // options by section name; "" is the top one
+        let mut sections: HashMap<&str, HashMap<&str, &str>> = HashMap::new();
+        let mut section = "";
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim();
+                continue;
+            }
+            let mut option = line.splitn(2, '=');
+            match (option.next(), option.next()) {
+                // like in Core, the first value of an option is used
+                (Some(key), Some(value)) => {
+                    sections
+                        .entry(section)
+                        .or_default()
+                        .entry(key.trim())
+                        .or_insert_with(|| value.trim());
+                }
+                _ => bail!("bitcoin.conf line {}: expected `option=value`", i + 1),
+            }
+        }
+
+        let top = sections.get("").cloned().unwrap_or_default();
+        let is_set = |key: &str| top.get(key).is_some_and(|value| *value != "0");
+        let network = match top.get("chain") {
+            Some(chain) => network_from_str(chain)?,
+            None if is_set("regtest") => bitcoin::Network::Regtest,
+            None if is_set("testnet") => bitcoin::Network::Testnet,
+            None if is_set("signet") => bitcoin::Network::Signet,
+            None => bitcoin::Network::Bitcoin,
+        };
+        let (section, subdir, default_port) = match network {
+            bitcoin::Network::Bitcoin => ("main", "", 8332),
+            bitcoin::Network::Testnet => ("test", "testnet3", 18332),
+            bitcoin::Network::Signet => ("signet", "signet", 38332),
+            bitcoin::Network::Regtest => ("regtest", "regtest", 18443),
+        };
+        let section = sections.get(section).cloned().unwrap_or_default();
+        let get = |key: &str| -> Option<String> {
+            section
+                .get(key)
+                .or_else(|| {
+                    if network == bitcoin::Network::Bitcoin
+                        || !MAINNET_ONLY_IN_TOP_SECTION.contains(&key)
+                    {
+                        top.get(key)
+                    } else {
+                        None
+                    }
+                })
+                .map(|value| (*value).to_owned())
+        };
+
+        let datadir = get("datadir").map_or_else(|| default_datadir.to_owned(), PathBuf::from);
+        let network_datadir = datadir.join(subdir);
+        let rpc_port = match get("rpcport") {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => bail!("Invalid rpcport in bitcoin.conf: {}", port),
+            },
+            None => default_port,
+        };
+
+        Ok(Self {
+            network,
+            rpc_host: get("rpcconnect").unwrap_or_else(|| "127.0.0.1".into()),
+            rpc_port,
+            rpc_user: get("rpcuser"),
+            rpc_password: get("rpcpassword"),
+            rpc_cookie_file: network_datadir
+                .join(get("rpccookiefile").unwrap_or_else(|| ".cookie".into())),
+            rest: get("rest").is_some_and(|value| value != "0"),
+            datadir,
+        })
+    }
+
+    /// Url of the node's RPC interface
+    pub fn url(&self) -> String {
+        format!("http://{}:{}", self.rpc_host, self.rpc_port)
+    }
+
+    /// `rpcuser` and `rpcpassword` if set, the cookie file otherwise
+    pub fn auth(&self) -> Auth {
+        match (&self.rpc_user, &self.rpc_password) {
+            (Some(user), Some(password)) => Auth::UserPass(user.clone(), password.clone()),
+            _ => Auth::CookieFile(self.rpc_cookie_file.clone()),
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Block source reading Bitcoin Core's `blocks/blk*.dat` files directly
+//!
+//! When the indexer runs on the same host as the node, going through
+//! JSON-RPC (`getblockhash` + hex-encoded `getblock`) is mostly wasted
+//! work: the blocks are already sitting on the disk in their raw,
+//! consensus-encoded form.
+//!
+//! Blocks in the `blk*.dat` files are stored in the order the node
+//! received them, which is not the chain order, and include stale blocks.
+//! Instead of opening Core's LevelDB block index (which the running node
+//! keeps locked), we build our own index by scanning the record headers and
+//! block headers, link them by `prev_blockhash` and pick the chain with the
+//! most work. Rescanning is incremental: only data appended since the last
+//! scan is read.
+//!
+//! Our index can diverge from Core's: the only check done on a header is
+//! its proof of work against its own `bits` (and the network's minimum
+//! difficulty). Difficulty adjustments, checkpoints and block validity
+//! are not checked, so a stored block that Core rejected, or a fork
+//! marked with `invalidateblock`, is followed if it has the most work.
+//! Since Core stores only blocks that passed its header checks, that takes
+//! a lot of real work, but use JSON-RPC or REST if it matters.
+//!
+//! Since Core 28.0 the files are XOR-obfuscated with a key from
+//! `blocks/xor.dat`; this is handled transparently.
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{
+    consensus::{deserialize, params::Params, Decodable},
+    hash_types::BlockHash,
+    util::uint::Uint256,
+    BlockHeader,
+};
+use log::{debug, trace};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const XOR_KEY_LEN: usize = 8;
+const RECORD_HEADER_LEN: u64 = 8;
+const BLOCK_HEADER_LEN: u64 = 80;
+
+/// Reader for a single (possibly obfuscated) `blk*.dat` file
+struct BlkFile {
+    file: fs::File,
+    xor_key: [u8; XOR_KEY_LEN],
+}
+
+impl BlkFile {
+    fn open(path: &Path, xor_key: [u8; XOR_KEY_LEN]) -> io::Result<Self> {
+        Ok(Self {
+            file: fs::File::open(path)?,
+            xor_key,
+        })
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Read exactly `buf.len()` bytes at `offset`, as they are on the disk
+    fn read_raw_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    /// Read exactly `buf.len()` bytes at `offset`, undoing the obfuscation
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.read_raw_at(offset, buf)?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b ^= self.xor_key[((offset + i as u64) % XOR_KEY_LEN as u64) as usize];
+        }
+        Ok(())
+    }
+}
+
+/// Location of a block's data in the `blk*.dat` files
+#[derive(Copy, Clone, Debug)]
+struct BlockPos {
+    file: u32,
+    offset: u64,
+    len: u32,
+}
+
+struct IndexEntry {
+    prev: BlockHash,
+    pos: BlockPos,
+    work: Uint256,
+    /// Height and cumulative chainwork, once linked to genesis
+    linked: Option<(BlockHeight, Uint256)>,
+}
+
+/// Index of all the blocks found in `blk*.dat` files
+#[derive(Default)]
+struct BlockIndex {
+    entries: HashMap<BlockHash, IndexEntry>,
+    /// Blocks waiting for their parent to get linked, by `prev_blockhash`
+    orphans: HashMap<BlockHash, Vec<BlockHash>>,
+    /// Active (most-work) chain, by height
+    chain: Vec<BlockHash>,
+    tip_work: Uint256,
+    /// Position the next scan should resume from
+    next_file: u32,
+    next_offset: u64,
+}
+
+impl BlockIndex {
+    /// Link a new block to its parent, along with all the orphans
+    /// waiting for it, and update the active chain
+    ///
+    /// If the parent isn't linked yet, the block waits as an orphan.
+    fn connect(&mut self, hash: BlockHash) {
+        let prev = self.entries[&hash].prev;
+        let base = if prev == BlockHash::default() {
+            None
+        } else {
+            match self.entries.get(&prev).and_then(|entry| entry.linked) {
+                Some(linked) => Some(linked),
+                None => {
+                    self.orphans.entry(prev).or_default().push(hash);
+                    return;
+                }
+            }
+        };
+
+        let mut to_link = vec![(hash, base)];
+        while let Some((hash, base)) = to_link.pop() {
+            let entry = self.entries.get_mut(&hash).expect("indexed");
+            let (height, work) = match base {
+                Some((height, work)) => (height + 1, work + entry.work),
+                None => (0, entry.work),
+            };
+            entry.linked = Some((height, work));
+            self.maybe_update_tip(hash, height, work);
+            for child in self.orphans.remove(&hash).unwrap_or_default() {
+                to_link.push((child, Some((height, work))));
+            }
+        }
+    }
+
+    /// Switch active chain to `tip` if it has more work
+    fn maybe_update_tip(&mut self, tip: BlockHash, height: BlockHeight, work: Uint256) {
+        if !self.chain.is_empty() && work <= self.tip_work {
+            return;
+        }
+
+        let mut new_blocks = vec![];
+        let mut cur = tip;
+        let mut cur_height = height;
+        loop {
+            if self.chain.get(cur_height as usize) == Some(&cur) {
+                break;
+            }
+            new_blocks.push(cur);
+            if cur_height == 0 {
+                break;
+            }
+            cur = self.entries[&cur].prev;
+            cur_height -= 1;
+        }
+
+        let fork_height = height as usize + 1 - new_blocks.len();
+        if fork_height < self.chain.len() {
+            debug!(
+                "blk files: switching to a new chain at {}H (tip {}H {})",
+                fork_height, height, tip
+            );
+        }
+        self.chain.truncate(fork_height);
+        self.chain.extend(new_blocks.into_iter().rev());
+        self.tip_work = work;
+    }
+}
+
+/// `Rpc` implementation reading blocks directly from node's `blocks` directory
+pub struct BlkFilesRpc {
+    blocks_dir: PathBuf,
+    network: bitcoin::Network,
+    magic: [u8; 4],
+    xor_key: [u8; XOR_KEY_LEN],
+    /// Easiest target allowed on the network
+    pow_limit: Uint256,
+    index: Mutex<BlockIndex>,
+}
+
+impl BlkFilesRpc {
+    /// Create a new instance and scan the files in `blocks_dir`
+    ///
+    /// `blocks_dir` is the `blocks` subdirectory of the node's datadir
+    /// (eg. `~/.bitcoin/blocks` or `~/.bitcoin/testnet3/blocks`).
+    pub fn new(blocks_dir: impl Into<PathBuf>, network: bitcoin::Network) -> Result<Self> {
+        let blocks_dir = blocks_dir.into();
+        let xor_key = Self::read_xor_key(&blocks_dir)?;
+
+        let s = Self {
+            blocks_dir,
+            network,
+            magic: network.magic().to_le_bytes(),
+            xor_key,
+            pow_limit: Params::new(network).pow_limit,
+            index: Mutex::new(BlockIndex::default()),
+        };
+        s.rescan()?;
+        Ok(s)
+    }
+
+    /// Network of the blocks in `blocks_dir`, from the magic of the first one
+    pub fn detect_network(blocks_dir: impl AsRef<Path>) -> Result<bitcoin::Network> {
+        let blocks_dir = blocks_dir.as_ref();
+        let mut file = BlkFile::open(
+            &blocks_dir.join("blk00000.dat"),
+            Self::read_xor_key(blocks_dir)?,
+        )?;
+        let mut magic = [0u8; 4];
+        file.read_at(0, &mut magic)?;
+        let magic = u32::from_le_bytes(magic);
+        match [
+            bitcoin::Network::Bitcoin,
+            bitcoin::Network::Testnet,
+            bitcoin::Network::Signet,
+            bitcoin::Network::Regtest,
+        ]
+        .iter()
+        .find(|network| network.magic() == magic)
+        {
+            Some(network) => Ok(*network),
+            None => bail!(
+                "Unknown network magic {:x} in {}",
+                magic,
+                blocks_dir.display()
+            ),
+        }
+    }
+
+    /// Network the blocks are from
+    pub fn network(&self) -> bitcoin::Network {
+        self.network
+    }
+
+    fn read_xor_key(blocks_dir: &Path) -> Result<[u8; XOR_KEY_LEN]> {
+        let mut key = [0u8; XOR_KEY_LEN];
+        match fs::read(blocks_dir.join("xor.dat")) {
+            Ok(bytes) => {
+                if bytes.len() != XOR_KEY_LEN {
+                    bail!(
+                        "Invalid xor.dat in {}: {} bytes",
+                        blocks_dir.display(),
+                        bytes.len()
+                    );
+                }
+                key.copy_from_slice(&bytes);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(key)
+    }
+
+    fn blk_file_path(&self, file: u32) -> PathBuf {
+        self.blocks_dir.join(format!("blk{:05}.dat", file))
+    }
+
+    /// Scan all the data appended since the last scan
+    fn rescan(&self) -> Result<()> {
+        let mut index = self.index.lock().expect("lock works");
+        let mut new_blocks = vec![];
+
+        loop {
+            let path = self.blk_file_path(index.next_file);
+            let mut file = match BlkFile::open(&path, self.xor_key) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            };
+            let (end, complete) = self.scan_file(&mut file, &mut index, &mut new_blocks)?;
+            index.next_offset = end;
+
+            // the node moves to the next file only once the previous one is
+            // full, so there's no need to look at the next one before then
+            let next_file_exists = self.blk_file_path(index.next_file + 1).exists();
+            if complete && next_file_exists {
+                index.next_file += 1;
+                index.next_offset = 0;
+            } else {
+                break;
+            }
+        }
+
+        for hash in new_blocks {
+            index.connect(hash);
+        }
+
+        Ok(())
+    }
+
+    /// Scan one file starting at `index.next_offset`
+    ///
+    /// Returns the offset where the scan ended, and whether the rest of
+    /// the file is only zero-padding (the file is complete).
+    fn scan_file(
+        &self,
+        file: &mut BlkFile,
+        index: &mut BlockIndex,
+        new_blocks: &mut Vec<BlockHash>,
+    ) -> Result<(u64, bool)> {
+        let file_len = file.len()?;
+        let mut offset = index.next_offset;
+
+        while offset + RECORD_HEADER_LEN + BLOCK_HEADER_LEN <= file_len {
+            let mut record_header = [0u8; RECORD_HEADER_LEN as usize];
+            file.read_raw_at(offset, &mut record_header)?;
+            if record_header[..4] == [0u8; 4] {
+                // preallocated space (not obfuscated), not written yet
+                return Ok((offset, true));
+            }
+            file.read_at(offset, &mut record_header)?;
+            if record_header[..4] != self.magic {
+                bail!(
+                    "Invalid magic in blk{:05}.dat at {}",
+                    index.next_file,
+                    offset
+                );
+            }
+            let len = u32::from_le_bytes([
+                record_header[4],
+                record_header[5],
+                record_header[6],
+                record_header[7],
+            ]);
+            let data_offset = offset + RECORD_HEADER_LEN;
+            if data_offset + u64::from(len) > file_len {
+                // block being written right now
+                return Ok((offset, false));
+            }
+
+            let mut header_bytes = [0u8; BLOCK_HEADER_LEN as usize];
+            file.read_at(data_offset, &mut header_bytes)?;
+            let header: BlockHeader = deserialize(&header_bytes)?;
+            let hash = header.block_hash();
+            offset = data_offset + u64::from(len);
+
+            let target = header.target();
+            if target > self.pow_limit || header.validate_pow(&target).is_err() {
+                debug!(
+                    "blk files: skipping {} with invalid proof of work in blk{:05}.dat",
+                    hash, index.next_file
+                );
+                continue;
+            }
+            trace!(
+                "blk files: found {} in blk{:05}.dat at {}",
+                hash,
+                index.next_file,
+                data_offset
+            );
+
+            let pos = BlockPos {
+                file: index.next_file,
+                offset: data_offset,
+                len,
+            };
+            index.entries.entry(hash).or_insert_with(|| {
+                new_blocks.push(hash);
+                IndexEntry {
+                    prev: header.prev_blockhash,
+                    pos,
+                    work: header.work(),
+                    linked: None,
+                }
+            });
+        }
+
+        // whatever is left is too short for a block: either padding or
+        // a record being written
+        let mut rest = vec![0u8; (file_len - offset) as usize];
+        file.read_raw_at(offset, &mut rest)?;
+        Ok((offset, rest.iter().all(|b| *b == 0)))
+    }
+
+    fn read_block(&self, pos: BlockPos) -> Result<bitcoin::Block> {
+        let mut file = BlkFile::open(&self.blk_file_path(pos.file), self.xor_key)?;
+        let mut buf = vec![0u8; pos.len as usize];
+        file.read_at(pos.offset, &mut buf)?;
+        Ok(bitcoin::Block::consensus_decode(&mut io::Cursor::new(buf))?)
+    }
+}
+
+impl Rpc for BlkFilesRpc {
+    type Data = Box<bitcoin::Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 2000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 100;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        self.rescan()?;
+        let index = self.index.lock().expect("lock works");
+        if index.chain.is_empty() {
+            bail!("No blocks found in {}", self.blocks_dir.display());
+        }
+        Ok(index.chain.len() as BlockHeight - 1)
+    }
+
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        self.rescan()?;
+        let index = self.index.lock().expect("lock works");
+        if index.chain.is_empty() {
+            bail!("No blocks found in {}", self.blocks_dir.display());
+        }
+        Ok((index.chain.len() as BlockHeight - 1, Some(index.tip_work)))
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        if let Some(id) = self
+            .index
+            .lock()
+            .expect("lock works")
+            .chain
+            .get(height as usize)
+        {
+            return Ok(Some(*id));
+        }
+
+        self.rescan()?;
+        Ok(self
+            .index
+            .lock()
+            .expect("lock works")
+            .chain
+            .get(height as usize)
+            .cloned())
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let pos = match self.index.lock().expect("lock works").entries.get(hash) {
+            Some(entry) => entry.pos,
+            None => return Ok(None),
+        };
+
+        let block = self.read_block(pos)?;
+        let prev_id = block.header.prev_blockhash;
+        Ok(Some((Box::new(block), prev_id)))
+    }
+}
@@ -0,0 +1,172 @@
+//! Block source using an Esplora HTTP API
+//!
+//! For when there's no access to a full node, only to an Esplora (or
+//! electrs-esplora) instance, eg. `https://blockstream.info/api`.
+//! Blocks are fetched raw, with `/block/:hash/raw`.
+//!
+//! Public instances rate limit their clients. `429 Too Many Requests` is
+//! returned as an error, like any other, and callers retry it after their
+//! error retry delay. Until the time the server asked for in `Retry-After`
+//! (or `RECOMMENDED_ERROR_RETRY_DELAY_MS` if it didn't), requests fail
+//! without being sent, so the retries of all the workers don't make it worse.
+use super::headers::HeaderRpc;
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    consensus::{deserialize, Decodable},
+    hash_types::BlockHash,
+    hashes::hex::FromHex,
+    BlockHeader, Network,
+};
+use log::debug;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// `Rpc` implementation using Esplora's REST API
+pub struct EsploraRpc {
+    base_url: String,
+    agent: ureq::Agent,
+    /// Don't send requests before this time
+    retry_at: Mutex<Option<Instant>>,
+}
+
+impl EsploraRpc {
+    /// Create a new client; `url` is the API base (eg. `https://blockstream.info/api`)
+    pub fn new(url: &str) -> Self {
+        Self::with_timeouts(
+            url,
+            crate::DEFAULT_CONNECT_TIMEOUT,
+            crate::DEFAULT_READ_TIMEOUT,
+        )
+    }
+
+    pub fn with_timeouts(url: &str, connect_timeout: Duration, read_timeout: Duration) -> Self {
+        Self {
+            base_url: url.trim_end_matches('/').to_owned(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(connect_timeout)
+                .timeout_read(read_timeout)
+                .build(),
+            retry_at: Mutex::new(None),
+        }
+    }
+
+    /// Get a `path`, returning `None` on `404 Not Found`
+    fn get(&self, path: &str) -> Result<Option<ureq::Response>> {
+        if let Some(retry_at) = *self.retry_at.lock().expect("lock works") {
+            let now = Instant::now();
+            if now < retry_at {
+                bail!(
+                    "Esplora rate limit; retrying in {}ms",
+                    (retry_at - now).as_millis()
+                );
+            }
+        }
+
+        match self
+            .agent
+            .get(&format!("{}/{}", self.base_url, path))
+            .call()
+        {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(429, response)) => {
+                let retry_after = response
+                    .header("Retry-After")
+                    .and_then(|secs| secs.trim().parse().ok())
+                    .map_or(
+                        Duration::from_millis(Self::RECOMMENDED_ERROR_RETRY_DELAY_MS),
+                        Duration::from_secs,
+                    );
+                debug!("Esplora rate limit; retrying after {:?}", retry_after);
+                *self.retry_at.lock().expect("lock works") = Some(Instant::now() + retry_after);
+                bail!("Esplora {} rate limited (429)", path)
+            }
+            Err(ureq::Error::Status(code, response)) => bail!(
+                "Esplora {} failed with {}: {}",
+                path,
+                code,
+                response.into_string().unwrap_or_default()
+            ),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_string(&self, path: &str) -> Result<Option<String>> {
+        Ok(match self.get(path)? {
+            Some(response) => Some(response.into_string()?.trim().to_owned()),
+            None => None,
+        })
+    }
+
+    /// Network of the chain, recognized by its genesis block
+    pub fn get_network(&self) -> Result<Network> {
+        let genesis = match self.get_block_id_by_height(0)? {
+            Some(genesis) => genesis,
+            None => bail!("Esplora at {} has no genesis block", self.base_url),
+        };
+        for network in &[
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            if genesis_block(*network).block_hash() == genesis {
+                return Ok(*network);
+            }
+        }
+        bail!("Unknown genesis block {}", genesis)
+    }
+}
+
+impl Rpc for EsploraRpc {
+    type Data = Box<bitcoin::Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 10_000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 1000;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        match self.get_string("blocks/tip/height")? {
+            Some(height) => match height.parse() {
+                Ok(height) => Ok(height),
+                Err(_) => bail!("Invalid Esplora tip height: {}", height),
+            },
+            None => bail!("Esplora API not available at {}", self.base_url),
+        }
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(
+            match self.get_string(&format!("block-height/{}", height))? {
+                Some(hash) => Some(BlockHash::from_hex(&hash)?),
+                None => None,
+            },
+        )
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let response = match self.get(&format!("block/{}/raw", hash))? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let block = bitcoin::Block::consensus_decode(response.into_reader())?;
+        let prev_id = block.header.prev_blockhash;
+        Ok(Some((Box::new(block), prev_id)))
+    }
+}
+
+impl HeaderRpc for EsploraRpc {
+    /// Esplora has no endpoint for a range of headers; they are fetched one by one
+    fn get_headers_by_height(&self, start: BlockHeight, count: usize) -> Result<Vec<BlockHeader>> {
+        let mut headers = vec![];
+        for id in self.get_block_ids_by_heights(start, count)? {
+            match self.get_string(&format!("block/{}/header", id))? {
+                Some(header) => headers.push(deserialize(&Vec::<u8>::from_hex(&header)?)?),
+                None => break,
+            }
+        }
+        Ok(headers)
+    }
+}
@@ -44,6 +44,16 @@ where
     }
 }
 
+impl<R> Fetcher<R>
+where
+    R: Rpc + 'static,
+{
+    /// See `Prefetcher::take_error`
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.prefetcher.take_error()
+    }
+}
+
 impl<R> Iterator for Fetcher<R>
 where
     R: Rpc + 'static,
@@ -55,7 +65,13 @@ where
             return None;
         }
 
-        let item = self.prefetcher.next().expect("Prefetcher never ends");
+        let item = match self.prefetcher.next() {
+            Some(item) => item,
+            None => {
+                self.ended = true;
+                return None;
+            }
+        };
         let height = item.height;
 
         if let Some(end) = self.end {
@@ -0,0 +1,372 @@
+//! Headers-first sync
+//!
+//! `HeadersFirst` wraps a node `Rpc` and first downloads all the headers of
+//! the node's best chain, checking that they link, that proof-of-work
+//! matches `bits`, and that `bits` follow the difficulty retargeting rules.
+//! Cumulative chainwork is tracked along the way.
+//!
+//! Block ids by height are then answered from the validated header chain,
+//! and block bodies fetched from the node are checked against it. Reorgs
+//! show up as headers not connecting to the current tip, before any block
+//! body is downloaded.
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{
+    blockdata::constants::genesis_block, consensus::params::Params, util::uint::Uint256, BlockHash,
+    BlockHeader,
+};
+use log::{debug, info, warn};
+use std::sync::Mutex;
+
+/// Max. number of headers to request at once
+///
+/// Same as Core's limit for both REST and P2P.
+pub const HEADERS_BATCH_SIZE: usize = 2000;
+
+/// Number of blocks used to calculate median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// `bits` for the first block of a new difficulty period
+///
+/// `prev` is the last block of the previous period and `first_time`
+/// the time of its first block. Port of Core's `CalculateNextWorkRequired`.
+pub fn retarget_bits(params: &Params, prev: &BlockHeader, first_time: u32) -> u32 {
+    let timespan = u64::from(prev.time.saturating_sub(first_time)).clamp(
+        params.pow_target_timespan / 4,
+        params.pow_target_timespan * 4,
+    );
+    let mut target = prev.target().mul_u32(timespan as u32)
+        / Uint256::from_u64(params.pow_target_timespan).expect("fits");
+    if target > params.pow_limit {
+        target = params.pow_limit;
+    }
+    BlockHeader::compact_target_from_u256(&target)
+}
+
+/// `Rpc` that can fetch block headers in bulk
+pub trait HeaderRpc: Rpc<Id = BlockHash> {
+    /// Get up to `count` headers of the node's best chain, starting at `start` height
+    ///
+    /// Can return fewer (or none) if the chain is shorter.
+    fn get_headers_by_height(&self, start: BlockHeight, count: usize) -> Result<Vec<BlockHeader>>;
+}
+
+struct HeaderEntry {
+    header: BlockHeader,
+    hash: BlockHash,
+    chainwork: Uint256,
+}
+
+/// Chain of validated headers, starting at genesis
+pub struct HeaderChain {
+    params: Params,
+    entries: Vec<HeaderEntry>,
+}
+
+impl HeaderChain {
+    pub fn new(network: bitcoin::Network) -> Self {
+        let genesis = genesis_block(network).header;
+        Self {
+            params: Params::new(network),
+            entries: vec![HeaderEntry {
+                hash: genesis.block_hash(),
+                chainwork: genesis.work(),
+                header: genesis,
+            }],
+        }
+    }
+
+    pub fn tip_height(&self) -> BlockHeight {
+        self.entries.len() as BlockHeight - 1
+    }
+
+    pub fn get_hash(&self, height: BlockHeight) -> Option<BlockHash> {
+        self.entries.get(height as usize).map(|e| e.hash)
+    }
+
+    pub fn get_header(&self, height: BlockHeight) -> Option<&BlockHeader> {
+        self.entries.get(height as usize).map(|e| &e.header)
+    }
+
+    /// Cumulative work of the chain up to (and including) block at `height`
+    pub fn get_chainwork(&self, height: BlockHeight) -> Option<Uint256> {
+        self.entries.get(height as usize).map(|e| e.chainwork)
+    }
+
+    fn median_time_past(&self) -> u32 {
+        let mut times: Vec<_> = self
+            .entries
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|e| e.header.time)
+            .collect();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// `bits` required for a `header` following the current tip
+    ///
+    /// Port of Core's `GetNextWorkRequired`.
+    fn required_bits(&self, header: &BlockHeader) -> u32 {
+        let params = &self.params;
+        let interval = params.difficulty_adjustment_interval() as usize;
+        let height = self.entries.len();
+        let prev = &self.entries[height - 1].header;
+        let pow_limit_bits = BlockHeader::compact_target_from_u256(&params.pow_limit);
+
+        if params.no_pow_retargeting {
+            return prev.bits;
+        }
+
+        if !height.is_multiple_of(interval) {
+            if params.allow_min_difficulty_blocks {
+                // testnet: min. difficulty allowed if the block is late
+                if u64::from(header.time) > u64::from(prev.time) + params.pow_target_spacing * 2 {
+                    return pow_limit_bits;
+                }
+                // otherwise it's the last non-special difficulty
+                return self.entries[..height]
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .find(|(h, e)| h.is_multiple_of(interval) || e.header.bits != pow_limit_bits)
+                    .map(|(_, e)| e.header.bits)
+                    .expect("genesis is always there");
+            }
+            return prev.bits;
+        }
+
+        retarget_bits(params, prev, self.entries[height - interval].header.time)
+    }
+
+    /// Validate and append a `header` on top of the current tip
+    fn push(&mut self, header: BlockHeader) -> Result<()> {
+        let height = self.entries.len();
+        let prev = &self.entries[height - 1];
+        let hash = header.block_hash();
+        if header.prev_blockhash != prev.hash {
+            bail!(
+                "Header {} at {}H doesn't connect to {}",
+                hash,
+                height,
+                prev.hash
+            );
+        }
+        let required_bits = self.required_bits(&header);
+        if header.bits != required_bits {
+            bail!(
+                "Header {} at {}H has bits {:08x}; expected {:08x}",
+                hash,
+                height,
+                header.bits,
+                required_bits
+            );
+        }
+        if let Err(e) = header.validate_pow(&header.target()) {
+            bail!("Header {} at {}H: {}", hash, height, e);
+        }
+        if header.time <= self.median_time_past() {
+            bail!(
+                "Header {} at {}H has time earlier than median of previous blocks",
+                hash,
+                height
+            );
+        }
+        let chainwork = prev.chainwork + header.work();
+        self.entries.push(HeaderEntry {
+            header,
+            hash,
+            chainwork,
+        });
+        Ok(())
+    }
+
+    /// Replace everything from `height` up with `headers`
+    ///
+    /// On any validation error, the chain is left unchanged.
+    pub fn connect(&mut self, height: BlockHeight, headers: &[BlockHeader]) -> Result<()> {
+        let height = height as usize;
+        assert!(0 < height && height <= self.entries.len());
+        let old_tip_chainwork = self.entries.last().expect("not empty").chainwork;
+        let replaced = self.entries.split_off(height);
+
+        for header in headers {
+            if let Err(e) = self.push(*header) {
+                self.entries.truncate(height);
+                self.entries.extend(replaced);
+                return Err(e);
+            }
+        }
+
+        let unchanged = replaced
+            .iter()
+            .zip(&self.entries[height..])
+            .take_while(|(old, new)| old.hash == new.hash)
+            .count();
+        if unchanged < replaced.len() {
+            info!(
+                "Headers: reorg of {} blocks at {}H; new tip {}H",
+                replaced.len() - unchanged,
+                height + unchanged,
+                self.tip_height()
+            );
+            let new_tip_chainwork = self.entries.last().expect("not empty").chainwork;
+            if new_tip_chainwork < old_tip_chainwork {
+                warn!("Headers: node switched to a chain with less work");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `Rpc` wrapper doing headers-first sync
+pub struct HeadersFirst<R> {
+    rpc: R,
+    chain: Mutex<HeaderChain>,
+}
+
+impl<R> HeadersFirst<R>
+where
+    R: HeaderRpc<Data = Box<bitcoin::Block>>,
+{
+    pub fn new(rpc: R, network: bitcoin::Network) -> Self {
+        Self {
+            rpc,
+            chain: Mutex::new(HeaderChain::new(network)),
+        }
+    }
+
+    /// The wrapped `Rpc`
+    pub fn inner(&self) -> &R {
+        &self.rpc
+    }
+
+    /// Bring the header chain up to date with the node
+    ///
+    /// Returns the height of the tip.
+    pub fn sync(&self) -> Result<BlockHeight> {
+        let mut chain = self.chain.lock().expect("lock works");
+        self.sync_locked(&mut chain)?;
+        Ok(chain.tip_height())
+    }
+
+    /// Cumulative chainwork at `height`
+    pub fn get_chainwork(&self, height: BlockHeight) -> Option<Uint256> {
+        self.chain.lock().expect("lock works").get_chainwork(height)
+    }
+
+    fn sync_locked(&self, chain: &mut HeaderChain) -> Result<()> {
+        // every request starts at a header we already have, to check it's
+        // still in the node's chain
+        let mut anchor = chain.tip_height();
+        let start_tip = anchor;
+        loop {
+            let headers = self.rpc.get_headers_by_height(anchor, HEADERS_BATCH_SIZE)?;
+            if headers.first().map(BlockHeader::block_hash) != chain.get_hash(anchor) {
+                anchor = self.find_fork(chain, anchor)?;
+                debug!(
+                    "Headers: tip not in node's chain; last common at {}H",
+                    anchor
+                );
+                continue;
+            }
+            chain.connect(anchor + 1, &headers[1..])?;
+            if headers.len() < HEADERS_BATCH_SIZE {
+                break;
+            }
+            anchor = chain.tip_height();
+            debug!("Headers: synced to {}H", anchor);
+        }
+        if chain.tip_height() != start_tip {
+            debug!(
+                "Headers: tip at {}H; chainwork {:?}",
+                chain.tip_height(),
+                chain.get_chainwork(chain.tip_height()).expect("tip exists")
+            );
+        }
+        Ok(())
+    }
+
+    /// Find a height below `height` where the node's chain matches ours
+    fn find_fork(&self, chain: &HeaderChain, mut height: BlockHeight) -> Result<BlockHeight> {
+        let mut step = 1;
+        loop {
+            height = height.saturating_sub(step);
+            let headers = self.rpc.get_headers_by_height(height, 1)?;
+            if headers.first().map(BlockHeader::block_hash) == chain.get_hash(height) {
+                return Ok(height);
+            }
+            if height == 0 {
+                bail!("Node's genesis block doesn't match; wrong network?");
+            }
+            step *= 2;
+        }
+    }
+
+    /// Check that a block from the node is the one we expect
+    fn check_block(id: &BlockHash, block: &bitcoin::Block) -> Result<()> {
+        let hash = block.block_hash();
+        if hash != *id {
+            bail!("Node returned block {} when asked for {}", hash, id);
+        }
+        Ok(())
+    }
+}
+
+impl<R> Rpc for HeadersFirst<R>
+where
+    R: HeaderRpc<Data = Box<bitcoin::Block>>,
+{
+    type Data = Box<bitcoin::Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = R::RECOMMENDED_ERROR_RETRY_DELAY_MS;
+    const RECOMMENDED_BATCH_SIZE: usize = R::RECOMMENDED_BATCH_SIZE;
+
+    fn head_retry_delay_ms(&self) -> u64 {
+        self.rpc.head_retry_delay_ms()
+    }
+
+    fn error_retry_delay_ms(&self) -> u64 {
+        self.rpc.error_retry_delay_ms()
+    }
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        self.sync()
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        let mut chain = self.chain.lock().expect("lock works");
+        if height > chain.tip_height() {
+            self.sync_locked(&mut chain)?;
+        }
+        Ok(chain.get_hash(height))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let res = self.rpc.get_block_by_id(id)?;
+        if let Some((block, _)) = &res {
+            Self::check_block(id, block)?;
+        }
+        Ok(res)
+    }
+
+    fn get_block_ids_by_heights(&self, start: BlockHeight, count: usize) -> Result<Vec<Self::Id>> {
+        let mut chain = self.chain.lock().expect("lock works");
+        let end = start + count as BlockHeight;
+        if end > chain.tip_height() + 1 {
+            self.sync_locked(&mut chain)?;
+        }
+        Ok((start..end).map_while(|h| chain.get_hash(h)).collect())
+    }
+
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let blocks = self.rpc.get_blocks_by_ids(ids)?;
+        for (id, block) in ids.iter().zip(&blocks) {
+            if let Some((block, _)) = block {
+                Self::check_block(id, block)?;
+            }
+        }
+        Ok(blocks)
+    }
+}
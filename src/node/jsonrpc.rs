@@ -0,0 +1,335 @@
+//! Block source using Bitcoin Core's JSON-RPC interface
+//!
+//! `Rpc` is implemented for any JSON-RPC client that can send batches of
+//! calls: `bitcoincore_rpc::Client`, and `JsonRpcClient`, which adds
+//! connect/read timeouts and follows cookie file rotation.
+use log::debug;
+
+use super::headers::HeaderRpc;
+use crate::{prelude::*, util, BlockHeight, Rpc};
+use bitcoin::util::uint::Uint256;
+use bitcoincore_rpc::{jsonrpc, Auth, RpcApi};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// JSON-RPC client that can send many calls in one request
+pub trait JsonRpcBatch: RpcApi + Send + Sync {
+    /// Call `method` once for each of `params` in a single JSON-RPC batch
+    ///
+    /// Responses are in the order of `params`.
+    fn send_batch<P: AsRef<[serde_json::Value]>>(
+        &self,
+        method: &str,
+        params: &[P],
+    ) -> Result<Vec<jsonrpc::Response>>;
+}
+
+impl<C> Rpc for C
+where
+    C: JsonRpcBatch,
+{
+    type Data = Box<bitcoin::Block>;
+    type Id = bitcoin::hash_types::BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 2000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 100;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(RpcApi::get_block_count(self)? as u32)
+    }
+
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        // only the fields needed, as the others change between Core versions
+        let info: serde_json::Value = self.call("getblockchaininfo", &[])?;
+        match (info["blocks"].as_u64(), info["chainwork"].as_str()) {
+            (Some(blocks), Some(chainwork)) => Ok((
+                blocks as BlockHeight,
+                Some(util::bitcoin::chainwork_from_hex(chainwork)?),
+            )),
+            _ => bail!("Missing `blocks` or `chainwork` in getblockchaininfo"),
+        }
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        match self.get_block_hash(u64::from(height)) {
+            Err(e) => {
+                if e.to_string().contains("Block height out of range") {
+                    Ok(None)
+                } else {
+                    Err(e.into())
+                }
+            }
+            Ok(o) => Ok(Some(o)),
+        }
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let block: Box<bitcoin::Block> = match self.get_by_id(hash) {
+            // the node might be behind, or on another fork
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(ref e)))
+                if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+            Ok(o) => Box::new(o),
+        };
+        let prev_id = block.header.prev_blockhash;
+        Ok(Some((block, prev_id)))
+    }
+
+    const RECOMMENDED_BATCH_SIZE: usize = 16;
+
+    fn get_block_ids_by_heights(&self, start: BlockHeight, count: usize) -> Result<Vec<Self::Id>> {
+        let params: Vec<_> = (start..)
+            .take(count)
+            .map(|height| [serde_json::Value::from(height)])
+            .collect();
+        let mut ids = Vec::with_capacity(count);
+        for response in self.send_batch("getblockhash", &params)? {
+            match response.into_result() {
+                Ok(id) => ids.push(id),
+                Err(jsonrpc::Error::Rpc(ref e)) if e.code == RPC_INVALID_PARAMETER => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(ids)
+    }
+
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let params: Vec<_> = ids
+            .iter()
+            .map(|id| [serde_json::Value::from(id.to_string()), 0.into()])
+            .collect();
+        let mut blocks = Vec::with_capacity(ids.len());
+        for response in self.send_batch("getblock", &params)? {
+            match response.into_result::<String>() {
+                Ok(hex) => {
+                    let block: Box<bitcoin::Block> =
+                        Box::new(bitcoin::consensus::deserialize(&hex::decode(hex)?)?);
+                    let prev_id = block.header.prev_blockhash;
+                    blocks.push(Some((block, prev_id)));
+                }
+                Err(jsonrpc::Error::Rpc(ref e)) if e.code == RPC_INVALID_ADDRESS_OR_KEY => {
+                    blocks.push(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+impl<C> HeaderRpc for C
+where
+    C: JsonRpcBatch,
+{
+    fn get_headers_by_height(
+        &self,
+        start: BlockHeight,
+        count: usize,
+    ) -> Result<Vec<bitcoin::BlockHeader>> {
+        let params: Vec<_> = self
+            .get_block_ids_by_heights(start, count)?
+            .iter()
+            .map(|id| [serde_json::Value::from(id.to_string()), false.into()])
+            .collect();
+        self.send_batch("getblockheader", &params)?
+            .into_iter()
+            .map(|response| {
+                let hex: String = response.into_result()?;
+                Ok(bitcoin::consensus::deserialize(&hex::decode(hex)?)?)
+            })
+            .collect()
+    }
+}
+
+/// `RPC_INVALID_PARAMETER` from Core's `rpc/protocol.h` (eg. height out of range)
+const RPC_INVALID_PARAMETER: i32 = -8;
+/// `RPC_INVALID_ADDRESS_OR_KEY` from Core's `rpc/protocol.h` (eg. block not found)
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+impl JsonRpcBatch for bitcoincore_rpc::Client {
+    fn send_batch<P: AsRef<[serde_json::Value]>>(
+        &self,
+        method: &str,
+        params: &[P],
+    ) -> Result<Vec<jsonrpc::Response>> {
+        if params.is_empty() {
+            return Ok(vec![]);
+        }
+        let client = self.get_jsonrpc_client();
+        let requests: Vec<_> = params
+            .iter()
+            .map(|p| client.build_request(method, p.as_ref()))
+            .collect();
+        let mut responses = Vec::with_capacity(params.len());
+        for response in client.send_batch(&requests)? {
+            match response {
+                Some(response) => responses.push(response),
+                None => bail!("Node didn't respond to some of the `{}` calls", method),
+            }
+        }
+        Ok(responses)
+    }
+}
+
+/// JSON-RPC client with timeouts, re-reading the cookie file when needed
+///
+/// Core writes a new cookie file every time it starts, so on `401 Unauthorized`
+/// the cookie is read again, and the call retried once.
+pub struct JsonRpcClient {
+    url: String,
+    auth: Auth,
+    agent: ureq::Agent,
+    // `Authorization` header value; for cookie auth read lazily,
+    // as the node might not have been started yet
+    authorization: Mutex<Option<String>>,
+    nonce: AtomicU64,
+}
+
+impl JsonRpcClient {
+    pub fn new(url: &str, auth: Auth, connect_timeout: Duration, read_timeout: Duration) -> Self {
+        let authorization = match &auth {
+            Auth::UserPass(user, pass) => Some(basic_authorization(user, pass)),
+            Auth::None | Auth::CookieFile(_) => None,
+        };
+        Self {
+            url: url.to_owned(),
+            auth,
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(connect_timeout)
+                .timeout_read(read_timeout)
+                .build(),
+            authorization: Mutex::new(authorization),
+            nonce: AtomicU64::new(0),
+        }
+    }
+
+    /// `Authorization` header value, reading the cookie file if not read yet, or `reread`
+    fn authorization(&self, reread: bool) -> bitcoincore_rpc::Result<Option<String>> {
+        let mut authorization = self.authorization.lock().expect("lock works");
+        if let Auth::CookieFile(path) = &self.auth {
+            if reread || authorization.is_none() {
+                let cookie = std::fs::read_to_string(path)?;
+                let mut split = cookie.trim().splitn(2, ':');
+                match (split.next(), split.next()) {
+                    (Some(user), Some(pass)) => {
+                        *authorization = Some(basic_authorization(user, pass))
+                    }
+                    _ => return Err(bitcoincore_rpc::Error::InvalidCookieFile),
+                }
+            }
+        }
+        Ok(authorization.clone())
+    }
+
+    fn build_request<'a, 'b>(
+        &self,
+        method: &'a str,
+        params: &'b [serde_json::Value],
+    ) -> jsonrpc::Request<'a, 'b> {
+        jsonrpc::Request {
+            method,
+            params,
+            id: (self.nonce.fetch_add(1, Ordering::SeqCst) + 1).into(),
+            jsonrpc: Some("2.0"),
+        }
+    }
+
+    /// Post `body`, and parse the response
+    fn send_raw<R>(&self, body: &impl serde::Serialize) -> bitcoincore_rpc::Result<R>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        let body = serde_json::to_vec(body)?;
+        let mut reread = false;
+        loop {
+            let mut request = self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/json");
+            if let Some(authorization) = self.authorization(reread)? {
+                request = request.set("Authorization", &authorization);
+            }
+            match request.send_bytes(&body) {
+                Ok(response) => return Ok(serde_json::from_reader(response.into_reader())?),
+                Err(ureq::Error::Status(401, _))
+                    if !reread && matches!(self.auth, Auth::CookieFile(_)) =>
+                {
+                    debug!("Node at {} rejected the cookie; reading it again", self.url);
+                    reread = true;
+                }
+                // Core responds to failed calls with error statuses, but still JSON-RPC bodies
+                Err(ureq::Error::Status(code, response)) => {
+                    let text = response.into_string()?;
+                    return serde_json::from_str(&text).map_err(|_| {
+                        io_error(format!(
+                            "Node at {} responded with {}: {}",
+                            self.url,
+                            code,
+                            text.trim()
+                        ))
+                    });
+                }
+                Err(e) => return Err(io_error(e.to_string())),
+            }
+        }
+    }
+}
+
+fn basic_authorization(user: &str, pass: &str) -> String {
+    format!("Basic {}", base64::encode(format!("{}:{}", user, pass)))
+}
+
+fn io_error(msg: String) -> bitcoincore_rpc::Error {
+    std::io::Error::other(msg).into()
+}
+
+impl RpcApi for JsonRpcClient {
+    fn call<T: for<'a> serde::Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[serde_json::Value],
+    ) -> bitcoincore_rpc::Result<T> {
+        let request = self.build_request(cmd, args);
+        let response: jsonrpc::Response = self.send_raw(&request)?;
+        if response.id != request.id {
+            return Err(jsonrpc::Error::NonceMismatch.into());
+        }
+        Ok(response.into_result()?)
+    }
+}
+
+impl JsonRpcBatch for JsonRpcClient {
+    fn send_batch<P: AsRef<[serde_json::Value]>>(
+        &self,
+        method: &str,
+        params: &[P],
+    ) -> Result<Vec<jsonrpc::Response>> {
+        if params.is_empty() {
+            return Ok(vec![]);
+        }
+        let requests: Vec<_> = params
+            .iter()
+            .map(|p| self.build_request(method, p.as_ref()))
+            .collect();
+        let responses: Vec<jsonrpc::Response> = self.send_raw(&requests)?;
+        let mut responses: HashMap<_, _> = responses
+            .into_iter()
+            .map(|response| (response.id.to_string(), response))
+            .collect();
+        requests
+            .iter()
+            .map(|request| match responses.remove(&request.id.to_string()) {
+                Some(response) => Ok(response),
+                None => bail!("Node didn't respond to some of the `{}` calls", method),
+            })
+            .collect()
+    }
+}
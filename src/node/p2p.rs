@@ -0,0 +1,402 @@
+//! Block source speaking Bitcoin P2P protocol
+//!
+//! Connects to a single peer (usually own node), does the `version`/`verack`
+//! handshake and keeps a header chain synced with `getheaders`, so it can
+//! answer queries by height. Blocks are requested with `getdata`, over
+//! as many connections as there are concurrent requests.
+//!
+//! No RPC credentials are needed. The peer is trusted to send its best chain;
+//! if that chain changes, the header chain simply switches to it, and it's
+//! up to the `Prefetcher` to detect the reorg, as with any other `Rpc`.
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    consensus::encode::{self, Decodable},
+    hash_types::BlockHash,
+    network::{
+        address::Address,
+        constants::ServiceFlags,
+        message::{NetworkMessage, RawNetworkMessage},
+        message_blockdata::{GetHeadersMessage, Inventory},
+        message_network::VersionMessage,
+    },
+    BlockHeader,
+};
+use log::{debug, info, trace};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Protocol version we announce (`sendheaders`, witness blocks)
+const PROTOCOL_VERSION: u32 = 70015;
+/// Max. number of headers a peer sends in one `headers` message
+const MAX_HEADERS_RESULTS: usize = 2000;
+/// Max. size of a message payload a peer may send (same as Core's)
+const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4_000_000;
+/// Size of a message header: magic, command, payload length and checksum
+const MESSAGE_HEADER_SIZE: usize = 24;
+const USER_AGENT: &str = concat!("/bitcoin-indexer:", env!("CARGO_PKG_VERSION"), "/");
+
+/// Port nodes of `network` listen on by default
+pub fn default_port(network: bitcoin::Network) -> u16 {
+    match network {
+        bitcoin::Network::Bitcoin => 8333,
+        bitcoin::Network::Testnet => 18333,
+        bitcoin::Network::Signet => 38333,
+        bitcoin::Network::Regtest => 18444,
+    }
+}
+
+/// One connection to a peer, after a successful handshake
+struct Connection {
+    stream: TcpStream,
+    magic: u32,
+}
+
+impl Connection {
+    fn connect(
+        addr: &SocketAddr,
+        network: bitcoin::Network,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(addr, connect_timeout)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_write_timeout(Some(read_timeout))?;
+        stream.set_nodelay(true)?;
+
+        let mut conn = Self {
+            stream,
+            magic: network.magic(),
+        };
+        conn.handshake(addr)?;
+        Ok(conn)
+    }
+
+    fn handshake(&mut self, addr: &SocketAddr) -> Result<()> {
+        let local_addr = self.stream.local_addr()?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            Address::new(addr, ServiceFlags::NONE),
+            Address::new(&local_addr, ServiceFlags::NONE),
+            timestamp as u64,
+            USER_AGENT.into(),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        self.send(NetworkMessage::Version(version))?;
+
+        let (mut got_version, mut got_verack) = (false, false);
+        while !(got_version && got_verack) {
+            match self.recv()? {
+                NetworkMessage::Version(version) => {
+                    debug!(
+                        "P2P: connected to {} ({}, version {})",
+                        addr, version.user_agent, version.version
+                    );
+                    self.send(NetworkMessage::Verack)?;
+                    got_version = true;
+                }
+                NetworkMessage::Verack => got_verack = true,
+                other => trace!("P2P: ignoring {} during handshake", other.cmd()),
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<()> {
+        let msg = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.stream.write_all(&encode::serialize(&msg))?;
+        Ok(())
+    }
+
+    /// Read exactly one message from the stream
+    ///
+    /// The payload length from the header tells how much to read, so
+    /// every message is parsed once, no matter how big it is.
+    fn read_message(&mut self) -> Result<RawNetworkMessage> {
+        let mut buf = vec![0; MESSAGE_HEADER_SIZE];
+        self.stream.read_exact(&mut buf)?;
+        let magic = u32::consensus_decode(&buf[0..4])?;
+        if magic != self.magic {
+            bail!("P2P: peer sent message with wrong magic {:x}", magic);
+        }
+        let payload_len = u32::consensus_decode(&buf[16..20])? as usize;
+        if payload_len > MAX_PROTOCOL_MESSAGE_LENGTH {
+            bail!("P2P: peer sent a message of {} bytes", payload_len);
+        }
+        buf.resize(MESSAGE_HEADER_SIZE + payload_len, 0);
+        self.stream.read_exact(&mut buf[MESSAGE_HEADER_SIZE..])?;
+        Ok(encode::deserialize(&buf)?)
+    }
+
+    /// Receive next message, handling the ones that don't need the caller
+    fn recv(&mut self) -> Result<NetworkMessage> {
+        loop {
+            match self.read_message()?.payload {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+}
+
+/// Chain of block hashes, as seen by the peer
+struct HeaderChain {
+    by_height: Vec<BlockHash>,
+    heights: HashMap<BlockHash, BlockHeight>,
+}
+
+impl HeaderChain {
+    fn new(network: bitcoin::Network) -> Self {
+        let genesis = genesis_block(network).block_hash();
+        Self {
+            by_height: vec![genesis],
+            heights: vec![(genesis, 0)].into_iter().collect(),
+        }
+    }
+
+    fn tip_height(&self) -> BlockHeight {
+        self.by_height.len() as BlockHeight - 1
+    }
+
+    /// Block locator: dense near the tip, exponentially sparser down to genesis
+    fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut height = self.by_height.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.by_height[height]);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Apply headers from the peer
+    ///
+    /// Returns number of headers that were connected.
+    fn apply(&mut self, headers: &[BlockHeader]) -> usize {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return 0,
+        };
+        let fork_height = match self.heights.get(&first.prev_blockhash) {
+            Some(h) => *h,
+            None => {
+                debug!(
+                    "P2P: headers starting at {} don't connect",
+                    first.block_hash()
+                );
+                return 0;
+            }
+        };
+
+        let mut connected = 0;
+        let mut prev = first.prev_blockhash;
+        for (i, header) in headers.iter().enumerate() {
+            if header.prev_blockhash != prev {
+                debug!("P2P: non-continuous headers from the peer");
+                break;
+            }
+            let height = fork_height + 1 + i as BlockHeight;
+            let hash = header.block_hash();
+            if self.by_height.get(height as usize) != Some(&hash) {
+                if (height as usize) < self.by_height.len() {
+                    info!("P2P: peer switched chains at {}H", height);
+                    for stale in self.by_height.drain(height as usize..) {
+                        self.heights.remove(&stale);
+                    }
+                }
+                self.by_height.push(hash);
+                self.heights.insert(hash, height);
+            }
+            prev = hash;
+            connected += 1;
+        }
+        connected
+    }
+}
+
+/// `Rpc` implementation talking to a node over Bitcoin P2P protocol
+pub struct P2pRpc {
+    addr: SocketAddr,
+    network: bitcoin::Network,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    /// Connections nobody is using at the moment
+    idle: Mutex<Vec<Connection>>,
+    chain: Mutex<HeaderChain>,
+}
+
+impl P2pRpc {
+    pub fn new(addr: SocketAddr, network: bitcoin::Network) -> Self {
+        Self::with_timeouts(
+            addr,
+            network,
+            crate::DEFAULT_CONNECT_TIMEOUT,
+            crate::DEFAULT_READ_TIMEOUT,
+        )
+    }
+
+    pub fn with_timeouts(
+        addr: SocketAddr,
+        network: bitcoin::Network,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> Self {
+        Self {
+            addr,
+            network,
+            connect_timeout,
+            read_timeout,
+            idle: Mutex::new(vec![]),
+            chain: Mutex::new(HeaderChain::new(network)),
+        }
+    }
+
+    pub fn network(&self) -> bitcoin::Network {
+        self.network
+    }
+
+    /// Run `f` with a connection nobody else is using
+    ///
+    /// Takes an idle connection, or opens a new one, and puts it back
+    /// afterwards. Any error drops the connection instead.
+    fn with_conn<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let idle = self.idle.lock().expect("lock works").pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => Connection::connect(
+                &self.addr,
+                self.network,
+                self.connect_timeout,
+                self.read_timeout,
+            )?,
+        };
+        let res = f(&mut conn)?;
+        self.idle.lock().expect("lock works").push(conn);
+        Ok(res)
+    }
+
+    /// Download all the new headers from the peer
+    fn sync_headers(&self, chain: &mut HeaderChain) -> Result<()> {
+        self.with_conn(|conn| loop {
+            conn.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+                chain.locator(),
+                BlockHash::default(),
+            )))?;
+            let headers = loop {
+                if let NetworkMessage::Headers(headers) = conn.recv()? {
+                    break headers;
+                }
+            };
+            let len = headers.len();
+            let connected = chain.apply(&headers);
+            trace!(
+                "P2P: got {} headers, connected {}; tip at {}H",
+                len,
+                connected,
+                chain.tip_height()
+            );
+            if len < MAX_HEADERS_RESULTS || connected == 0 {
+                return Ok(());
+            }
+        })
+    }
+}
+
+impl Rpc for P2pRpc {
+    type Data = Box<bitcoin::Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 2000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 100;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        let mut chain = self.chain.lock().expect("lock works");
+        self.sync_headers(&mut chain)?;
+        Ok(chain.tip_height())
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        let mut chain = self.chain.lock().expect("lock works");
+        if height > chain.tip_height() {
+            self.sync_headers(&mut chain)?;
+        }
+        Ok(chain.by_height.get(height as usize).cloned())
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        Ok(self
+            .get_blocks_by_ids(std::slice::from_ref(hash))?
+            .pop()
+            .expect("one block per id"))
+    }
+
+    /// Blocks are streamed back for a single `getdata`, so a whole batch
+    /// takes one round trip
+    const RECOMMENDED_BATCH_SIZE: usize = 16;
+
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let mut blocks: Vec<Option<(Self::Data, Self::Id)>> = ids.iter().map(|_| None).collect();
+        let mut pending: HashMap<BlockHash, Vec<usize>> = HashMap::new();
+        for (i, id) in ids.iter().enumerate() {
+            pending.entry(*id).or_default().push(i);
+        }
+        if pending.is_empty() {
+            return Ok(blocks);
+        }
+
+        self.with_conn(|conn| {
+            let inv = pending
+                .keys()
+                .map(|hash| Inventory::WitnessBlock(*hash))
+                .collect();
+            conn.send(NetworkMessage::GetData(inv))?;
+            while !pending.is_empty() {
+                match conn.recv()? {
+                    NetworkMessage::Block(block) => {
+                        let hash = block.block_hash();
+                        let positions = match pending.remove(&hash) {
+                            Some(positions) => positions,
+                            None => {
+                                trace!("P2P: ignoring block {} nobody asked for", hash);
+                                continue;
+                            }
+                        };
+                        let prev_id = block.header.prev_blockhash;
+                        let (first, rest) = positions.split_first().expect("never empty");
+                        for i in rest {
+                            blocks[*i] = Some((Box::new(block.clone()), prev_id));
+                        }
+                        blocks[*first] = Some((Box::new(block), prev_id));
+                    }
+                    NetworkMessage::NotFound(inv) => {
+                        for item in inv {
+                            if let Inventory::WitnessBlock(hash) | Inventory::Block(hash) = item {
+                                pending.remove(&hash);
+                            }
+                        }
+                    }
+                    other => trace!("P2P: ignoring {} while waiting for blocks", other.cmd()),
+                }
+            }
+            Ok(())
+        })?;
+        Ok(blocks)
+    }
+}
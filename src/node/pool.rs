@@ -0,0 +1,343 @@
+//! Pool of nodes acting as one `Rpc`
+//!
+//! Block fetches (the expensive part) are spread round-robin across all
+//! healthy nodes. A node that returns an error is marked unhealthy and
+//! skipped, with exponential backoff, until it's time to retry it.
+//!
+//! Nodes can be at different heights, or even on different forks. To keep
+//! reorg detection in `Prefetcher` sound, height -> id queries are always
+//! answered by a single *leader*: the healthy node with the most chainwork.
+//! Nodes that can't report their chainwork (eg. Esplora) are compared by
+//! block count instead, so a longer but lower-work fork can win there.
+//! Blocks are requested by id, so it doesn't matter which node serves them;
+//! a node that doesn't have a given block just defers to the others.
+//! The leader changes only when it fails or another node gets ahead of it,
+//! which to the `Prefetcher` looks like any other reorg.
+use super::headers::HeaderRpc;
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::util::uint::Uint256;
+use log::{debug, info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const MIN_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF_MS: u64 = 60_000;
+
+#[derive(Default)]
+struct Health {
+    /// Consecutive failures
+    failures: u32,
+    /// Don't use the node before this time
+    retry_at: Option<Instant>,
+    /// Last known block count
+    block_count: Option<BlockHeight>,
+}
+
+struct Member<R> {
+    name: String,
+    rpc: R,
+    health: Mutex<Health>,
+}
+
+/// `Rpc` spreading the load across multiple nodes
+pub struct RpcPool<R> {
+    members: Vec<Member<R>>,
+    /// Index of the node that defines the canonical chain
+    leader: AtomicUsize,
+    /// Round-robin counter for block fetches
+    next: AtomicUsize,
+}
+
+impl<R> RpcPool<R>
+where
+    R: Rpc,
+{
+    /// Create a pool from `(name, rpc)` pairs; name is used only for logging
+    pub fn new(members: Vec<(String, R)>) -> Result<Self> {
+        if members.is_empty() {
+            bail!("Node pool can't be empty");
+        }
+        Ok(Self {
+            members: members
+                .into_iter()
+                .map(|(name, rpc)| Member {
+                    name,
+                    rpc,
+                    health: default(),
+                })
+                .collect(),
+            leader: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// All the nodes in the pool
+    pub fn iter(&self) -> impl Iterator<Item = &R> {
+        self.members.iter().map(|m| &m.rpc)
+    }
+
+    /// Is the node at `i` currently considered healthy
+    pub fn is_healthy(&self, i: usize) -> bool {
+        let health = self.members[i].health.lock().expect("lock works");
+        health.retry_at.is_none_or(|t| t <= Instant::now())
+    }
+
+    fn leader(&self) -> usize {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    fn set_leader(&self, i: usize) {
+        if self.leader.swap(i, Ordering::SeqCst) != i {
+            info!("Node pool: following {} now", self.members[i].name);
+        }
+    }
+
+    /// Call `f` on the node at `i`, recording the outcome
+    fn call<T>(&self, i: usize, f: impl FnOnce(&R) -> Result<T>) -> Result<T> {
+        let member = &self.members[i];
+        let res = f(&member.rpc);
+        let mut health = member.health.lock().expect("lock works");
+        match res {
+            Ok(_) => {
+                if health.failures > 0 {
+                    info!("Node pool: {} is healthy again", member.name);
+                }
+                health.failures = 0;
+                health.retry_at = None;
+            }
+            Err(ref e) => {
+                health.failures += 1;
+                let backoff_ms = MIN_BACKOFF_MS
+                    .saturating_mul(1 << (health.failures - 1).min(16))
+                    .min(MAX_BACKOFF_MS);
+                health.retry_at = Some(Instant::now() + Duration::from_millis(backoff_ms));
+                if health.failures == 1 {
+                    warn!("Node pool: {} failed: {}", member.name, e);
+                } else {
+                    debug!(
+                        "Node pool: {} failed {} times in a row: {}",
+                        member.name, health.failures, e
+                    );
+                }
+            }
+        }
+        res
+    }
+
+    /// Indices of nodes to try, in order: healthy ones, starting from `first`
+    ///
+    /// If no node is healthy, all of them are worth a try.
+    fn candidates(&self, first: usize) -> Vec<usize> {
+        let n = self.members.len();
+        let all = (0..n).map(|i| (first + i) % n);
+        let healthy: Vec<_> = all.clone().filter(|&i| self.is_healthy(i)).collect();
+        if healthy.is_empty() {
+            all.collect()
+        } else {
+            healthy
+        }
+    }
+
+    fn known_block_count(&self, i: usize) -> Option<BlockHeight> {
+        self.members[i]
+            .health
+            .lock()
+            .expect("lock works")
+            .block_count
+    }
+
+    /// Query tips of all the healthy nodes and elect the leader
+    ///
+    /// The current leader is kept unless another node is strictly ahead:
+    /// has more chainwork, or more blocks if either's chainwork is unknown.
+    fn refresh_leader(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        let leader = self.leader();
+        let mut best: Option<(usize, BlockHeight, Option<Uint256>)> = None;
+        let mut last_err = None;
+        for i in self.candidates(leader) {
+            match self.call(i, |rpc| rpc.get_block_count_and_work()) {
+                Ok((count, work)) => {
+                    self.members[i]
+                        .health
+                        .lock()
+                        .expect("lock works")
+                        .block_count = Some(count);
+                    // `candidates` starts with the leader, so it wins ties
+                    let ahead = match best {
+                        None => true,
+                        Some((_, _, Some(best_work))) if work.is_some() => Some(best_work) < work,
+                        Some((_, best_count, _)) => best_count < count,
+                    };
+                    if ahead {
+                        best = Some((i, count, work));
+                    }
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match (best, last_err) {
+            (Some((i, count, work)), _) => {
+                self.set_leader(i);
+                Ok((count, work))
+            }
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!("pool is never empty"),
+        }
+    }
+
+    /// Run a height-based query `f` against the leader
+    ///
+    /// If the leader is down, the nodes that were furthest ahead are tried
+    /// next. If the answer is that nothing is at `height` yet (`!found`),
+    /// check if some other node got ahead, and switch to it.
+    fn ask_leader<T>(
+        &self,
+        height: BlockHeight,
+        f: impl Fn(&R) -> Result<T>,
+        found: impl Fn(&T) -> bool,
+    ) -> Result<T> {
+        let leader = self.leader();
+        let mut candidates = self.candidates(leader);
+        candidates.sort_by_key(|&i| (i != leader, std::cmp::Reverse(self.known_block_count(i))));
+
+        let mut last_err = None;
+        for i in candidates {
+            match self.call(i, &f) {
+                Ok(res) => {
+                    self.set_leader(i);
+                    if !found(&res) && self.members.len() > 1 && self.refresh_leader()?.0 >= height
+                    {
+                        return self.call(self.leader(), &f);
+                    }
+                    return Ok(res);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.expect("at least one candidate"))
+    }
+}
+
+impl<R> Rpc for RpcPool<R>
+where
+    R: Rpc,
+{
+    type Data = R::Data;
+    type Id = R::Id;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = R::RECOMMENDED_ERROR_RETRY_DELAY_MS;
+
+    /// The longest of the members', so none of them gets asked too often
+    fn head_retry_delay_ms(&self) -> u64 {
+        self.iter()
+            .map(R::head_retry_delay_ms)
+            .max()
+            .expect("pool is never empty")
+    }
+
+    /// See `head_retry_delay_ms`
+    fn error_retry_delay_ms(&self) -> u64 {
+        self.iter()
+            .map(R::error_retry_delay_ms)
+            .max()
+            .expect("pool is never empty")
+    }
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.refresh_leader()?.0)
+    }
+
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        self.refresh_leader()
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        self.ask_leader(
+            height,
+            |rpc| rpc.get_block_id_by_height(height),
+            Option::is_some,
+        )
+    }
+
+    const RECOMMENDED_BATCH_SIZE: usize = R::RECOMMENDED_BATCH_SIZE;
+
+    fn get_block_ids_by_heights(&self, start: BlockHeight, count: usize) -> Result<Vec<Self::Id>> {
+        self.ask_leader(
+            start,
+            |rpc| rpc.get_block_ids_by_heights(start, count),
+            |ids| !ids.is_empty(),
+        )
+    }
+
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+
+        let mut last_err = None;
+        for i in self.candidates(first) {
+            match self.call(i, |rpc| rpc.get_blocks_by_ids(ids)) {
+                Ok(blocks) => {
+                    // ask the other nodes for the ones this one doesn't have
+                    return ids
+                        .iter()
+                        .zip(blocks)
+                        .map(|(id, block)| match block {
+                            Some(block) => Ok(Some(block)),
+                            None => self.get_block_by_id(id),
+                        })
+                        .collect();
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.expect("at least one candidate"))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+
+        let mut last_err = None;
+        let mut not_found = false;
+        for i in self.candidates(first) {
+            match self.call(i, |rpc| rpc.get_block_by_id(id)) {
+                Ok(Some(block)) => return Ok(Some(block)),
+                // the node might be behind or on a different fork
+                Ok(None) => not_found = true,
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match last_err {
+            Some(e) if !not_found => Err(e),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<R> HeaderRpc for RpcPool<R>
+where
+    R: HeaderRpc,
+{
+    fn get_headers_by_height(
+        &self,
+        start: BlockHeight,
+        count: usize,
+    ) -> Result<Vec<bitcoin::BlockHeader>> {
+        self.ask_leader(
+            start,
+            |rpc| rpc.get_headers_by_height(start, count),
+            |headers| !headers.is_empty(),
+        )
+    }
+}
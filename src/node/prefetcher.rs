@@ -1,15 +1,18 @@
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 
-use crate::{prelude::*, BlockHeight, Rpc, RpcBlock, RpcBlockWithPrevId, WithHeightAndId};
-use bitcoincore_rpc::RpcApi;
+use crate::{
+    prelude::*, BlockHeight, DataSize, Rpc, RpcBlock, RpcBlockWithPrevId, WithHeightAndId,
+};
 use common_failures::prelude::*;
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display},
+    ops::Range,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc, Mutex,
+        Arc, Condvar, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 fn retry<T>(mut f: impl FnMut() -> Result<T>) -> T {
@@ -31,45 +34,418 @@ fn retry<T>(mut f: impl FnMut() -> Result<T>) -> T {
     }
 }
 
-impl Rpc for bitcoincore_rpc::Client {
-    type Data = Box<bitcoin::Block>;
-    type Id = bitcoin::hash_types::BlockHash;
-    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 2000;
-    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 100;
+/// Wakes up prefetcher workers waiting for a new block
+///
+/// Workers poll the node every `RECOMMENDED_HEAD_RETRY_DELAY_MS` anyway,
+/// so a missed notification only delays things.
+#[derive(Clone, Default)]
+pub struct TipNotify {
+    inner: Arc<(Mutex<u64>, Condvar)>,
+}
 
-    fn get_block_count(&self) -> Result<BlockHeight> {
-        Ok(RpcApi::get_block_count(self)? as u32)
+impl TipNotify {
+    /// Signal that there might be a new block available
+    pub fn notify(&self) {
+        let (generation, condvar) = &*self.inner;
+        *generation.lock().expect("lock works") += 1;
+        condvar.notify_all();
     }
 
-    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
-        match self.get_block_hash(u64::from(height)) {
-            Err(e) => {
-                if e.to_string().contains("Block height out of range") {
-                    Ok(None)
-                } else {
-                    Err(e.into())
-                }
-            }
-            Ok(o) => Ok(Some(o)),
+    pub(crate) fn generation(&self) -> u64 {
+        *self.inner.0.lock().expect("lock works")
+    }
+
+    /// Wait until `notify` is called after `generation` was read, or `timeout`
+    pub(crate) fn wait(&self, generation: u64, timeout: Duration) {
+        let (lock, condvar) = &*self.inner;
+        let guard = lock.lock().expect("lock works");
+        let _ = condvar
+            .wait_timeout_while(guard, timeout, |current| *current == generation)
+            .expect("lock works");
+    }
+}
+
+/// Handle to stop a `Prefetcher` from another thread (eg. a signal handler)
+///
+/// After `shutdown`, the `Prefetcher` returns `None` instead of waiting
+/// for more blocks.
+#[derive(Clone)]
+pub struct PrefetcherShutdown {
+    shutdown: Arc<AtomicBool>,
+    tip_notify: TipNotify,
+}
+
+impl PrefetcherShutdown {
+    pub(crate) fn new(shutdown: Arc<AtomicBool>, tip_notify: TipNotify) -> Self {
+        Self {
+            shutdown,
+            tip_notify,
         }
     }
 
-    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
-        let block: Box<bitcoin::Block> = match self.get_by_id(hash) {
-            Err(e) => {
-                if e.to_string().contains("Block height out of range") {
-                    return Ok(None);
-                } else {
-                    return Err(e.into());
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.tip_notify.notify();
+    }
+}
+
+/// Default `ByteBudget` limit of a `Prefetcher`
+pub const DEFAULT_BYTE_BUDGET: usize = 512 * 1024 * 1024;
+
+/// Limit on the size of blocks fetched, but not returned by `Prefetcher` yet
+///
+/// Covers both the blocks waiting to be picked up from the workers, and
+/// the ones that arrived out of order. Over the limit, workers don't claim
+/// any new heights, so it can be exceeded only by the blocks the workers
+/// are already fetching.
+#[derive(Clone)]
+pub struct ByteBudget {
+    inner: Arc<(Mutex<ByteBudgetState>, Condvar)>,
+}
+
+struct ByteBudgetState {
+    limit: usize,
+    used: usize,
+}
+
+impl ByteBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new((
+                Mutex::new(ByteBudgetState { limit, used: 0 }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// Change the limit, also for workers already running
+    pub fn set_limit(&self, limit: usize) {
+        self.update(|state| state.limit = limit);
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.0.lock().expect("lock works").limit
+    }
+
+    /// Size of blocks currently held
+    pub fn used(&self) -> usize {
+        self.inner.0.lock().expect("lock works").used
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ByteBudgetState)) {
+        let (state, condvar) = &*self.inner;
+        f(&mut state.lock().expect("lock works"));
+        condvar.notify_all();
+    }
+
+    fn acquire(&self, size: usize) {
+        self.update(|state| state.used += size);
+    }
+
+    fn release(&self, size: usize) {
+        self.update(|state| state.used -= size);
+    }
+
+    fn release_all(&self) {
+        self.update(|state| state.used = 0);
+    }
+
+    /// Wake up everyone waiting in `wait_available`
+    fn notify(&self) {
+        self.update(|_| ());
+    }
+
+    /// Wait up to `timeout` for usage to go under the limit, or `stop`
+    ///
+    /// Returns `false` if it didn't go under.
+    fn wait_available(&self, timeout: Duration, stop: impl Fn() -> bool) -> bool {
+        let (state, condvar) = &*self.inner;
+        let guard = state.lock().expect("lock works");
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |state| state.limit <= state.used && !stop())
+            .expect("lock works");
+        guard.used < guard.limit
+    }
+}
+
+impl fmt::Display for ByteBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (state, _) = &*self.inner;
+        let state = state.lock().expect("lock works");
+        write!(
+            f,
+            "{}MB of {}MB",
+            state.used / 1024 / 1024,
+            state.limit / 1024 / 1024
+        )
+    }
+}
+
+/// How often `Concurrency` reconsiders the number of workers
+pub(crate) const CONCURRENCY_ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happened since `Concurrency` last adjusted the number of workers
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConcurrencyStats {
+    /// Time the stats cover
+    pub window: Duration,
+    /// Blocks fetched
+    pub fetched: usize,
+    /// Total time of the node calls that fetched them
+    pub fetch_time: Duration,
+    /// Node calls that failed
+    pub errors: usize,
+    /// All node calls made
+    pub calls: usize,
+    /// Some worker found no block at the height it wanted
+    pub tip_reached: bool,
+    /// Some worker waited for the `ByteBudget`
+    pub budget_exhausted: bool,
+    /// Time the `Prefetcher` user spent waiting for blocks
+    pub consumer_wait: Duration,
+}
+
+impl ConcurrencyStats {
+    fn latency(&self) -> Option<Duration> {
+        if self.fetched == 0 {
+            None
+        } else {
+            Some(self.fetch_time / self.fetched as u32)
+        }
+    }
+}
+
+/// Pick the number of workers for the next `CONCURRENCY_ADJUST_INTERVAL`
+///
+/// `best_latency` is the lowest per-block latency seen so far; it's allowed
+/// to drift up slowly, so a node that became slower for good doesn't keep
+/// the workers down forever.
+pub(crate) fn adjust_worker_num(
+    active: usize,
+    max: usize,
+    stats: &ConcurrencyStats,
+    best_latency: &mut Option<Duration>,
+) -> usize {
+    let latency = stats.latency();
+    if let Some(latency) = latency {
+        *best_latency = Some(match *best_latency {
+            Some(best) => latency.min(best + best / 16),
+            None => latency,
+        });
+    }
+
+    let new = if stats.tip_reached {
+        // one worker keeps up with new blocks just fine
+        1
+    } else if stats.errors * 10 > stats.calls {
+        active / 2
+    } else if stats.budget_exhausted || stats.consumer_wait < stats.window / 10 {
+        // more workers would only hold more blocks in memory
+        active - 1
+    } else if stats.fetch_time < stats.window * active as u32 / 2 {
+        // workers are not busy enough to tell
+        active
+    } else if latency.expect("fetched something") > best_latency.expect("set above") * 2 {
+        // node is saturated
+        active - 1
+    } else {
+        active + 1
+    };
+    new.max(1).min(max)
+}
+
+/// Number of workers a `Prefetcher` is fetching with
+///
+/// Adjusted every `CONCURRENCY_ADJUST_INTERVAL` (see `adjust_worker_num`):
+/// down to one worker at the chain tip, and up again while the user of
+/// the `Prefetcher` waits for blocks, for as long as the node keeps
+/// answering without errors and without slowing down. Fewer workers are
+/// used when the user can't keep up with the blocks anyway, which is the
+/// same signal `BottleCheck` reports.
+#[derive(Clone)]
+pub struct Concurrency {
+    inner: Arc<(Mutex<ConcurrencyState>, Condvar)>,
+}
+
+struct ConcurrencyState {
+    active: usize,
+    max: usize,
+    window_start: Instant,
+    stats: ConcurrencyStats,
+    best_latency: Option<Duration>,
+    consumer_waiting_since: Option<Instant>,
+}
+
+impl Concurrency {
+    pub(crate) fn new(active: usize, max: usize) -> Self {
+        Self {
+            inner: Arc::new((
+                Mutex::new(ConcurrencyState {
+                    active,
+                    max,
+                    window_start: Instant::now(),
+                    stats: default(),
+                    best_latency: None,
+                    consumer_waiting_since: None,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// Number of workers currently fetching
+    pub fn active(&self) -> usize {
+        self.inner.0.lock().expect("lock works").active
+    }
+
+    /// Maximum number of workers
+    pub fn max(&self) -> usize {
+        self.inner.0.lock().expect("lock works").max
+    }
+
+    fn record(&self, f: impl FnOnce(&mut ConcurrencyStats)) {
+        f(&mut self.inner.0.lock().expect("lock works").stats)
+    }
+
+    pub(crate) fn consumer_wait_start(&self) {
+        self.inner
+            .0
+            .lock()
+            .expect("lock works")
+            .consumer_waiting_since = Some(Instant::now());
+    }
+
+    pub(crate) fn consumer_wait_end(&self) {
+        let mut state = self.inner.0.lock().expect("lock works");
+        if let Some(since) = state.consumer_waiting_since.take() {
+            state.stats.consumer_wait += since.elapsed();
+        }
+    }
+
+    /// Adjust the number of workers, if it's time to
+    pub(crate) fn maybe_adjust(&self) {
+        let (state, condvar) = &*self.inner;
+        let mut guard = state.lock().expect("lock works");
+        let state = &mut *guard;
+        let now = Instant::now();
+        let window = now.duration_since(state.window_start);
+        if window < CONCURRENCY_ADJUST_INTERVAL {
+            return;
+        }
+        // only if it's still waiting; the rest of the wait goes to the next window
+        if let Some(since) = state.consumer_waiting_since.as_mut() {
+            state.stats.consumer_wait += now.duration_since(*since);
+            *since = now;
+        }
+        let ConcurrencyState {
+            active,
+            max,
+            stats,
+            best_latency,
+            ..
+        } = state;
+        stats.window = window;
+        let new = adjust_worker_num(*active, *max, stats, best_latency);
+        if new != *active {
+            debug!(
+                "Prefetcher: {} -> {} workers; {:?}, best latency {:?}",
+                active, new, stats, best_latency
+            );
+            *active = new;
+            condvar.notify_all();
+        }
+        state.stats = default();
+        state.window_start = now;
+    }
+
+    /// Wake up everyone waiting in `wait_active`
+    fn notify(&self) {
+        let (state, condvar) = &*self.inner;
+        let _guard = state.lock().expect("lock works");
+        condvar.notify_all();
+    }
+
+    /// Wait up to `timeout` for the worker number `index` to be active, or `stop`
+    ///
+    /// Returns `false` if it isn't active.
+    fn wait_active(&self, index: usize, timeout: Duration, stop: impl Fn() -> bool) -> bool {
+        let (state, condvar) = &*self.inner;
+        let guard = state.lock().expect("lock works");
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |state| state.active <= index && !stop())
+            .expect("lock works");
+        index < guard.active
+    }
+}
+
+impl fmt::Display for Concurrency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.inner.0.lock().expect("lock works");
+        write!(f, "{} of {} workers", state.active, state.max)
+    }
+}
+
+/// How many hashes of returned blocks `Prefetcher` keeps in memory
+///
+/// Deeper reorgs need a `HashLookup`.
+pub(crate) const PREV_HASHES_WINDOW: BlockHeight = 1000;
+
+/// Detect reorgs
+///
+/// Track previous hashes and detect if a block at `height` points
+/// to a different `prev_id` than we recorded. That means that the
+/// previous hash we've recorded was abandoned.
+pub(crate) fn track_reorgs<Id>(
+    prev_hashes: &mut BTreeMap<BlockHeight, Id>,
+    height: BlockHeight,
+    id: &Id,
+    prev_id: &Id,
+) -> bool
+where
+    Id: Eq + Display + Clone,
+{
+    if height > 0 {
+        if let Some(stored_prev_id) = prev_hashes.get(&(height - 1)) {
+            trace!(
+                "Reorg check: last_id {} =? current {} at {}H",
+                stored_prev_id,
+                prev_id,
+                height - 1
+            );
+            if stored_prev_id != prev_id {
+                return true;
+            }
+        } else {
+            let max_prev_hash = prev_hashes
+                .iter()
+                .next_back()
+                .expect("At least one element");
+            if height != *max_prev_hash.0 + 1 {
+                for (h, hash) in prev_hashes.iter() {
+                    debug!("prev_hash {}H -> {}", h, hash);
                 }
+                panic!(
+                    "No prev_hash for a new block {}H {}; max_prev_hash: {}H {}",
+                    height, id, max_prev_hash.0, max_prev_hash.1
+                );
             }
-            Ok(o) => Box::new(o),
-        };
-        let prev_id = block.header.prev_blockhash;
-        Ok(Some((block, prev_id)))
+        }
+    }
+    prev_hashes.insert(height, id.clone());
+    if height >= PREV_HASHES_WINDOW {
+        prev_hashes.remove(&(height - PREV_HASHES_WINDOW));
     }
+    assert!(prev_hashes.len() <= PREV_HASHES_WINDOW as usize);
+
+    false
 }
 
+/// Hash of a block already returned by `Prefetcher` at a given height
+///
+/// Used to find the fork point of reorgs deeper than what `Prefetcher`
+/// remembers, eg. by asking `IndexerStore::get_hash_by_height`.
+pub type HashLookup<Id> = Box<dyn FnMut(BlockHeight) -> Result<Option<Id>> + Send>;
+
 /// A block fetcher from a `Rpc`
 ///
 /// Implemented as an iterator that yields block events in order,
@@ -110,21 +486,39 @@ where
 
     cur_height: BlockHeight,
     prev_hashes: BTreeMap<BlockHeight, R::Id>,
+    hash_lookup: Option<HashLookup<R::Id>>,
+    /// Why no more blocks are returned, if not because of a shutdown
+    error: Option<Error>,
     workers_finish: Arc<AtomicBool>,
-    thread_num: usize,
+    shutdown: Arc<AtomicBool>,
+    tip_notify: TipNotify,
     rpc: Arc<R>,
-    end_of_fast_sync: BlockHeight,
+    concurrency: Concurrency,
+    byte_budget: ByteBudget,
 }
 
 impl<R> Prefetcher<R>
 where
     R: Rpc + 'static,
 {
+    /// Reorgs deeper than `PREV_HASHES_WINDOW` stop it; see `take_error`
     pub fn new(rpc: Arc<R>, last_block: Option<WithHeightAndId<R::Id>>) -> Result<Self> {
-        let thread_num = num_cpus::get() * 2;
+        Self::new_with_hash_lookup(rpc, last_block, None)
+    }
+
+    /// Like `new`, but able to handle reorgs of any depth
+    ///
+    /// `hash_lookup` should return hashes of blocks previously returned
+    /// (or indexed before `last_block`).
+    pub fn new_with_hash_lookup(
+        rpc: Arc<R>,
+        last_block: Option<WithHeightAndId<R::Id>>,
+        hash_lookup: Option<HashLookup<R::Id>>,
+    ) -> Result<Self> {
+        let max_workers = num_cpus::get() * 2;
         let workers_finish = Arc::new(AtomicBool::new(false));
 
-        let end_of_fast_sync = retry(|| rpc.get_block_count());
+        let node_height = retry(|| rpc.get_block_count());
         let mut prev_hashes = BTreeMap::default();
         let start = if let Some(h_and_hash) = last_block {
             let h = h_and_hash.height;
@@ -135,46 +529,96 @@ where
             info!("Starting block fetcher starting at genesis block");
             0
         };
+        // start with all the workers, unless they would all be waiting at the tip anyway
+        let far_from_tip =
+            start as usize + max_workers * R::RECOMMENDED_BATCH_SIZE.max(1) <= node_height as usize;
+        let concurrency = Concurrency::new(if far_from_tip { max_workers } else { 1 }, max_workers);
 
         let mut s = Self {
             rx: None,
             rpc,
             thread_joins: default(),
-            thread_num,
             cur_height: start,
             out_of_order_items: default(),
             workers_finish,
+            shutdown: default(),
+            tip_notify: default(),
             prev_hashes,
-            end_of_fast_sync,
+            hash_lookup,
+            error: None,
+            concurrency,
+            byte_budget: ByteBudget::new(DEFAULT_BYTE_BUDGET),
         };
 
         s.start_workers();
         Ok(s)
     }
 
+    /// Handle to wake up workers waiting at the chain tip
+    ///
+    /// Use it when the node is known to have a new block (eg. from ZMQ
+    /// notifications) to avoid waiting for the next poll.
+    pub fn tip_notify(&self) -> TipNotify {
+        self.tip_notify.clone()
+    }
+
+    /// Limit on the size of fetched blocks held in memory
+    ///
+    /// Use it to change the limit (`DEFAULT_BYTE_BUDGET` by default),
+    /// or to report current usage.
+    pub fn byte_budget(&self) -> ByteBudget {
+        self.byte_budget.clone()
+    }
+
+    /// Number of workers fetching blocks, adjusted automatically
+    pub fn concurrency(&self) -> Concurrency {
+        self.concurrency.clone()
+    }
+
+    /// Handle to make the prefetcher stop returning blocks
+    pub fn shutdown_handle(&self) -> PrefetcherShutdown {
+        PrefetcherShutdown::new(self.shutdown.clone(), self.tip_notify.clone())
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
     fn start_workers(&mut self) {
         self.workers_finish.store(false, Ordering::SeqCst);
 
-        let (tx, rx) = crossbeam_channel::bounded(self.thread_num * 64);
+        // bounded by `byte_budget` instead
+        let (tx, rx) = crossbeam_channel::unbounded();
         self.rx = Some(rx);
         let next_height = Arc::new(AtomicUsize::new(self.cur_height as usize));
+        let in_progress = Arc::new(Mutex::new(BTreeSet::new()));
         assert!(self.thread_joins.is_empty());
-        for _ in 0..self.thread_num {
+        // all of them, even if not all are active now
+        for index in 0..self.concurrency.max() {
             self.thread_joins.push({
                 std::thread::spawn({
                     let next_height = next_height.clone();
                     let rpc = self.rpc.clone();
                     let tx = tx.clone();
                     let workers_finish = self.workers_finish.clone();
-                    let in_progress = Arc::new(Mutex::new(default()));
+                    let shutdown = self.shutdown.clone();
+                    let tip_notify = self.tip_notify.clone();
+                    let byte_budget = self.byte_budget.clone();
+                    let concurrency = self.concurrency.clone();
+                    let in_progress = in_progress.clone();
                     move || {
                         // TODO: constructor
                         let mut worker = Worker {
                             next_height,
                             workers_finish,
+                            shutdown,
+                            tip_notify,
                             rpc,
                             tx,
                             in_progress,
+                            index,
+                            concurrency,
+                            byte_budget,
                         };
 
                         worker.run()
@@ -184,81 +628,129 @@ where
         }
     }
 
-    /// Detect reorgs
-    ///
-    /// Track previous hashes and detect if a given block points
-    /// to a different `prev_blockhash` than we recorded. That
-    /// means that the previous hash we've recorded was abandoned.
+    /// Detect reorgs; see `track_reorgs`
     fn track_reorgs(&mut self, block: &RpcBlockWithPrevId<R>) -> bool {
         debug_assert_eq!(block.block.height, self.cur_height);
-        if self.cur_height > 0 {
-            if let Some(stored_prev_id) = self.prev_hashes.get(&(self.cur_height - 1)) {
-                trace!(
-                    "Reorg check: last_id {} =? current {} at {}H",
-                    stored_prev_id,
-                    block.prev_block_id,
-                    self.cur_height - 1
-                );
-                if stored_prev_id != &block.prev_block_id {
-                    return true;
+        track_reorgs(
+            &mut self.prev_hashes,
+            self.cur_height,
+            &block.block.id,
+            &block.prev_block_id,
+        )
+    }
+
+    /// Hash of the block returned at `height`, if still known
+    fn get_known_hash(&mut self, height: BlockHeight) -> Option<R::Id> {
+        if let Some(id) = self.prev_hashes.get(&height) {
+            return Some(id.clone());
+        }
+        let lookup = self.hash_lookup.as_mut()?;
+        retry(|| lookup(height))
+    }
+
+    /// Does the node's chain contain the block we returned at `height`
+    ///
+    /// Fails if we don't know what we returned there.
+    fn is_in_node_chain(&mut self, height: BlockHeight) -> Result<bool> {
+        let known = match self.get_known_hash(height) {
+            Some(known) => known,
+            None => bail!(
+                "Reorg deeper than the known blocks: can't tell if {}H is the fork point",
+                height
+            ),
+        };
+        let rpc = self.rpc.clone();
+        Ok(retry(|| rpc.get_block_id_by_height(height)) == Some(known))
+    }
+
+    /// Find the highest height below `cur_height` where the node's chain
+    /// matches the blocks we returned
+    ///
+    /// Steps back exponentially, then bisects. `None` if nothing matches,
+    /// not even genesis. Fails if it gets to a height where it doesn't know
+    /// what it returned, ie. below the remembered blocks, without a `HashLookup`.
+    fn find_fork_height(&mut self) -> Result<Option<BlockHeight>> {
+        // the block at `cur_height` didn't connect
+        let mut mismatch = self.cur_height - 1;
+        let mut step = 1;
+        let mut matching = loop {
+            if mismatch == 0 {
+                return Ok(None);
+            }
+            let mut height = mismatch.saturating_sub(step);
+            // don't step over the lowest remembered block
+            if let Some(&lowest) = self.prev_hashes.keys().next() {
+                if lowest < mismatch {
+                    height = height.max(lowest);
                 }
-            } else if self.cur_height
-                < *self
-                    .prev_hashes
-                    .iter()
-                    .next()
-                    .expect("At least one element")
-                    .0
-            {
-                panic!(
-                    "Prefetcher detected a reorg beyond acceptable depth. No hash for {}H",
-                    self.cur_height
-                );
+            }
+            if self.is_in_node_chain(height)? {
+                break height;
+            }
+            mismatch = height;
+            step *= 2;
+        };
+        while matching + 1 < mismatch {
+            let height = matching + (mismatch - matching) / 2;
+            if self.is_in_node_chain(height)? {
+                matching = height;
             } else {
-                let max_prev_hash = self
-                    .prev_hashes
-                    .iter()
-                    .next_back()
-                    .expect("At least one element");
-                if self.cur_height != *max_prev_hash.0 + 1 {
-                    for (h, hash) in self.prev_hashes.iter() {
-                        debug!("prev_hash {}H -> {}", h, hash);
-                    }
-                    panic!(
-                        "No prev_hash for a new block {}H {}; max_prev_hash: {}H {}",
-                        self.cur_height, block.block.id, max_prev_hash.0, max_prev_hash.1
-                    );
-                }
+                mismatch = height;
             }
         }
-        self.prev_hashes
-            .insert(block.block.height, block.block.id.clone());
-        // this is how big reorgs we're going to detect
-        let window_size = 1000;
-        if self.cur_height >= window_size {
-            self.prev_hashes.remove(&(self.cur_height - window_size));
-        }
-        assert!(self.prev_hashes.len() <= window_size as usize);
-
-        false
+        Ok(Some(matching))
     }
 
-    /// Handle condition detected by `detected_reorg`
+    /// Handle condition detected by `track_reorgs`
     ///
-    /// Basically, stop all workers (discarding their work), adjust height and
-    /// start workers again.
+    /// Basically, stop all workers (discarding their work), find the fork
+    /// point, and start workers again right above it.
     ///
     /// This doesn't have to be blazing fast, so it isn't.
-    fn reset_on_reorg(&mut self) {
-        debug!(
-            "Resetting on reorg from {}H to {}H",
-            self.cur_height,
-            self.cur_height - 1
-        );
+    ///
+    /// If the fork point can't be found, the workers stay stopped.
+    fn reset_on_reorg(&mut self) -> Result<()> {
         self.stop_workers();
         assert!(self.cur_height > 0);
-        self.cur_height -= 1;
+        let new_height = if let Some(fork_height) = self.find_fork_height()? {
+            let fork_id = self
+                .get_known_hash(fork_height)
+                .expect("fork point hash is known");
+            self.prev_hashes.split_off(&fork_height);
+            self.prev_hashes.insert(fork_height, fork_id);
+            fork_height + 1
+        } else {
+            self.prev_hashes.clear();
+            0
+        };
+        info!(
+            "Reorg detected at {}H; continuing from {}H",
+            self.cur_height, new_height
+        );
+        self.cur_height = new_height;
         self.start_workers();
+        Ok(())
+    }
+
+    /// Handle a reorg; `false` if no more blocks can be returned
+    fn handle_reorg(&mut self) -> bool {
+        match self.reset_on_reorg() {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Stopping block fetching: {}", e);
+                self.error = Some(e);
+                self.shutdown.store(true, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    /// Why the prefetcher stopped returning blocks, unless it was shut down
+    ///
+    /// Without a `HashLookup`, the fork point of a reorg deeper than
+    /// `PREV_HASHES_WINDOW` can't be found.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
     }
 }
 
@@ -268,17 +760,19 @@ where
 {
     fn stop_workers(&mut self) {
         self.workers_finish.store(true, Ordering::SeqCst);
+        self.tip_notify.notify();
+        self.concurrency.notify();
+        self.byte_budget.notify();
 
-        while let Ok(_) = self
-            .rx
-            .as_ref()
-            .expect("start_workers called before stop_workers")
-            .recv()
-        {}
+        // already stopped, if the fork point of a reorg wasn't found
+        if let Some(rx) = self.rx.take() {
+            while rx.recv().is_ok() {}
+        }
 
-        self.rx = None;
         self.thread_joins.drain(..).map(|j| j.join()).for_each(drop);
         self.out_of_order_items.clear();
+        // everything fetched is gone now
+        self.byte_budget.release_all();
     }
 }
 
@@ -288,23 +782,19 @@ where
 {
     type Item = RpcBlock<R>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.end_of_fast_sync == self.cur_height {
-            debug!(
-                "Prefetcher: end of fast sync at {}H; switching to one worker",
-                self.cur_height
-            );
-            self.stop_workers();
-            self.thread_num = 1;
-            self.start_workers();
+        if self.is_shut_down() {
+            return None;
         }
-
         'retry_on_reorg: loop {
             if let Some(item) = self.out_of_order_items.remove(&self.cur_height) {
                 if self.track_reorgs(&item) {
-                    self.reset_on_reorg();
+                    if !self.handle_reorg() {
+                        return None;
+                    }
                     continue 'retry_on_reorg;
                 }
                 self.cur_height += 1;
+                self.byte_budget.release(item.data_size);
                 return Some(item.block);
             }
 
@@ -313,22 +803,32 @@ where
                     "Waiting for the block from the workers at: {}H",
                     self.cur_height
                 );
-                let item = self
-                    .rx
-                    .as_ref()
-                    .expect("rx available")
-                    .recv()
-                    .expect("Workers shouldn't disconnect");
+                self.concurrency.consumer_wait_start();
+                let item = self.rx.as_ref().expect("rx available").recv();
+                self.concurrency.consumer_wait_end();
+                let item = match item {
+                    Ok(item) => item,
+                    Err(_) => {
+                        assert!(self.is_shut_down(), "Workers shouldn't disconnect");
+                        return None;
+                    }
+                };
+                if self.is_shut_down() {
+                    return None;
+                }
                 trace!(
                     "Got the block from the workers from: {}H",
                     item.block.height
                 );
                 if item.block.height == self.cur_height {
                     if self.track_reorgs(&item) {
-                        self.reset_on_reorg();
+                        if !self.handle_reorg() {
+                            return None;
+                        }
                         continue 'retry_on_reorg;
                     }
                     self.cur_height += 1;
+                    self.byte_budget.release(item.data_size);
                     return Some(item.block);
                 } else {
                     assert!(item.block.height > self.cur_height);
@@ -356,60 +856,141 @@ where
     rpc: Arc<R>,
     next_height: Arc<AtomicUsize>,
     workers_finish: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+    tip_notify: TipNotify,
     tx: crossbeam_channel::Sender<RpcBlockWithPrevId<R>>,
     in_progress: Arc<Mutex<BTreeSet<BlockHeight>>>,
+    /// Works only while `concurrency` has more active workers than that
+    index: usize,
+    concurrency: Concurrency,
+    byte_budget: ByteBudget,
 }
 
 impl<R> Worker<R>
 where
     R: Rpc,
 {
+    fn is_finished(&self) -> bool {
+        self.workers_finish.load(Ordering::SeqCst) || self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Wait until this worker is active, and there's room for more blocks
+    ///
+    /// Returns `false` if the worker should finish instead.
+    fn wait_for_turn(&self) -> bool {
+        let mut paused = false;
+        loop {
+            if self.is_finished() {
+                return false;
+            }
+            self.concurrency.maybe_adjust();
+            let timeout = Duration::from_millis(100);
+            if !self
+                .concurrency
+                .wait_active(self.index, timeout, || self.is_finished())
+            {
+                continue;
+            }
+            if self
+                .byte_budget
+                .wait_available(timeout, || self.is_finished())
+            {
+                if paused {
+                    debug!("Worker resumed; {} buffered", self.byte_budget);
+                }
+                return true;
+            }
+            self.concurrency
+                .record(|stats| stats.budget_exhausted = true);
+            if !paused {
+                debug!("Worker paused; {} buffered", self.byte_budget);
+                paused = true;
+            }
+        }
+    }
+
     fn run(&mut self) {
         loop {
-            let height = self.get_height_to_fetch();
+            if !self.wait_for_turn() {
+                return;
+            }
+            let mut heights = self.get_heights_to_fetch();
 
             let mut retry_count = 0;
             'retry: loop {
-                if self.workers_finish.load(Ordering::SeqCst) {
+                if self.is_finished() {
                     return;
                 }
+                self.concurrency.maybe_adjust();
 
-                match self.get_block_by_height(height) {
+                let tip_generation = self.tip_notify.generation();
+                let start = Instant::now();
+                let res = self.get_blocks_by_heights(heights.clone());
+                let fetch_time = start.elapsed();
+                self.concurrency.record(|stats| {
+                    stats.calls += 1;
+                    match &res {
+                        Err(_) => stats.errors += 1,
+                        Ok(items) => {
+                            stats.fetched += items.len();
+                            stats.fetch_time += fetch_time;
+                            stats.tip_reached |= items.len() < heights.len();
+                        }
+                    }
+                });
+                match res {
                     Err(e) => {
                         trace!("Error from the node: {}", e);
-                        let ahead_minimum = height
+                        // let the worker fetching the lowest height retry first
+                        let ahead_minimum = heights.start
                             - self
                                 .get_min_height_in_progress()
                                 .expect("at least current height");
                         std::thread::sleep(Duration::from_millis(
-                            (1 + R::RECOMMENDED_ERROR_RETRY_DELAY_MS) * u64::from(ahead_minimum),
+                            self.rpc.error_retry_delay_ms() * (1 + u64::from(ahead_minimum)),
                         ));
                         retry_count += 1;
                         if retry_count % 10 == 0 {
-                            debug!("Worker retrying rpc error {} at {}H", e, height);
+                            debug!("Worker retrying rpc error {} at {}H", e, heights.start);
                         }
                     }
-                    Ok(None) => {
-                        let sleep_ms = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
-                        std::thread::sleep(Duration::from_millis(sleep_ms));
+                    Ok(items) if items.is_empty() => {
+                        let sleep_ms = self.rpc.head_retry_delay_ms();
+                        self.tip_notify
+                            .wait(tip_generation, Duration::from_millis(sleep_ms));
                     }
-                    Ok(Some(item)) => {
-                        self.tx.send(item).expect("Send must not fail");
-                        self.mark_height_fetched(height);
-                        break 'retry;
+                    Ok(items) => {
+                        // the rest of the range (if any) is not available yet
+                        for item in items {
+                            let height = item.block.height;
+                            self.byte_budget.acquire(item.data_size);
+                            self.tx.send(item).expect("Send must not fail");
+                            self.mark_height_fetched(height);
+                            heights.start += 1;
+                        }
+                        if heights.is_empty() {
+                            break 'retry;
+                        }
                     }
                 }
             }
         }
     }
 
-    fn get_height_to_fetch(&self) -> BlockHeight {
-        let height = self.next_height.fetch_add(1, Ordering::SeqCst) as BlockHeight;
+    fn get_heights_to_fetch(&self) -> Range<BlockHeight> {
+        // claiming ranges of blocks makes sense only when far from the tip
+        let batch_size = if self.concurrency.active() > 1 {
+            R::RECOMMENDED_BATCH_SIZE.max(1)
+        } else {
+            1
+        };
+        let start = self.next_height.fetch_add(batch_size, Ordering::SeqCst) as BlockHeight;
+        let heights = start..start + batch_size as BlockHeight;
         self.in_progress
             .lock()
             .expect("unlock works")
-            .insert(height);
-        height
+            .extend(heights.clone());
+        heights
     }
 
     fn get_min_height_in_progress(&self) -> Option<BlockHeight> {
@@ -425,24 +1006,27 @@ where
             .remove(&height));
     }
 
-    fn get_block_by_height(
+    /// Fetch consecutive blocks from `heights`, stopping at the first missing one
+    fn get_blocks_by_heights(
         &mut self,
-        height: BlockHeight,
-    ) -> Result<Option<RpcBlockWithPrevId<R>>> {
-        if let Some(id) = self.rpc.get_block_id_by_height(height)? {
-            Ok(self
-                .rpc
-                .get_block_by_id(&id)?
-                .map(|block| RpcBlockWithPrevId {
-                    block: WithHeightAndId {
-                        height,
-                        id,
-                        data: block.0,
-                    },
-                    prev_block_id: block.1,
-                }))
-        } else {
-            Ok(None)
+        heights: Range<BlockHeight>,
+    ) -> Result<Vec<RpcBlockWithPrevId<R>>> {
+        let ids = self
+            .rpc
+            .get_block_ids_by_heights(heights.start, heights.len())?;
+        if ids.is_empty() {
+            return Ok(vec![]);
         }
+        let blocks = self.rpc.get_blocks_by_ids(&ids)?;
+        Ok(heights
+            .zip(ids.into_iter().zip(blocks))
+            .map_while(|(height, (id, block))| {
+                block.map(|(data, prev_block_id)| RpcBlockWithPrevId {
+                    data_size: data.data_size(),
+                    block: WithHeightAndId { height, id, data },
+                    prev_block_id,
+                })
+            })
+            .collect())
     }
 }
@@ -0,0 +1,620 @@
+//! Recording node answers to a file and replaying them without a node
+//!
+//! `RecordingRpc` wraps any block source and writes its answers to
+//! `get_block_count`, `get_block_id_by_height` and `get_block_by_id` to a
+//! file. `ReplayRpc` reads such a file and serves the same answers back,
+//! so whole indexing runs against real chain data can be repeated offline.
+//!
+//! The file starts with a magic, format version and the network magic,
+//! followed by records: a tag byte and a little-endian payload. Answers that
+//! don't tell anything new are skipped, and every block is stored once,
+//! consensus-encoded.
+//!
+//! The node's chain changes while it's being recorded, so the recording is
+//! split into steps: a new one starts whenever an answer doesn't fit the
+//! chain seen so far (the tip moved, or a different block id showed up at a
+//! known height), or at an explicit step marker. Markers are written by
+//! `Recorder::record_chain`, which is how reorgs can be scripted. The replay
+//! moves to the next step when asked for a height above the current tip,
+//! unless the client is still to fetch the tip block.
+use super::headers::HeaderRpc;
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    hash_types::BlockHash,
+    Block, BlockHeader, Network,
+};
+use log::{debug, warn};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryInto,
+    fs,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+const FILE_MAGIC: &[u8; 4] = b"BIRR";
+const FILE_VERSION: u8 = 1;
+/// Magic, version and network magic
+const FILE_HEADER_LEN: u64 = 9;
+const BLOCK_HEADER_LEN: usize = 80;
+
+const TAG_COUNT: u8 = 0;
+const TAG_ID: u8 = 1;
+const TAG_BLOCK: u8 = 2;
+const TAG_STEP: u8 = 3;
+
+/// How an answer changed the chain seen so far
+struct Change {
+    /// The answer doesn't fit the current step
+    new_step: bool,
+    /// Heights with no known block id anymore
+    removed: Vec<BlockHeight>,
+}
+
+/// Node's best chain, as seen through its answers
+#[derive(Default)]
+struct SeenChain {
+    tip: Option<BlockHeight>,
+    ids: BTreeMap<BlockHeight, BlockHash>,
+    /// Current step was started with a marker, so everything up to the next
+    /// marker belongs to it
+    scripted: bool,
+}
+
+impl SeenChain {
+    /// Account for `count` returned by `get_block_count`
+    ///
+    /// `None` if it's nothing new.
+    fn see_count(&mut self, count: BlockHeight) -> Option<Change> {
+        if self.tip == Some(count) {
+            return None;
+        }
+        let new_step = self.tip.is_some() && !self.scripted;
+        self.tip = Some(count);
+        Some(Change {
+            new_step,
+            removed: self.remove_from(count + 1),
+        })
+    }
+
+    /// Account for `id` returned by `get_block_id_by_height`
+    ///
+    /// `None` if it's nothing new.
+    fn see_id(&mut self, height: BlockHeight, id: BlockHash) -> Option<Change> {
+        let change = match self.ids.get(&height) {
+            Some(known) if *known == id => return None,
+            Some(_) => {
+                let removed = self.remove_from(height);
+                self.tip = Some(height);
+                Change {
+                    new_step: !self.scripted,
+                    removed,
+                }
+            }
+            None => {
+                let new_step = self.tip.is_some_and(|tip| tip < height) && !self.scripted;
+                self.tip = Some(self.tip.map_or(height, |tip| tip.max(height)));
+                Change {
+                    new_step,
+                    removed: vec![],
+                }
+            }
+        };
+        self.ids.insert(height, id);
+        Some(change)
+    }
+
+    /// Account for a step marker
+    fn see_step(&mut self) -> Change {
+        self.scripted = true;
+        Change {
+            new_step: self.tip.is_some(),
+            removed: vec![],
+        }
+    }
+
+    fn remove_from(&mut self, height: BlockHeight) -> Vec<BlockHeight> {
+        self.ids.split_off(&height).into_keys().collect()
+    }
+}
+
+/// Writer of recording files
+pub struct Recorder {
+    file: BufWriter<fs::File>,
+    chain: SeenChain,
+    blocks: HashSet<BlockHash>,
+}
+
+impl Recorder {
+    /// Start a recording of a node on `network`, replacing any existing file at `path`
+    pub fn create(path: &Path, network: Network) -> Result<Self> {
+        let mut file = match fs::File::create(path) {
+            Ok(file) => BufWriter::new(file),
+            Err(e) => bail!("Couldn't create {}: {}", path.display(), e),
+        };
+        file.write_all(FILE_MAGIC)?;
+        file.write_all(&[FILE_VERSION])?;
+        file.write_all(&network.magic().to_le_bytes())?;
+        Ok(Self {
+            file,
+            chain: default(),
+            blocks: default(),
+        })
+    }
+
+    pub fn record_count(&mut self, count: BlockHeight) -> Result<()> {
+        if self.chain.see_count(count).is_some() {
+            self.file.write_all(&[TAG_COUNT])?;
+            self.file.write_all(&count.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn record_id(&mut self, height: BlockHeight, id: &BlockHash) -> Result<()> {
+        if self.chain.see_id(height, *id).is_some() {
+            self.file.write_all(&[TAG_ID])?;
+            self.file.write_all(&height.to_le_bytes())?;
+            self.file.write_all(&serialize(id))?;
+        }
+        Ok(())
+    }
+
+    pub fn record_block(&mut self, block: &Block) -> Result<()> {
+        if self.blocks.insert(block.block_hash()) {
+            let bytes = serialize(block);
+            self.file.write_all(&[TAG_BLOCK])?;
+            self.file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            self.file.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Start a new step
+    ///
+    /// Everything recorded until the next one is a single state of the
+    /// node's chain, which the replay switches to at once.
+    pub fn record_step(&mut self) -> Result<()> {
+        self.chain.see_step();
+        self.file.write_all(&[TAG_STEP])?;
+        Ok(())
+    }
+
+    /// Script the node switching to `chain`, starting at `start` height
+    ///
+    /// Blocks below `start` stay as they were. Recording a chain that forks
+    /// off the previous one scripts a reorg.
+    pub fn record_chain(&mut self, start: BlockHeight, chain: &[Block]) -> Result<()> {
+        assert!(!chain.is_empty());
+        self.record_step()?;
+        for (height, block) in (start..).zip(chain) {
+            self.record_id(height, &block.block_hash())?;
+            self.record_block(block)?;
+        }
+        self.record_count(start + chain.len() as BlockHeight - 1)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// `Rpc` wrapper recording all the answers of the wrapped one
+pub struct RecordingRpc<R> {
+    inner: R,
+    recorder: Mutex<Recorder>,
+}
+
+impl<R> RecordingRpc<R> {
+    /// Record answers of `inner`, a node on `network`, to a new file at `path`
+    pub fn new(inner: R, network: Network, path: &Path) -> Result<Self> {
+        Ok(Self {
+            inner,
+            recorder: Mutex::new(Recorder::create(path, network)?),
+        })
+    }
+
+    /// Record and flush, so the recording is complete even if the process is killed
+    fn record(&self, f: impl FnOnce(&mut Recorder) -> Result<()>) -> Result<()> {
+        let mut recorder = self.recorder.lock().expect("lock works");
+        f(&mut recorder)?;
+        recorder.flush()
+    }
+}
+
+impl<R> Rpc for RecordingRpc<R>
+where
+    R: Rpc<Id = BlockHash, Data = Box<Block>>,
+{
+    type Data = Box<Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = R::RECOMMENDED_HEAD_RETRY_DELAY_MS;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = R::RECOMMENDED_ERROR_RETRY_DELAY_MS;
+
+    fn head_retry_delay_ms(&self) -> u64 {
+        self.inner.head_retry_delay_ms()
+    }
+
+    fn error_retry_delay_ms(&self) -> u64 {
+        self.inner.error_retry_delay_ms()
+    }
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        let count = self.inner.get_block_count()?;
+        self.record(|recorder| recorder.record_count(count))?;
+        Ok(count)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        let id = self.inner.get_block_id_by_height(height)?;
+        if let Some(id) = &id {
+            self.record(|recorder| recorder.record_id(height, id))?;
+        }
+        Ok(id)
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let block = self.inner.get_block_by_id(hash)?;
+        if let Some((block, _)) = &block {
+            self.record(|recorder| recorder.record_block(block))?;
+        }
+        Ok(block)
+    }
+
+    const RECOMMENDED_BATCH_SIZE: usize = R::RECOMMENDED_BATCH_SIZE;
+
+    fn get_block_ids_by_heights(&self, start: BlockHeight, count: usize) -> Result<Vec<Self::Id>> {
+        let ids = self.inner.get_block_ids_by_heights(start, count)?;
+        self.record(|recorder| {
+            for (height, id) in (start..).zip(&ids) {
+                recorder.record_id(height, id)?;
+            }
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    fn get_blocks_by_ids(&self, ids: &[Self::Id]) -> Result<Vec<Option<(Self::Data, Self::Id)>>> {
+        let blocks = self.inner.get_blocks_by_ids(ids)?;
+        self.record(|recorder| {
+            for (block, _) in blocks.iter().flatten() {
+                recorder.record_block(block)?;
+            }
+            Ok(())
+        })?;
+        Ok(blocks)
+    }
+}
+
+enum Record {
+    Count(BlockHeight),
+    Id(BlockHeight, BlockHash),
+    Block(BlockPos),
+    Step,
+}
+
+/// A recorded block in the file
+struct BlockPos {
+    header: BlockHeader,
+    offset: u64,
+    len: u32,
+}
+
+/// Read the rest of a record with a given `tag`, starting at `offset`
+///
+/// Returns the record along with the length read.
+fn read_record(
+    reader: &mut BufReader<fs::File>,
+    tag: u8,
+    offset: u64,
+    file_len: u64,
+) -> Result<(Record, u64)> {
+    let mut height = [0u8; 4];
+    Ok(match tag {
+        TAG_COUNT => {
+            reader.read_exact(&mut height)?;
+            (Record::Count(u32::from_le_bytes(height)), 4)
+        }
+        TAG_ID => {
+            let mut id = [0u8; 32];
+            reader.read_exact(&mut height)?;
+            reader.read_exact(&mut id)?;
+            (
+                Record::Id(u32::from_le_bytes(height), deserialize(&id)?),
+                36,
+            )
+        }
+        TAG_BLOCK => {
+            let mut len = [0u8; 4];
+            let mut header = [0u8; BLOCK_HEADER_LEN];
+            reader.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len);
+            if offset + 4 + u64::from(len) > file_len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            reader.read_exact(&mut header)?;
+            reader.seek_relative(i64::from(len) - BLOCK_HEADER_LEN as i64)?;
+            let pos = BlockPos {
+                header: deserialize(&header)?,
+                offset: offset + 4,
+                len,
+            };
+            (Record::Block(pos), 4 + u64::from(len))
+        }
+        TAG_STEP => (Record::Step, 0),
+        tag => bail!("Unknown record tag {} at offset {}", tag, offset),
+    })
+}
+
+/// Replay progress
+struct ReplayState {
+    step: usize,
+    /// Block ids of the current step that differ from the recorded ones,
+    /// so they link to the tip
+    resolved: HashMap<BlockHeight, BlockHash>,
+    /// Last block id returned at each height
+    handed: HashMap<BlockHeight, BlockHash>,
+    /// Blocks returned so far
+    served: HashSet<BlockHash>,
+}
+
+/// `Rpc` serving node answers from a recording
+pub struct ReplayRpc {
+    network: Network,
+    /// Tip height in every step
+    tips: Vec<BlockHeight>,
+    /// Recorded block id changes at every height, as `(step, id)`;
+    /// `None` when the height got removed from the chain
+    ids: HashMap<BlockHeight, Vec<(usize, Option<BlockHash>)>>,
+    blocks: HashMap<BlockHash, BlockPos>,
+    file: Mutex<fs::File>,
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayRpc {
+    /// Open a recording at `path`
+    ///
+    /// A truncated last record (eg. after the recording process got killed) is ignored.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => bail!("Couldn't open {}: {}", path.display(), e),
+        };
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file.try_clone()?);
+
+        let mut header = [0u8; FILE_HEADER_LEN as usize];
+        if reader.read_exact(&mut header).is_err() || &header[..4] != FILE_MAGIC {
+            bail!("{} is not an RPC recording", path.display());
+        }
+        if header[4] != FILE_VERSION {
+            bail!("Unsupported RPC recording version {}", header[4]);
+        }
+        let magic = u32::from_le_bytes(header[5..].try_into().expect("4 bytes"));
+        let network = match Network::from_magic(magic) {
+            Some(network) => network,
+            None => bail!("Unknown network magic {:#x} in {}", magic, path.display()),
+        };
+
+        let mut chain = SeenChain::default();
+        let mut tips: Vec<BlockHeight> = vec![];
+        let mut ids: HashMap<_, Vec<_>> = HashMap::new();
+        let mut blocks = HashMap::new();
+        let mut offset = FILE_HEADER_LEN;
+        loop {
+            let mut tag = [0u8; 1];
+            if reader.read(&mut tag)? == 0 {
+                break;
+            }
+            let (record, len) = match read_record(&mut reader, tag[0], offset + 1, file_len) {
+                Ok(record) => record,
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof) =>
+                {
+                    warn!("Ignoring truncated record at the end of {}", path.display());
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            offset += 1 + len;
+
+            let (change, id) = match record {
+                Record::Count(count) => (chain.see_count(count), None),
+                Record::Id(height, id) => (chain.see_id(height, id), Some((height, id))),
+                Record::Step => (Some(chain.see_step()), None),
+                Record::Block(pos) => {
+                    blocks.insert(pos.header.block_hash(), pos);
+                    continue;
+                }
+            };
+            if let Some(change) = change {
+                if let Some(tip) = chain.tip {
+                    match tips.last_mut() {
+                        Some(last) if !change.new_step => *last = tip,
+                        _ => tips.push(tip),
+                    }
+                }
+                let step = tips.len().saturating_sub(1);
+                for height in change.removed {
+                    ids.entry(height).or_default().push((step, None));
+                }
+                if let Some((height, id)) = id {
+                    ids.entry(height).or_default().push((step, Some(id)));
+                }
+            }
+        }
+        if tips.is_empty() {
+            bail!("Nothing recorded in {}", path.display());
+        }
+        debug!(
+            "Loaded {} steps and {} blocks from {}",
+            tips.len(),
+            blocks.len(),
+            path.display()
+        );
+
+        let mut replay = Self {
+            network,
+            tips,
+            ids,
+            blocks,
+            file: Mutex::new(file),
+            state: Mutex::new(ReplayState {
+                step: 0,
+                resolved: default(),
+                handed: default(),
+                served: default(),
+            }),
+        };
+        let resolved = replay.resolve(0);
+        replay.state.get_mut().expect("lock works").resolved = resolved;
+        Ok(replay)
+    }
+
+    /// Network the recorded node was on
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Is the replay at its last step, with the tip block already returned
+    pub fn is_finished(&self) -> bool {
+        let state = self.lock_state();
+        state.step + 1 == self.tips.len()
+            && match self.id_at(&state, self.tips[state.step]) {
+                Some(id) => state.served.contains(&id),
+                None => true,
+            }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.lock().expect("lock works")
+    }
+
+    fn recorded_id(&self, height: BlockHeight, step: usize) -> Option<BlockHash> {
+        self.ids
+            .get(&height)?
+            .iter()
+            .rev()
+            .find(|(changed_in, _)| *changed_in <= step)?
+            .1
+    }
+
+    fn id_at(&self, state: &ReplayState, height: BlockHeight) -> Option<BlockHash> {
+        state
+            .resolved
+            .get(&height)
+            .cloned()
+            .or_else(|| self.recorded_id(height, state.step))
+    }
+
+    /// Block ids of `step` that link to its tip, where they differ from the recorded ones
+    ///
+    /// Answers recorded in one step can come from before and after the node
+    /// switched to a new chain. Following `prev` links of the recorded
+    /// blocks keeps the chain served in a step consistent.
+    fn resolve(&self, step: usize) -> HashMap<BlockHeight, BlockHash> {
+        let mut resolved = HashMap::new();
+        let mut height = self.tips[step];
+        let mut id = match self.recorded_id(height, step) {
+            Some(id) => id,
+            None => return resolved,
+        };
+        while let Some(pos) = self.blocks.get(&id) {
+            if height == 0 {
+                break;
+            }
+            height -= 1;
+            id = pos.header.prev_blockhash;
+            if self.recorded_id(height, step) == Some(id) {
+                break;
+            }
+            resolved.insert(height, id);
+        }
+        resolved
+    }
+
+    /// Can the replay move past the current step
+    ///
+    /// Not while the client is still to fetch the tip block it was given
+    /// the id of.
+    fn can_advance(&self, state: &ReplayState) -> bool {
+        if state.step + 1 == self.tips.len() {
+            return false;
+        }
+        let tip = self.tips[state.step];
+        match self.id_at(state, tip) {
+            Some(id) => state.handed.get(&tip) != Some(&id) || state.served.contains(&id),
+            None => true,
+        }
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut file = self.file.lock().expect("lock works");
+        let mut buf = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl Rpc for ReplayRpc {
+    type Data = Box<Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 10;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 10;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.tips[self.lock_state().step])
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        let mut state = self.lock_state();
+        while height > self.tips[state.step] {
+            if !self.can_advance(&state) {
+                return Ok(None);
+            }
+            state.step += 1;
+            state.resolved = self.resolve(state.step);
+            debug!(
+                "Replay at step {}, tip {}H",
+                state.step, self.tips[state.step]
+            );
+        }
+        match self.id_at(&state, height) {
+            Some(id) => {
+                state.handed.insert(height, id);
+                Ok(Some(id))
+            }
+            None => bail!(
+                "No block id at {}H recorded (replay step {})",
+                height,
+                state.step
+            ),
+        }
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let pos = match self.blocks.get(hash) {
+            Some(pos) => pos,
+            None => bail!("Block {} not recorded", hash),
+        };
+        let block: Block = deserialize(&self.read_at(pos.offset, pos.len as usize)?)?;
+        self.lock_state().served.insert(*hash);
+        Ok(Some((Box::new(block), pos.header.prev_blockhash)))
+    }
+}
+
+impl HeaderRpc for ReplayRpc {
+    fn get_headers_by_height(&self, start: BlockHeight, count: usize) -> Result<Vec<BlockHeader>> {
+        self.get_block_ids_by_heights(start, count)?
+            .iter()
+            .map(|id| match self.blocks.get(id) {
+                Some(pos) => Ok(pos.header),
+                None => bail!("Block {} not recorded", id),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,146 @@
+//! Block source using Bitcoin Core's REST interface
+//!
+//! Needs `rest=1` in `bitcoin.conf`. The REST interface requires no
+//! authentication and returns blocks as raw bytes, so there's no
+//! JSON/hex encoding and decoding on the hot path.
+use super::headers::HeaderRpc;
+use crate::{prelude::*, util, BlockHeight, Rpc};
+use bitcoin::{
+    consensus::{deserialize, Decodable},
+    hash_types::BlockHash,
+    util::uint::Uint256,
+    BlockHeader,
+};
+use std::{io::Read, time::Duration};
+
+/// `Rpc` implementation using Core's `/rest/` endpoints
+pub struct RestRpc {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl RestRpc {
+    /// Create a new client; `url` is the node's RPC address (eg. `http://localhost:8332`)
+    pub fn new(url: &str) -> Self {
+        Self::with_timeouts(
+            url,
+            crate::DEFAULT_CONNECT_TIMEOUT,
+            crate::DEFAULT_READ_TIMEOUT,
+        )
+    }
+
+    pub fn with_timeouts(url: &str, connect_timeout: Duration, read_timeout: Duration) -> Self {
+        Self {
+            base_url: url.trim_end_matches('/').to_owned(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(connect_timeout)
+                .timeout_read(read_timeout)
+                .build(),
+        }
+    }
+
+    /// Get a `path` under `/rest/`, returning `None` on `404 Not Found`
+    fn get(&self, path: &str) -> Result<Option<ureq::Response>> {
+        match self
+            .agent
+            .get(&format!("{}/rest/{}", self.base_url, path))
+            .call()
+        {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(code, response)) => bail!(
+                "REST {} failed with {}: {}",
+                path,
+                code,
+                response.into_string().unwrap_or_default()
+            ),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_bytes(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        Ok(match self.get(path)? {
+            Some(response) => {
+                let mut bytes = vec![];
+                response.into_reader().read_to_end(&mut bytes)?;
+                Some(bytes)
+            }
+            None => None,
+        })
+    }
+
+    fn get_chain_info(&self) -> Result<serde_json::Value> {
+        match self.get("chaininfo.json")? {
+            Some(response) => Ok(serde_json::from_reader(response.into_reader())?),
+            None => bail!("REST interface not available at {}", self.base_url),
+        }
+    }
+
+    /// Name of the chain the node is on (`main`, `test`, ...)
+    pub fn get_chain(&self) -> Result<String> {
+        match self.get_chain_info()?["chain"].as_str() {
+            Some(chain) => Ok(chain.to_owned()),
+            None => bail!("Missing `chain` in REST chaininfo"),
+        }
+    }
+}
+
+impl Rpc for RestRpc {
+    type Data = Box<bitcoin::Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 2000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 100;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        match self.get_chain_info()?["blocks"].as_u64() {
+            Some(blocks) => Ok(blocks as BlockHeight),
+            None => bail!("Missing `blocks` in REST chaininfo"),
+        }
+    }
+
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        let info = self.get_chain_info()?;
+        match (info["blocks"].as_u64(), info["chainwork"].as_str()) {
+            (Some(blocks), Some(chainwork)) => Ok((
+                blocks as BlockHeight,
+                Some(util::bitcoin::chainwork_from_hex(chainwork)?),
+            )),
+            _ => bail!("Missing `blocks` or `chainwork` in REST chaininfo"),
+        }
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(
+            match self.get_bytes(&format!("blockhashbyheight/{}.bin", height))? {
+                Some(bytes) => Some(deserialize(&bytes)?),
+                None => None,
+            },
+        )
+    }
+
+    fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let response = match self.get(&format!("block/{}.bin", hash))? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let block = bitcoin::Block::consensus_decode(response.into_reader())?;
+        let prev_id = block.header.prev_blockhash;
+        Ok(Some((Box::new(block), prev_id)))
+    }
+}
+
+impl HeaderRpc for RestRpc {
+    fn get_headers_by_height(&self, start: BlockHeight, count: usize) -> Result<Vec<BlockHeader>> {
+        let hash = match self.get_block_id_by_height(start)? {
+            Some(hash) => hash,
+            None => return Ok(vec![]),
+        };
+        match self.get_bytes(&format!("headers/{}/{}.bin", count, hash))? {
+            Some(bytes) => bytes
+                .chunks(80)
+                .map(|header| Ok(deserialize(header)?))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Subscriber for Bitcoin Core's ZMQ notifications
+//!
+//! Needs `zmqpub*` options in `bitcoin.conf` (eg. `zmqpubhashblock=tcp://127.0.0.1:28332`).
+//! Notifications are only a hint: ZMQ can drop messages, so callers should keep
+//! polling the node, just less eagerly. Gaps in per-topic sequence numbers are
+//! reported as `Notification::Missed`, so the caller can resync right away.
+use crate::prelude::*;
+use bitcoin::{consensus::deserialize, hashes::Hash, BlockHash, Txid};
+use log::{debug, info, trace, warn};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+/// How often the subscriber thread checks if it should stop
+const RECV_TIMEOUT_MS: i32 = 500;
+
+/// Topics published by Core
+pub const TOPIC_HASHBLOCK: &str = "hashblock";
+pub const TOPIC_RAWBLOCK: &str = "rawblock";
+pub const TOPIC_RAWTX: &str = "rawtx";
+pub const TOPIC_SEQUENCE: &str = "sequence";
+
+/// Event from the `sequence` topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SequenceEvent {
+    BlockConnected(BlockHash),
+    BlockDisconnected(BlockHash),
+    TxAdded { txid: Txid, mempool_seq: u64 },
+    TxRemoved { txid: Txid, mempool_seq: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+    Block(BlockHash),
+    RawBlock(Box<bitcoin::Block>),
+    RawTx(Box<bitcoin::Transaction>),
+    Sequence(SequenceEvent),
+    /// Some messages were dropped; the caller should poll the node
+    Missed {
+        topic: String,
+    },
+}
+
+impl Notification {
+    /// Does it suggest there might be a new block
+    pub fn is_block_related(&self) -> bool {
+        matches!(
+            self,
+            Notification::Block(_)
+                | Notification::RawBlock(_)
+                | Notification::Sequence(SequenceEvent::BlockConnected(_))
+                | Notification::Sequence(SequenceEvent::BlockDisconnected(_))
+                | Notification::Missed { .. }
+        )
+    }
+}
+
+/// Hashes in ZMQ messages are in the reversed (RPC display) byte order
+fn hash_from_zmq<H: Hash>(bytes: &[u8]) -> Result<H> {
+    let mut bytes = bytes.to_vec();
+    bytes.reverse();
+    Ok(H::from_slice(&bytes)?)
+}
+
+fn parse(topic: &str, body: &[u8]) -> Result<Notification> {
+    Ok(match topic {
+        TOPIC_HASHBLOCK => Notification::Block(hash_from_zmq(body)?),
+        TOPIC_RAWBLOCK => Notification::RawBlock(Box::new(deserialize(body)?)),
+        TOPIC_RAWTX => Notification::RawTx(Box::new(deserialize(body)?)),
+        TOPIC_SEQUENCE => {
+            if body.len() < 33 {
+                bail!("ZMQ: `sequence` message too short");
+            }
+            let hash = &body[..32];
+            let mempool_seq = || -> Result<u64> {
+                match body[33..].try_into() {
+                    Ok(bytes) => Ok(u64::from_le_bytes(bytes)),
+                    Err(_) => bail!("ZMQ: `sequence` message without mempool sequence"),
+                }
+            };
+            Notification::Sequence(match body[32] {
+                b'C' => SequenceEvent::BlockConnected(hash_from_zmq(hash)?),
+                b'D' => SequenceEvent::BlockDisconnected(hash_from_zmq(hash)?),
+                b'A' => SequenceEvent::TxAdded {
+                    txid: hash_from_zmq(hash)?,
+                    mempool_seq: mempool_seq()?,
+                },
+                b'R' => SequenceEvent::TxRemoved {
+                    txid: hash_from_zmq(hash)?,
+                    mempool_seq: mempool_seq()?,
+                },
+                label => bail!("ZMQ: unknown `sequence` label {}", label),
+            })
+        }
+        topic => bail!("ZMQ: unexpected topic {}", topic),
+    })
+}
+
+/// Background thread receiving ZMQ notifications
+///
+/// Every notification is passed to a handler, on the subscriber thread.
+/// Stops when dropped.
+pub struct ZmqSubscriber {
+    finish: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ZmqSubscriber {
+    /// Subscribe to `topics` at `endpoint` (eg. `tcp://127.0.0.1:28332`)
+    pub fn new(
+        endpoint: &str,
+        topics: &[&str],
+        mut handler: impl FnMut(Notification) + Send + 'static,
+    ) -> Result<Self> {
+        let ctx = ::zmq::Context::new();
+        let socket = ctx.socket(::zmq::SUB)?;
+        socket.set_rcvtimeo(RECV_TIMEOUT_MS)?;
+        socket.connect(endpoint)?;
+        for topic in topics {
+            socket.set_subscribe(topic.as_bytes())?;
+        }
+        info!("ZMQ: subscribed to {} at {}", topics.join(", "), endpoint);
+
+        let finish = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let finish = finish.clone();
+            move || {
+                // keep the context alive as long as the socket
+                let _ctx = ctx;
+                let mut last_seq: HashMap<String, u32> = HashMap::new();
+                while !finish.load(Ordering::SeqCst) {
+                    let parts = match socket.recv_multipart(0) {
+                        Ok(parts) => parts,
+                        Err(::zmq::Error::EAGAIN) => continue,
+                        Err(e) => {
+                            warn!("ZMQ: receive failed: {}", e);
+                            continue;
+                        }
+                    };
+                    if parts.len() != 3 {
+                        debug!("ZMQ: ignoring message with {} parts", parts.len());
+                        continue;
+                    }
+                    let topic = String::from_utf8_lossy(&parts[0]).into_owned();
+                    let seq = match parts[2][..].try_into() {
+                        Ok(bytes) => u32::from_le_bytes(bytes),
+                        Err(_) => {
+                            debug!("ZMQ: ignoring message with malformed sequence");
+                            continue;
+                        }
+                    };
+                    if let Some(last) = last_seq.insert(topic.clone(), seq) {
+                        if seq != last.wrapping_add(1) {
+                            debug!("ZMQ: missed {} messages", topic);
+                            handler(Notification::Missed {
+                                topic: topic.clone(),
+                            });
+                        }
+                    }
+                    trace!("ZMQ: got {} #{}", topic, seq);
+                    match parse(&topic, &parts[1]) {
+                        Ok(notification) => handler(notification),
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            finish,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ZmqSubscriber {
+    fn drop(&mut self) {
+        self.finish.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("ZMQ thread panicked");
+        }
+    }
+}
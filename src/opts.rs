@@ -5,4 +5,44 @@ use structopt::StructOpt;
 pub struct Opts {
     #[structopt(long = "wipe-whole-db")]
     pub wipe_db: bool,
+
+    /// Download and validate all block headers before fetching blocks
+    #[structopt(long = "headers-first")]
+    pub headers_first: bool,
+
+    /// Check that blocks match their ids and txs (merkle root, witness commitment) before writing them
+    #[structopt(long = "verify-blocks")]
+    pub verify_blocks: bool,
+
+    /// In bulk modes, write tables over separate connections in parallel (needs `max_prepared_transactions` >= 5)
+    #[structopt(long = "parallel-writers")]
+    pub parallel_writers: bool,
+
+    /// During initial sync, don't store input witness stacks (`input.witness` is left NULL)
+    #[structopt(long = "skip-witness")]
+    pub skip_witness: bool,
+
+    /// On SIGINT/SIGTERM, how long to wait for pending blocks to be written before aborting
+    #[structopt(long = "shutdown-timeout", default_value = "60")]
+    pub shutdown_timeout_secs: u64,
+
+    /// On an empty db, index from the node's tip backwards, down to this height, first
+    #[structopt(long = "backward-until")]
+    pub backward_until: Option<u32>,
+
+    /// Record all node answers to a file, to replay later with a `replay:<path>` node url
+    #[structopt(long = "record-rpc", parse(from_os_str))]
+    pub record_rpc: Option<std::path::PathBuf>,
+
+    /// How many megabytes of fetched blocks can wait in memory to be indexed
+    #[structopt(long = "prefetch-budget-mb", default_value = "512")]
+    pub prefetch_budget_mb: usize,
+
+    /// Re-fetch already indexed blocks from this height on, fix whatever doesn't match the node, and exit
+    #[structopt(long = "repair-from")]
+    pub repair_from: Option<u32>,
+
+    /// Last height to repair with `--repair-from` (default: the indexed chain-head)
+    #[structopt(long = "repair-to")]
+    pub repair_to: Option<u32>,
 }
@@ -1,3 +1,29 @@
+#[cfg(feature = "async")]
+mod async_prefetcher;
+mod backward;
+mod bitcoin_conf;
+mod blk_files;
+mod chain_stats;
+mod concurrency;
+mod error_retry;
+mod esplora;
+mod fixtures;
+mod headers;
+mod http_stub;
+mod jsonrpc;
+mod p2p;
+mod pg;
+mod pool;
+mod prefetch_budget;
+mod reorg;
+mod replay;
+mod rest;
+mod script_type;
+mod shutdown;
+mod verify;
+#[cfg(feature = "zmq")]
+mod zmq;
+
 use super::*;
 use crate::node::prefetcher;
 use log::{debug, info, trace};
@@ -62,18 +88,21 @@ impl TestRpcInner {
 /// Current implementation is kind of dumb and just does
 /// reorgs. Lots of reorgs. But it's a good enough stresstest
 /// and actually caught some corner cases already.
-struct TestRpc {
+///
+/// `BATCH_SIZE` is its `RECOMMENDED_BATCH_SIZE`; above 1 it exercises
+/// range claims in workers.
+struct TestRpc<const BATCH_SIZE: usize> {
     inner: Mutex<TestRpcInner>,
 }
 
-impl TestRpc {
+impl<const BATCH_SIZE: usize> TestRpc<BATCH_SIZE> {
     fn new(start: Option<u8>, reorgs_base: Vec<(u8, u8, u8)>) -> Self {
         let reorgs: Vec<_> = reorgs_base
             .into_iter()
             .map(|n| ReorgParams {
                 delay: n.0 % 8,
                 depth: n.1,
-                add: n.2 + 1,
+                add: n.2.saturating_add(1),
             })
             .collect();
 
@@ -86,7 +115,7 @@ impl TestRpc {
             next_block_data: 1337,
         };
 
-        for _ in 0..start.map(|n| n + 1).unwrap_or(0) {
+        for _ in 0..start.map(|n| usize::from(n) + 1).unwrap_or(0) {
             inner.mine_block();
         }
 
@@ -158,11 +187,12 @@ impl TestRpc {
 /// data is just a fixed offset from it's id
 const DATA_TO_ID_OFFSET: usize = 3;
 
-impl Rpc for TestRpc {
+impl<const BATCH_SIZE: usize> Rpc for TestRpc<BATCH_SIZE> {
     type Data = usize;
     type Id = usize;
     const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
     const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_BATCH_SIZE: usize = BATCH_SIZE;
 
     fn get_block_count(&self) -> Result<BlockHeight> {
         self.maybe_change_state();
@@ -210,8 +240,13 @@ fn prefetcher_reorg_reliability_fixed() {
     for (start, reorgs_seed) in vec![
         (None, vec![(0, 1, 0), (0, 0, 0), (40, 69, 70)]),
         (None, vec![(0, 1, 0), (1, 56, 84)]),
+        (Some(6), vec![(1, 5, 0)]),
     ] {
-        assert!(prefetcher_reorg_reliability(start, reorgs_seed));
+        assert!(prefetcher_reorg_reliability::<1>(
+            start,
+            reorgs_seed.clone()
+        ));
+        assert!(prefetcher_reorg_reliability::<4>(start, reorgs_seed));
     }
 }
 
@@ -220,10 +255,36 @@ fn prefetcher_reorg_reliability_quickcheck(
     start: Option<u8>,
     reorgs_seed: Vec<(u8, u8, u8)>,
 ) -> bool {
-    prefetcher_reorg_reliability(start, reorgs_seed)
+    prefetcher_reorg_reliability::<1>(start, reorgs_seed)
+}
+
+#[quickcheck]
+fn prefetcher_batched_reorg_reliability_quickcheck(
+    start: Option<u8>,
+    reorgs_seed: Vec<(u8, u8, u8)>,
+) -> bool {
+    prefetcher_reorg_reliability::<4>(start, reorgs_seed)
 }
 
-fn prefetcher_reorg_reliability(start: Option<u8>, mut reorgs_seed: Vec<(u8, u8, u8)>) -> bool {
+fn prefetcher_reorg_reliability<const BATCH_SIZE: usize>(
+    start: Option<u8>,
+    reorgs_seed: Vec<(u8, u8, u8)>,
+) -> bool {
+    reorg_reliability::<BATCH_SIZE>(start, reorgs_seed, |rpc, start| {
+        let mut prefetcher = prefetcher::Prefetcher::new(rpc, start).unwrap();
+        Box::new(move || prefetcher.next())
+    })
+}
+
+/// Blocks returned one by one, by a fetcher being tested
+type NextBlock = Box<dyn FnMut() -> Option<WithHeightAndId<usize, usize>>>;
+
+/// Check that a fetcher created by `new_fetcher` follows `TestRpc`'s chain through all the reorgs
+fn reorg_reliability<const BATCH_SIZE: usize>(
+    start: Option<u8>,
+    mut reorgs_seed: Vec<(u8, u8, u8)>,
+    new_fetcher: impl FnOnce(Arc<TestRpc<BATCH_SIZE>>, Option<WithHeightAndId<usize>>) -> NextBlock,
+) -> bool {
     info!(
         "Prefetcher reliability; start {:?}H; reorgs_params.len() == {}",
         start,
@@ -245,8 +306,7 @@ fn prefetcher_reorg_reliability(start: Option<u8>, mut reorgs_seed: Vec<(u8, u8,
 
     let start = start.map(|start| {
         let prefetcher_starting_height = BlockHeight::from(start).saturating_sub(window_size);
-        let prefetcher_starting_id = rpc
-            .get_block_id_by_height(prefetcher_starting_height)
+        let prefetcher_starting_id = Rpc::get_block_id_by_height(&*rpc, prefetcher_starting_height)
             .unwrap()
             .unwrap();
 
@@ -257,7 +317,7 @@ fn prefetcher_reorg_reliability(start: Option<u8>, mut reorgs_seed: Vec<(u8, u8,
         }
     });
 
-    let mut prefetcher = prefetcher::Prefetcher::new(rpc.clone(), start).unwrap();
+    let mut next_block = new_fetcher(rpc.clone(), start);
 
     loop {
         let intern_chain = rpc.get_current_chain();
@@ -278,7 +338,7 @@ fn prefetcher_reorg_reliability(start: Option<u8>, mut reorgs_seed: Vec<(u8, u8,
             }
         }
 
-        let item = prefetcher.next().unwrap();
+        let item = next_block().unwrap();
         debug!(
             "prefetcher returned: {}H (id: {}; data: {})",
             item.height, item.id, item.data
@@ -0,0 +1,83 @@
+use super::{reorg_reliability, TestRpc};
+use crate::{
+    node::async_prefetcher::{AsyncPrefetcher, BlockingRpc},
+    prelude::*,
+    AsyncRpc, BlockHeight, Rpc, WithHeightAndId,
+};
+use futures::StreamExt;
+use quickcheck_macros::quickcheck;
+use std::sync::Arc;
+
+#[async_trait::async_trait]
+impl<const BATCH_SIZE: usize> AsyncRpc for TestRpc<BATCH_SIZE> {
+    type Data = usize;
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_BATCH_SIZE: usize = BATCH_SIZE;
+
+    async fn get_block_count(&self) -> Result<BlockHeight> {
+        Rpc::get_block_count(self)
+    }
+
+    async fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Rpc::get_block_id_by_height(self, height)
+    }
+
+    async fn get_block_by_id(&self, hash: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        Rpc::get_block_by_id(self, hash)
+    }
+}
+
+/// Run `AsyncPrefetcher` over `rpc` in its own runtime
+fn async_prefetcher<R>(rpc: Arc<R>, start: Option<WithHeightAndId<usize>>) -> super::NextBlock
+where
+    R: AsyncRpc<Id = usize, Data = usize> + 'static,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let mut prefetcher = runtime.block_on(AsyncPrefetcher::new(rpc, start)).unwrap();
+    Box::new(move || runtime.block_on(prefetcher.next()))
+}
+
+#[test]
+fn async_prefetcher_reorg_reliability_fixed() {
+    for (start, reorgs_seed) in [
+        (None, vec![(0, 1, 0), (0, 0, 0), (40, 69, 70)]),
+        (None, vec![(0, 1, 0), (1, 56, 84)]),
+        (Some(6), vec![(1, 5, 0)]),
+    ] {
+        assert!(reorg_reliability::<1>(
+            start,
+            reorgs_seed.clone(),
+            async_prefetcher
+        ));
+        assert!(reorg_reliability::<4>(
+            start,
+            reorgs_seed.clone(),
+            async_prefetcher
+        ));
+        // same thing, on the blocking threads
+        assert!(reorg_reliability::<4>(start, reorgs_seed, |rpc, start| {
+            async_prefetcher(Arc::new(BlockingRpc::new(rpc)), start)
+        }));
+    }
+}
+
+#[quickcheck]
+fn async_prefetcher_reorg_reliability_quickcheck(
+    start: Option<u8>,
+    reorgs_seed: Vec<(u8, u8, u8)>,
+) -> bool {
+    reorg_reliability::<1>(start, reorgs_seed, async_prefetcher)
+}
+
+#[quickcheck]
+fn async_prefetcher_batched_reorg_reliability_quickcheck(
+    start: Option<u8>,
+    reorgs_seed: Vec<(u8, u8, u8)>,
+) -> bool {
+    reorg_reliability::<4>(start, reorgs_seed, async_prefetcher)
+}
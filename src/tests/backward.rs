@@ -0,0 +1,72 @@
+use crate::node::backward::BackwardFetcher;
+use crate::{prelude::*, BlockHeight, Rpc, WithHeightAndId};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A node with a fixed chain of `len` blocks, failing every other call
+struct MockNode {
+    len: usize,
+    calls: AtomicUsize,
+}
+
+impl MockNode {
+    fn new(len: usize) -> Arc<Self> {
+        Arc::new(Self {
+            len,
+            calls: AtomicUsize::new(0),
+        })
+    }
+
+    fn tip(&self) -> WithHeightAndId<usize> {
+        WithHeightAndId {
+            height: self.len as BlockHeight - 1,
+            id: self.len - 1,
+            data: (),
+        }
+    }
+}
+
+impl Rpc for MockNode {
+    type Data = ();
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.len as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(Some(height as usize).filter(|&h| h < self.len))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        if self.calls.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+            bail!("node is flaky");
+        }
+        Ok(Some(((), id.saturating_sub(1))).filter(|_| *id < self.len))
+    }
+}
+
+#[test]
+fn backward_fetcher_follows_prev_links_down_to_until() {
+    let node = MockNode::new(100);
+    let fetcher = BackwardFetcher::new(node.clone(), node.tip(), 10);
+    let fetched: Vec<_> = fetcher.map(|item| (item.height, item.id)).collect();
+    let expected: Vec<_> = (10..100).rev().map(|h| (h as BlockHeight, h)).collect();
+    assert_eq!(fetched, expected);
+}
+
+#[test]
+fn backward_fetcher_stops_on_shutdown() {
+    let node = MockNode::new(100_000);
+    let mut fetcher = BackwardFetcher::new(node.clone(), node.tip(), 0);
+    let shutdown = fetcher.shutdown_handle();
+    for height in (99_900..100_000).rev() {
+        assert_eq!(fetcher.next().unwrap().height, height);
+    }
+    shutdown.shutdown();
+    assert!(fetcher.next().is_none());
+}
@@ -0,0 +1,92 @@
+use super::fixtures;
+use crate::{node::bitcoin_conf::BitcoinConf, RpcInfo, RpcKind};
+use bitcoincore_rpc::Auth;
+use std::path::Path;
+
+const CONF: &str = "
+# top section
+testnet=1
+rpcport=9999
+rest=1
+
+[main]
+rpcport=1111
+
+[test]
+rpcport=2222 # comment
+rpcconnect=10.0.0.1
+";
+
+#[test]
+fn bitcoin_conf_uses_network_section() {
+    let conf = BitcoinConf::parse(CONF, Path::new("/data")).unwrap();
+    assert_eq!(conf.network, bitcoin::Network::Testnet);
+    assert_eq!(conf.url(), "http://10.0.0.1:2222");
+    assert!(conf.rest);
+    match conf.auth() {
+        Auth::CookieFile(path) => assert_eq!(path, Path::new("/data/testnet3/.cookie")),
+        auth => panic!("unexpected {:?}", auth),
+    }
+}
+
+#[test]
+fn bitcoin_conf_top_section_rpcport_is_mainnet_only() {
+    let conf = BitcoinConf::parse("regtest=1\nrpcport=9999\n", Path::new("/data")).unwrap();
+    assert_eq!(conf.network, bitcoin::Network::Regtest);
+    assert_eq!(conf.url(), "http://127.0.0.1:18443");
+
+    let conf = BitcoinConf::parse("rpcport=9999\n", Path::new("/data")).unwrap();
+    assert_eq!(conf.network, bitcoin::Network::Bitcoin);
+    assert_eq!(conf.url(), "http://127.0.0.1:9999");
+}
+
+#[test]
+fn bitcoin_conf_user_and_password() {
+    let conf = BitcoinConf::parse(
+        "chain=signet\ndatadir=/btc\nrpcuser=user\nrpcpassword=pass\n",
+        Path::new("/data"),
+    )
+    .unwrap();
+    assert_eq!(conf.network, bitcoin::Network::Signet);
+    assert_eq!(conf.datadir, Path::new("/btc"));
+    match conf.auth() {
+        Auth::UserPass(user, pass) => assert_eq!((user.as_str(), pass.as_str()), ("user", "pass")),
+        auth => panic!("unexpected {:?}", auth),
+    }
+}
+
+#[test]
+fn rpc_info_from_bitcoin_conf() {
+    let dir = fixtures::temp_dir("bitcoin-conf");
+    let path = dir.join("bitcoin.conf");
+    std::fs::write(&path, CONF).unwrap();
+
+    let info = RpcInfo::from_url(&format!("rest+conf:{}?read_timeout=5", path.display())).unwrap();
+    assert_eq!(info.kind, RpcKind::Rest);
+    assert_eq!(info.url, "http://10.0.0.1:2222");
+    assert_eq!(info.read_timeout.as_secs(), 5);
+    match info.auth {
+        Auth::CookieFile(cookie) => assert_eq!(cookie, dir.join("testnet3/.cookie")),
+        auth => panic!("unexpected {:?}", auth),
+    }
+}
+
+#[test]
+fn rpc_info_reads_password_file() {
+    let path = fixtures::temp_dir("password-file").join("password");
+    std::fs::write(&path, "secret\n").unwrap();
+
+    let info = RpcInfo::from_url(&format!(
+        "http://rpcauth-user@localhost:8332?password_file={}",
+        path.display()
+    ))
+    .unwrap();
+    assert_eq!(info.url, "http://localhost:8332/");
+    match info.auth {
+        Auth::UserPass(user, pass) => {
+            assert_eq!((user.as_str(), pass.as_str()), ("rpcauth-user", "secret"))
+        }
+        auth => panic!("unexpected {:?}", auth),
+    }
+    assert!(RpcInfo::from_url("http://localhost:8332?unknown=1").is_err());
+}
@@ -0,0 +1,168 @@
+use super::fixtures;
+use crate::node::{any::AnyRpc, blk_files::BlkFilesRpc, fetcher};
+use crate::{Rpc, RpcInfo, RpcKind};
+use bitcoin::{consensus::serialize, Block, Network};
+use std::{fs, path::Path, sync::Arc};
+
+/// Write `blocks` as a `blk*.dat` file, obfuscated with `xor_key`
+fn write_blk_file(dir: &Path, file: u32, blocks: &[&Block], xor_key: [u8; 8]) {
+    let magic = Network::Regtest.magic().to_le_bytes();
+    let mut data = vec![];
+    for block in blocks {
+        let bytes = serialize(*block);
+        data.extend_from_slice(&magic);
+        data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bytes);
+    }
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= xor_key[i % 8];
+    }
+    // Core preallocates the files, so there's some (not obfuscated)
+    // zero-padding at the end
+    data.extend_from_slice(&[0u8; 64]);
+    fs::write(dir.join(format!("blk{:05}.dat", file)), data).unwrap();
+}
+
+fn fetch_all(rpc: BlkFilesRpc) -> Vec<bitcoin::BlockHash> {
+    let end = rpc.get_block_count().unwrap();
+    fetcher::Fetcher::new(Arc::new(rpc), None, Some(end))
+        .unwrap()
+        .map(|item| {
+            assert_eq!(item.data.block_hash(), item.id);
+            item.id
+        })
+        .collect()
+}
+
+#[test]
+fn blk_files_follow_most_work_chain() {
+    let dir = fixtures::temp_dir("blk-files-fork");
+    let chain = fixtures::regtest_chain(12);
+    let mut stale = chain[..8].to_vec();
+    fixtures::extend_chain(&mut stale, 2, 1);
+
+    // blocks stored out of order and including a stale branch, across two files
+    let first: Vec<_> = chain[..5].iter().chain(stale[8..].iter()).collect();
+    let second: Vec<_> = chain[5..].iter().rev().collect();
+    write_blk_file(&dir, 0, &first, [0; 8]);
+    write_blk_file(&dir, 1, &second, [0; 8]);
+
+    let rpc = BlkFilesRpc::new(&dir, Network::Regtest).unwrap();
+    assert_eq!(rpc.get_block_count().unwrap(), 11);
+    assert_eq!(fetch_all(rpc), fixtures::chain_ids(&chain));
+}
+
+#[test]
+fn blk_files_xor_obfuscated() {
+    let dir = fixtures::temp_dir("blk-files-xor");
+    let chain = fixtures::regtest_chain(6);
+    let xor_key = [0x1f, 0x2e, 0x3d, 0x4c, 0x5b, 0x6a, 0x79, 0x88];
+    fs::write(dir.join("xor.dat"), xor_key).unwrap();
+    write_blk_file(&dir, 0, &chain.iter().collect::<Vec<_>>(), xor_key);
+
+    let rpc = BlkFilesRpc::new(&dir, Network::Regtest).unwrap();
+    assert_eq!(fetch_all(rpc), fixtures::chain_ids(&chain));
+}
+
+#[test]
+fn blk_files_pick_up_new_blocks() {
+    let dir = fixtures::temp_dir("blk-files-append");
+    let chain = fixtures::regtest_chain(8);
+    write_blk_file(&dir, 0, &chain[..4].iter().collect::<Vec<_>>(), [0; 8]);
+
+    let rpc = BlkFilesRpc::new(&dir, Network::Regtest).unwrap();
+    assert_eq!(rpc.get_block_count().unwrap(), 3);
+    assert_eq!(rpc.get_block_id_by_height(4).unwrap(), None);
+
+    write_blk_file(&dir, 1, &chain[4..].iter().collect::<Vec<_>>(), [0; 8]);
+    assert_eq!(
+        rpc.get_block_id_by_height(7).unwrap(),
+        Some(chain[7].block_hash())
+    );
+    let (block, prev) = rpc
+        .get_block_by_id(&chain[5].block_hash())
+        .unwrap()
+        .unwrap();
+    assert_eq!(*block, chain[5]);
+    assert_eq!(prev, chain[4].block_hash());
+}
+
+#[test]
+fn blk_files_link_orphans_on_rescan() {
+    let dir = fixtures::temp_dir("blk-files-orphans");
+    let chain = fixtures::regtest_chain(8);
+    let first: Vec<_> = chain[..3].iter().chain(chain[5..].iter()).collect();
+    write_blk_file(&dir, 0, &first, [0; 8]);
+
+    let rpc = BlkFilesRpc::new(&dir, Network::Regtest).unwrap();
+    assert_eq!(rpc.get_block_count().unwrap(), 2);
+
+    // the missing parents arrive later
+    write_blk_file(&dir, 1, &chain[3..5].iter().collect::<Vec<_>>(), [0; 8]);
+    assert_eq!(rpc.get_block_count().unwrap(), 7);
+    assert_eq!(fetch_all(rpc), fixtures::chain_ids(&chain));
+}
+
+#[test]
+fn blk_files_skip_blocks_without_proof_of_work() {
+    let dir = fixtures::temp_dir("blk-files-pow");
+    let chain = fixtures::regtest_chain(8);
+
+    // a longer fork, with no valid proof of work
+    let mut bad_pow = chain[..5].to_vec();
+    for i in 0..6 {
+        let height = bad_pow.len() as u32;
+        let mut block = fixtures::mine_block(
+            bad_pow.last().unwrap(),
+            vec![fixtures::coinbase(height, 1 + i)],
+        );
+        while block.header.validate_pow(&block.header.target()).is_ok() {
+            block.header.nonce += 1;
+        }
+        bad_pow.push(block);
+    }
+    // and one with a target easier than the network allows
+    let mut too_easy = chain[..5].to_vec();
+    let mut block = fixtures::mine_block(&chain[4], vec![fixtures::coinbase(5, 9)]);
+    block.header.bits = 0x2100_ffff;
+    fixtures::mine_header(&mut block.header);
+    too_easy.push(block);
+    fixtures::extend_chain(&mut too_easy, 5, 9);
+
+    let blocks: Vec<_> = chain
+        .iter()
+        .chain(bad_pow[5..].iter())
+        .chain(too_easy[5..].iter())
+        .collect();
+    write_blk_file(&dir, 0, &blocks, [0; 8]);
+
+    let rpc = BlkFilesRpc::new(&dir, Network::Regtest).unwrap();
+    assert_eq!(rpc.get_block_count().unwrap(), 7);
+    assert_eq!(fetch_all(rpc), fixtures::chain_ids(&chain));
+}
+
+#[test]
+fn blk_files_from_url() {
+    let dir = fixtures::temp_dir("blk-files-url");
+    let chain = fixtures::regtest_chain(4);
+    let xor_key = [0x1f, 0x2e, 0x3d, 0x4c, 0x5b, 0x6a, 0x79, 0x88];
+    fs::write(dir.join("xor.dat"), xor_key).unwrap();
+    write_blk_file(&dir, 0, &chain.iter().collect::<Vec<_>>(), xor_key);
+
+    let info = RpcInfo::from_url(&format!("blk:{}", dir.display())).unwrap();
+    assert_eq!(info.kind, RpcKind::BlkFiles);
+    assert_eq!(info.network, None);
+    let rpc = info.to_any_rpc().unwrap();
+    assert_eq!(rpc.get_network().unwrap(), Network::Regtest);
+    assert!(!rpc.has_headers());
+    assert_eq!(rpc.get_block_count().unwrap(), 3);
+    assert!(matches!(rpc, AnyRpc::BlkFiles(_)));
+
+    // magic not matching the given network
+    let info = RpcInfo::from_url(&format!("blk:{}?network=main", dir.display())).unwrap();
+    assert_eq!(info.network, Some(Network::Bitcoin));
+    assert!(info.to_any_rpc().is_err());
+
+    assert!(RpcInfo::from_url(&format!("blk:{}?cookie=x", dir.display())).is_err());
+    assert!(RpcInfo::from_url(&format!("rest+blk:{}", dir.display())).is_err());
+}
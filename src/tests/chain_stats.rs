@@ -0,0 +1,96 @@
+use crate::db::pg::{parse_uint256, work_from_bits, write_numeric, ChainStatsCache};
+use bitcoin::{blockdata::constants::genesis_block, util::uint::Uint256, Network};
+
+#[test]
+fn chain_stats_median_time_past() {
+    let mut cache = ChainStatsCache::default();
+    let times = [10, 5, 30, 20, 1, 50, 60, 2, 3, 4, 70, 80, 90];
+    let mtps: Vec<_> = times
+        .iter()
+        .enumerate()
+        .map(|(height, time)| cache.push(height as u32, *time, None).median_time_past)
+        .collect();
+    assert_eq!(
+        mtps,
+        vec![
+            Some(10),
+            Some(10),
+            Some(10),
+            Some(20),
+            Some(10),
+            Some(20),
+            Some(20),
+            Some(20),
+            Some(10),
+            Some(10),
+            Some(10),
+            // 10 is out of the window now
+            Some(20),
+            // and 5 too
+            Some(30),
+        ]
+    );
+
+    // not enough ancestors to take the median of
+    let mut cache = ChainStatsCache::default();
+    assert_eq!(cache.push(100, 1, None).median_time_past, None);
+    for time in 2..=10 {
+        assert_eq!(cache.push(100 + time, time, None).median_time_past, None);
+    }
+    assert_eq!(cache.push(111, 11, None).median_time_past, Some(6));
+}
+
+#[test]
+fn chain_stats_chainwork() {
+    for network in &[Network::Bitcoin, Network::Regtest] {
+        let header = genesis_block(*network).header;
+        assert_eq!(work_from_bits(header.bits), header.work());
+    }
+    assert_eq!(
+        work_from_bits(0x1d00ffff),
+        Uint256::from_u64(0x1_0001_0001).expect("fits")
+    );
+
+    let mut cache = ChainStatsCache::default();
+    assert_eq!(
+        cache.push(0, 0, Some(work_from_bits(0x207fffff))).chainwork,
+        None
+    );
+
+    // as for a chain starting at genesis
+    let mut cache = ChainStatsCache::default();
+    cache.chainwork = Some(Uint256::default());
+    for height in 0..3 {
+        let stats = cache.push(height, 0, Some(work_from_bits(0x207fffff)));
+        assert_eq!(
+            stats.chainwork,
+            Some(Uint256::from_u64(2 * (u64::from(height) + 1)).expect("fits"))
+        );
+    }
+    assert_eq!(cache.push(3, 0, None).chainwork, None);
+    assert_eq!(
+        cache
+            .push(4, 0, Some(Uint256::from_u64(1).expect("fits")))
+            .chainwork,
+        None
+    );
+}
+
+#[test]
+fn chain_stats_numeric() {
+    for s in &[
+        "0",
+        "7",
+        "10000",
+        "100010001",
+        "14470626182187499912576757",
+        // 2**256 - 1
+        "115792089237316195423570985008687907853269984665640564039457584007913129639935",
+    ] {
+        let n = parse_uint256(s).unwrap();
+        let mut formatted = String::new();
+        write_numeric(&mut formatted, n).unwrap();
+        assert_eq!(&formatted, s);
+    }
+    assert!(parse_uint256("12a").is_err());
+}
@@ -0,0 +1,175 @@
+use crate::node::prefetcher::{
+    adjust_worker_num, Concurrency, ConcurrencyStats, Prefetcher, CONCURRENCY_ADJUST_INTERVAL,
+};
+use crate::{prelude::*, BlockHeight, Rpc};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// A node with a chain of `len` blocks, taking a millisecond per block
+struct MockNode {
+    len: AtomicUsize,
+}
+
+impl Rpc for Arc<MockNode> {
+    type Data = ();
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 10;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.len.load(Ordering::SeqCst) as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(Some(height as usize).filter(|&h| h < self.len.load(Ordering::SeqCst)))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        std::thread::sleep(Duration::from_millis(1));
+        Ok(Some(((), id.saturating_sub(1))).filter(|_| *id < self.len.load(Ordering::SeqCst)))
+    }
+}
+
+fn busy_stats(active: usize) -> ConcurrencyStats {
+    ConcurrencyStats {
+        window: Duration::from_secs(1),
+        fetched: 1000 * active,
+        fetch_time: Duration::from_secs(active as u64),
+        calls: 1000 * active,
+        consumer_wait: Duration::from_millis(500),
+        ..default()
+    }
+}
+
+#[test]
+fn adjust_worker_num_rules() {
+    let mut best = Some(Duration::from_millis(1));
+    assert_eq!(adjust_worker_num(4, 8, &busy_stats(4), &mut best), 5);
+    assert_eq!(adjust_worker_num(8, 8, &busy_stats(8), &mut best), 8);
+
+    let at_tip = ConcurrencyStats {
+        tip_reached: true,
+        ..busy_stats(4)
+    };
+    assert_eq!(adjust_worker_num(4, 8, &at_tip, &mut best), 1);
+
+    let failing = ConcurrencyStats {
+        errors: 1000,
+        ..busy_stats(4)
+    };
+    assert_eq!(adjust_worker_num(4, 8, &failing, &mut best), 2);
+
+    let consumer_busy = ConcurrencyStats {
+        consumer_wait: Duration::from_millis(10),
+        ..busy_stats(4)
+    };
+    assert_eq!(adjust_worker_num(4, 8, &consumer_busy, &mut best), 3);
+    assert_eq!(adjust_worker_num(1, 8, &consumer_busy, &mut best), 1);
+
+    let budget_exhausted = ConcurrencyStats {
+        budget_exhausted: true,
+        ..busy_stats(4)
+    };
+    assert_eq!(adjust_worker_num(4, 8, &budget_exhausted, &mut best), 3);
+
+    let idle = ConcurrencyStats {
+        fetch_time: Duration::from_millis(100),
+        ..busy_stats(4)
+    };
+    assert_eq!(adjust_worker_num(4, 8, &idle, &mut best.clone()), 4);
+
+    let saturated = ConcurrencyStats {
+        fetch_time: Duration::from_secs(12),
+        ..busy_stats(4)
+    };
+    assert_eq!(adjust_worker_num(4, 8, &saturated, &mut best), 3);
+    // but the node might just have become slower for good
+    for _ in 0..20 {
+        adjust_worker_num(3, 8, &saturated, &mut best);
+    }
+    assert_eq!(adjust_worker_num(3, 8, &saturated, &mut best), 4);
+}
+
+/// Let `Concurrency` adjust the number of workers at the end of a window
+fn next_window(concurrency: &Concurrency) {
+    std::thread::sleep(CONCURRENCY_ADJUST_INTERVAL + Duration::from_millis(10));
+    concurrency.maybe_adjust();
+}
+
+#[test]
+fn concurrency_consumer_busy_across_windows() {
+    let concurrency = Concurrency::new(4, 8);
+    concurrency.consumer_wait_start();
+    concurrency.consumer_wait_end();
+
+    // eg. a slow db write: the consumer doesn't wait for blocks at all
+    next_window(&concurrency);
+    assert_eq!(concurrency.active(), 3);
+    next_window(&concurrency);
+    assert_eq!(concurrency.active(), 2);
+}
+
+#[test]
+fn concurrency_consumer_waiting_across_windows() {
+    let concurrency = Concurrency::new(4, 8);
+    concurrency.consumer_wait_start();
+
+    // the wait is split between the windows
+    next_window(&concurrency);
+    assert_eq!(concurrency.active(), 4);
+    next_window(&concurrency);
+    assert_eq!(concurrency.active(), 4);
+    concurrency.consumer_wait_end();
+    next_window(&concurrency);
+    assert_eq!(concurrency.active(), 3);
+}
+
+/// Wait up to 10s for `f` to hold, while calling `step`
+fn wait_for(mut f: impl FnMut() -> bool, mut step: impl FnMut()) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if f() {
+            return true;
+        }
+        step();
+    }
+    false
+}
+
+#[test]
+fn prefetcher_scales_workers_to_how_far_behind_the_node_is() {
+    let node = Arc::new(MockNode {
+        len: AtomicUsize::new(100),
+    });
+    let mut prefetcher = Prefetcher::new(Arc::new(node.clone()), None).unwrap();
+    let concurrency = prefetcher.concurrency();
+    let mut next_height = 0;
+    let mut next = || {
+        assert_eq!(prefetcher.next().unwrap().height, next_height);
+        next_height += 1;
+    };
+
+    for _ in 0..100 {
+        next();
+    }
+    assert!(wait_for(
+        || concurrency.active() == 1,
+        || std::thread::sleep(Duration::from_millis(10))
+    ));
+
+    // eg. after a long outage
+    node.len.store(1_000_000, Ordering::SeqCst);
+    assert!(wait_for(|| concurrency.active() > 1, &mut next));
+
+    // not consuming blocks
+    let scaled_up = concurrency.active();
+    assert!(wait_for(
+        || concurrency.active() < scaled_up,
+        || std::thread::sleep(Duration::from_millis(10))
+    ));
+}
@@ -0,0 +1,56 @@
+use crate::node::prefetcher::Prefetcher;
+use crate::{prelude::*, BlockHeight, Rpc};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// A node failing every block request
+#[derive(Default)]
+struct FailingNode {
+    calls: AtomicUsize,
+}
+
+impl Rpc for FailingNode {
+    type Data = ();
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 20;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(100_000)
+    }
+
+    fn get_block_id_by_height(&self, _height: BlockHeight) -> Result<Option<Self::Id>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        bail!("node unavailable")
+    }
+
+    fn get_block_by_id(&self, _id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        unreachable!("no block ids are ever returned")
+    }
+}
+
+#[test]
+fn prefetcher_workers_wait_between_error_retries() {
+    let rpc = Arc::new(FailingNode::default());
+    let prefetcher = Prefetcher::new(rpc.clone(), None).unwrap();
+    // the most workers a prefetcher runs
+    let workers = 2 * num_cpus::get() as u64;
+    std::thread::sleep(Duration::from_millis(500));
+    let calls = rpc.calls.load(Ordering::SeqCst) as u64;
+    drop(prefetcher);
+
+    // every worker sleeps at least `RECOMMENDED_ERROR_RETRY_DELAY_MS` after an error
+    let max_calls = workers * (500 / FailingNode::RECOMMENDED_ERROR_RETRY_DELAY_MS + 1);
+    assert!(calls >= 1);
+    assert!(
+        calls <= max_calls,
+        "{} calls, expected at most {}",
+        calls,
+        max_calls
+    );
+}
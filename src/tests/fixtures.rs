@@ -0,0 +1,96 @@
+//! Synthetic regtest chains for tests that need real `bitcoin::Block`s
+use bitcoin::{
+    blockdata::{
+        block::{Block, BlockHeader},
+        constants::genesis_block,
+        script::Builder,
+        transaction::{OutPoint, Transaction, TxIn, TxOut},
+    },
+    hash_types::{BlockHash, TxMerkleNode},
+    Network,
+};
+use std::path::PathBuf;
+
+/// Regtest `bits`: the easiest target, so mining takes a couple of nonces
+pub const REGTEST_BITS: u32 = 0x207f_ffff;
+
+/// Create a coinbase tx unique for a given `height` and `tag`
+pub fn coinbase(height: u32, tag: u8) -> Transaction {
+    Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(i64::from(height))
+                .push_slice(&[tag])
+                .into_script(),
+            sequence: 0xffff_ffff,
+            witness: vec![],
+        }],
+        output: vec![TxOut {
+            value: 50 * 100_000_000,
+            script_pubkey: Builder::new().push_slice(&[tag; 20]).into_script(),
+        }],
+    }
+}
+
+/// Mine a block with a given `txdata` on top of `prev`
+pub fn mine_block(prev: &Block, txdata: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        header: BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::default(),
+            time: prev.header.time + 600,
+            bits: REGTEST_BITS,
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.merkle_root();
+    mine_header(&mut block.header);
+    block
+}
+
+/// Find a `nonce` satisfying the proof-of-work of a `header`
+pub fn mine_header(header: &mut BlockHeader) {
+    let target = header.target();
+    while header.validate_pow(&target).is_err() {
+        header.nonce += 1;
+    }
+}
+
+/// Extend `chain` by `count` blocks, using `tag` to make them unique
+pub fn extend_chain(chain: &mut Vec<Block>, count: usize, tag: u8) {
+    for _ in 0..count {
+        let height = chain.len() as u32;
+        let prev = chain.last().expect("genesis present");
+        let block = mine_block(prev, vec![coinbase(height, tag)]);
+        chain.push(block);
+    }
+}
+
+/// Regtest chain of `len` blocks, including genesis
+pub fn regtest_chain(len: usize) -> Vec<Block> {
+    let mut chain = vec![genesis_block(Network::Regtest)];
+    extend_chain(&mut chain, len - 1, 0);
+    chain
+}
+
+/// Hashes of all the blocks in a `chain`
+pub fn chain_ids(chain: &[Block]) -> Vec<BlockHash> {
+    chain.iter().map(Block::block_hash).collect()
+}
+
+/// A fresh, empty temporary directory for a test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "bitcoin-indexer-test-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("create test dir");
+    dir
+}
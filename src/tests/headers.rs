@@ -0,0 +1,141 @@
+use super::fixtures;
+use crate::node::headers::{retarget_bits, HeaderChain, HeaderRpc, HeadersFirst};
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{consensus::params::Params, Block, BlockHash, BlockHeader, Network};
+use std::sync::Mutex;
+
+/// Retarget cases from Core's `pow_tests.cpp`
+#[test]
+fn headers_retarget_matches_core() {
+    let params = Params::new(Network::Bitcoin);
+    let header = |time, bits| BlockHeader {
+        version: 1,
+        prev_blockhash: default(),
+        merkle_root: default(),
+        time,
+        bits,
+        nonce: 0,
+    };
+    for (first_time, last_time, bits, expected) in [
+        (1261130161, 1262152739, 0x1d00ffff, 0x1d00d86a),
+        (1231006505, 1233061996, 0x1d00ffff, 0x1d00ffff),
+        (1279008237, 1279297671, 0x1c05a3f4, 0x1c0168fd),
+        (1263163443, 1269211443, 0x1c387f6f, 0x1d00e1fd),
+    ] {
+        assert_eq!(
+            retarget_bits(&params, &header(last_time, bits), first_time),
+            expected
+        );
+    }
+}
+
+#[test]
+fn headers_chain_rejects_invalid() {
+    let chain = fixtures::regtest_chain(20);
+    let headers: Vec<_> = chain[1..].iter().map(|b| b.header).collect();
+    let mut header_chain = HeaderChain::new(Network::Regtest);
+    header_chain.connect(1, &headers[..10]).unwrap();
+
+    let mut bad_bits = headers[10];
+    bad_bits.bits = 0x207f_fffe;
+    let mut bad_pow = headers[10];
+    while bad_pow.validate_pow(&bad_pow.target()).is_ok() {
+        bad_pow.nonce += 1;
+    }
+    let mut bad_time = headers[10];
+    bad_time.time = headers[0].time;
+    fixtures::mine_header(&mut bad_time);
+
+    for bad in &[bad_bits, bad_pow, bad_time, headers[11]] {
+        assert!(header_chain.connect(11, &[*bad]).is_err());
+        assert_eq!(header_chain.tip_height(), 10);
+    }
+    // a failed reorg leaves the chain as it was
+    assert!(header_chain.connect(5, &[headers[4], bad_pow]).is_err());
+    assert_eq!(header_chain.get_hash(10), Some(chain[10].block_hash()));
+
+    header_chain.connect(11, &headers[10..]).unwrap();
+    assert_eq!(header_chain.tip_height(), 19);
+}
+
+/// A node serving a replaceable chain of blocks
+struct MockNode {
+    chain: Mutex<Vec<Block>>,
+    /// Serve this block no matter what was asked for
+    bogus_block: Mutex<Option<Block>>,
+}
+
+impl Rpc for MockNode {
+    type Data = Box<Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.chain.lock().unwrap().len() as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain.get(height as usize).map(Block::block_hash))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        if let Some(block) = self.bogus_block.lock().unwrap().clone() {
+            let prev = block.header.prev_blockhash;
+            return Ok(Some((Box::new(block), prev)));
+        }
+        let chain = self.chain.lock().unwrap();
+        Ok(chain
+            .iter()
+            .find(|b| b.block_hash() == *id)
+            .map(|b| (Box::new(b.clone()), b.header.prev_blockhash)))
+    }
+}
+
+impl HeaderRpc for MockNode {
+    fn get_headers_by_height(&self, start: BlockHeight, count: usize) -> Result<Vec<BlockHeader>> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain
+            .iter()
+            .skip(start as usize)
+            .take(count)
+            .map(|b| b.header)
+            .collect())
+    }
+}
+
+#[test]
+fn headers_first_follows_reorgs() {
+    let chain = fixtures::regtest_chain(30);
+    let node = MockNode {
+        chain: Mutex::new(chain.clone()),
+        bogus_block: Mutex::new(None),
+    };
+    let rpc = HeadersFirst::new(node, Network::Regtest);
+    assert_eq!(rpc.get_block_count().unwrap(), 29);
+    assert_eq!(
+        rpc.get_block_id_by_height(29).unwrap(),
+        Some(chain[29].block_hash())
+    );
+    let work_before = rpc.get_chainwork(29).unwrap();
+
+    let mut fork = chain[..20].to_vec();
+    fixtures::extend_chain(&mut fork, 15, 1);
+    *rpc.inner().chain.lock().unwrap() = fork.clone();
+
+    // noticed without downloading any block
+    assert_eq!(
+        rpc.get_block_id_by_height(30).unwrap(),
+        Some(fork[30].block_hash())
+    );
+    assert_eq!(
+        rpc.get_block_ids_by_heights(18, 4).unwrap(),
+        fixtures::chain_ids(&fork[18..22])
+    );
+    assert!(rpc.get_chainwork(34).unwrap() > work_before);
+
+    // blocks not matching the header chain are rejected
+    *rpc.inner().bogus_block.lock().unwrap() = Some(chain[25].clone());
+    assert!(rpc.get_block_by_id(&fork[25].block_hash()).is_err());
+}
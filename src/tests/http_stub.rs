@@ -0,0 +1,132 @@
+//! Minimal local HTTP server for testing http-based block sources
+use bitcoin::consensus::serialize;
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+};
+
+/// Status code and body
+pub type Response = (u16, Vec<u8>);
+
+/// Gets the `Authorization` header, the path and the body of a request
+type Handler = dyn Fn(Option<&str>, &str, &[u8]) -> Response;
+
+/// Serves requests with a handler, one thread per connection
+///
+/// The handler gets the path and the body of the request.
+pub struct HttpStub {
+    pub addr: SocketAddr,
+}
+
+impl HttpStub {
+    pub fn start(handler: impl Fn(&str, &[u8]) -> Response + Send + Sync + 'static) -> Self {
+        Self::start_with_auth(move |_authorization, path, body| handler(path, body))
+    }
+
+    /// Like `start`, but the handler also gets the `Authorization` header, if any
+    pub fn start_with_auth(
+        handler: impl Fn(Option<&str>, &str, &[u8]) -> Response + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    let _ = Self::serve(stream?, &*handler);
+                    std::io::Result::Ok(())
+                });
+            }
+        });
+
+        Self { addr }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    fn serve(mut stream: TcpStream, handler: &Handler) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_length = 0;
+        let mut authorization = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line == "\r\n" {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                } else if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.trim().to_owned());
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let path = request_line.split(' ').nth(1).unwrap_or("/");
+        let (status, body) = handler(authorization.as_deref(), path, &body);
+        write!(
+            stream,
+            "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        stream.flush()
+    }
+}
+
+/// Answer a JSON-RPC request (a single call or a batch) for `chain`
+pub fn core_jsonrpc(chain: &[bitcoin::Block], body: &[u8]) -> Response {
+    let request: Value = serde_json::from_slice(body).unwrap();
+    let response = match request {
+        Value::Array(calls) => calls.iter().map(|c| handle_call(chain, c)).collect(),
+        call => handle_call(chain, &call),
+    };
+    (200, serde_json::to_vec(&response).unwrap())
+}
+
+/// Answer a single JSON-RPC call the way Core would
+fn handle_call(chain: &[bitcoin::Block], call: &Value) -> Value {
+    let params = &call["params"];
+    let (result, error) = match call["method"].as_str().unwrap() {
+        "getblockcount" => (json!(chain.len() - 1), Value::Null),
+        // work of a regtest block is 2
+        "getblockchaininfo" => (
+            json!({
+                "blocks": chain.len() - 1,
+                "chainwork": format!("{:064x}", chain.len() * 2),
+            }),
+            Value::Null,
+        ),
+        "getblockhash" => match chain.get(params[0].as_u64().unwrap() as usize) {
+            Some(block) => (json!(block.block_hash().to_string()), Value::Null),
+            None => (
+                Value::Null,
+                json!({"code": -8, "message": "Block height out of range"}),
+            ),
+        },
+        "getblock" => match chain
+            .iter()
+            .find(|b| b.block_hash().to_string() == params[0].as_str().unwrap())
+        {
+            Some(block) => (json!(hex::encode(serialize(block))), Value::Null),
+            None => (
+                Value::Null,
+                json!({"code": -5, "message": "Block not found"}),
+            ),
+        },
+        method => panic!("unexpected call {}", method),
+    };
+    json!({"result": result, "error": error, "id": call["id"]})
+}
@@ -0,0 +1,105 @@
+use super::{
+    fixtures,
+    http_stub::{core_jsonrpc, HttpStub},
+};
+use crate::{node::fetcher, Rpc, RpcInfo};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Fetch the whole `chain` from a stub node with `rpc_from_url`
+///
+/// Returns the number of http requests made.
+fn fetch_chain_from_stub<R>(
+    chain: &[bitcoin::Block],
+    rpc_from_url: impl FnOnce(String) -> R,
+) -> usize
+where
+    R: Rpc<Id = bitcoin::BlockHash, Data = Box<bitcoin::Block>> + 'static,
+{
+    let http_requests = Arc::new(AtomicUsize::new(0));
+    let stub = HttpStub::start({
+        let chain = chain.to_vec();
+        let http_requests = http_requests.clone();
+        move |_path, body| {
+            http_requests.fetch_add(1, Ordering::SeqCst);
+            core_jsonrpc(&chain, body)
+        }
+    });
+
+    let rpc = rpc_from_url(stub.url());
+    let fetched: Vec<_> = fetcher::Fetcher::new(Arc::new(rpc), None, Some(chain.len() as u32 - 1))
+        .unwrap()
+        .map(|item| {
+            assert_eq!(item.data.block_hash(), item.id);
+            item.id
+        })
+        .collect();
+    assert_eq!(fetched, fixtures::chain_ids(chain));
+    http_requests.load(Ordering::SeqCst)
+}
+
+#[test]
+fn jsonrpc_batches_block_fetches() {
+    let chain = fixtures::regtest_chain(100);
+    let http_requests = fetch_chain_from_stub(&chain, |url| {
+        bitcoincore_rpc::Client::new(url, bitcoincore_rpc::Auth::None).unwrap()
+    });
+    // two round-trips per block without batching
+    assert!(http_requests < chain.len());
+
+    let http_requests = fetch_chain_from_stub(&chain, |url| {
+        RpcInfo::from_url(&url).unwrap().to_rpc_client().unwrap()
+    });
+    assert!(http_requests < chain.len());
+}
+
+#[test]
+fn jsonrpc_client_rereads_rotated_cookie() {
+    let chain = fixtures::regtest_chain(10);
+    let cookie = Arc::new(std::sync::Mutex::new(String::from("__cookie__:first")));
+    let stub = HttpStub::start_with_auth({
+        let cookie = cookie.clone();
+        move |authorization, _path, body| {
+            let expected = format!("Basic {}", base64::encode(&*cookie.lock().unwrap()));
+            if authorization != Some(expected.as_str()) {
+                return (401, vec![]);
+            }
+            core_jsonrpc(&chain, body)
+        }
+    });
+
+    let cookie_path = fixtures::temp_dir("cookie").join(".cookie");
+    std::fs::write(&cookie_path, "__cookie__:first").unwrap();
+    let rpc = RpcInfo::from_url(&format!("{}?cookie={}", stub.url(), cookie_path.display()))
+        .unwrap()
+        .to_rpc_client()
+        .unwrap();
+    assert_eq!(Rpc::get_block_count(&rpc).unwrap(), 9);
+    assert_eq!(rpc.get_block_id_by_height(10).unwrap(), None);
+
+    // node restarted
+    *cookie.lock().unwrap() = "__cookie__:second".into();
+    std::fs::write(&cookie_path, "__cookie__:second").unwrap();
+    assert_eq!(Rpc::get_block_count(&rpc).unwrap(), 9);
+}
+
+#[test]
+fn jsonrpc_client_times_out() {
+    let stub = HttpStub::start(|_path, _body| {
+        std::thread::sleep(Duration::from_secs(5));
+        (500, vec![])
+    });
+
+    let rpc = RpcInfo::from_url(&format!("{}?read_timeout=1", stub.url()))
+        .unwrap()
+        .to_rpc_client()
+        .unwrap();
+    let start = Instant::now();
+    assert!(Rpc::get_block_count(&rpc).is_err());
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
@@ -0,0 +1,237 @@
+//! Tests against a real Postgres, given with `DATABASE_URL`
+//!
+//! Skipped when it's not set. Tables are created in schemas of their own,
+//! but leftover prepared transactions are resolved database-wide, so don't
+//! point it at a database an indexer is using. The server needs
+//! `max_prepared_transactions` above zero.
+use crate::db::pg::{
+    commit_parallel_bulk_insert, resolve_prepared_transactions, BatchQuery, BinaryCopyFormatter,
+    PREPARED_TRANSACTION_PREFIX,
+};
+use bitcoin::util::uint::Uint256;
+use std::sync::Mutex;
+
+/// Tests resolving prepared transactions would interfere with each other
+static PREPARED_TRANSACTIONS: Mutex<()> = Mutex::new(());
+
+/// Connection to `DATABASE_URL`, using (recreated) schema `name`
+fn connect(name: &str) -> Option<postgres::Client> {
+    let url = std::env::var("DATABASE_URL").ok()?;
+    let mut conn = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    conn.batch_execute(&format!(
+        "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}; SET search_path TO {0};",
+        name
+    ))
+    .unwrap();
+    Some(conn)
+}
+
+/// Another connection, using existing schema `name`
+fn connect_again(name: &str) -> postgres::Client {
+    let url = std::env::var("DATABASE_URL").unwrap();
+    let mut conn = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    conn.batch_execute(&format!("SET search_path TO {}", name))
+        .unwrap();
+    conn
+}
+
+fn numbers(conn: &mut postgres::Client, table: &str) -> Vec<i32> {
+    conn.query(&*format!("SELECT n FROM {} ORDER BY n", table), &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+fn prepared_transactions(conn: &mut postgres::Client) -> Vec<String> {
+    conn.query(
+        "SELECT gid FROM pg_prepared_xacts WHERE database = current_database() AND starts_with(gid, $1)",
+        &[&PREPARED_TRANSACTION_PREFIX],
+    )
+    .unwrap()
+    .iter()
+    .map(|row| row.get(0))
+    .collect()
+}
+
+#[test]
+fn pg_binary_copy_round_trip() {
+    let mut conn = match connect("test_binary_copy") {
+        Some(conn) => conn,
+        None => return,
+    };
+    conn.batch_execute(
+        "CREATE TABLE copied (id INT, n NUMERIC, b BIGINT, t TEXT, x BYTEA, f BOOLEAN, z INT)",
+    )
+    .unwrap();
+
+    let u64_max = Uint256::from_u64(u64::MAX).unwrap();
+    let numerics = vec![
+        (Uint256::default(), "0"),
+        (Uint256::from_u64(1).unwrap(), "1"),
+        (Uint256::from_u64(9_999).unwrap(), "9999"),
+        (Uint256::from_u64(10_000).unwrap(), "10000"),
+        (Uint256::from_u64(10_001).unwrap(), "10001"),
+        (Uint256::from_u64(99_990_000).unwrap(), "99990000"),
+        (Uint256::from_u64(100_000_000).unwrap(), "100000000"),
+        (Uint256::from_u64(100_000_001).unwrap(), "100000001"),
+        (u64_max, "18446744073709551615"),
+        (u64_max * u64_max, "340282366920938463426481119284349108225"),
+        (
+            !Uint256::default(),
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935",
+        ),
+    ];
+
+    let mut formatter = BinaryCopyFormatter::new(
+        "copied",
+        "COPY copied (id, n, b, t, x, f, z) FROM STDIN (FORMAT binary)",
+    );
+    for (id, (n, _)) in numerics.iter().enumerate() {
+        formatter.fmt_with(|row| {
+            row.int(id as i32)
+                .numeric(*n)
+                .bigint(i64::MIN + id as i64)
+                .text("zażółć")
+                .bytea(&[0, 0xff, id as u8])
+                .boolean(id % 2 == 0)
+                .null();
+        });
+    }
+    let (table, query) = formatter.finish().unwrap();
+    assert_eq!(table, "copied");
+    query.execute(&mut conn).unwrap();
+
+    let rows = conn
+        .query(
+            "SELECT id, n::text, b, t, x, f, z FROM copied ORDER BY id",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(rows.len(), numerics.len());
+    for (id, (row, (_, n))) in rows.iter().zip(&numerics).enumerate() {
+        assert_eq!(row.get::<_, i32>(0), id as i32);
+        assert_eq!(row.get::<_, String>(1), *n);
+        assert_eq!(row.get::<_, i64>(2), i64::MIN + id as i64);
+        assert_eq!(row.get::<_, String>(3), "zażółć");
+        assert_eq!(row.get::<_, Vec<u8>>(4), vec![0, 0xff, id as u8]);
+        assert_eq!(row.get::<_, bool>(5), id % 2 == 0);
+        assert_eq!(row.get::<_, Option<i32>>(6), None);
+    }
+
+    // nothing to copy
+    assert!(BinaryCopyFormatter::new("copied", "").finish().is_none());
+}
+
+#[test]
+fn pg_parallel_bulk_insert_is_atomic() {
+    let _lock = PREPARED_TRANSACTIONS.lock().unwrap();
+    let schema = "test_parallel_insert";
+    let mut conn = match connect(schema) {
+        Some(conn) => conn,
+        None => return,
+    };
+    conn.batch_execute(
+        "CREATE TABLE block (n INT); CREATE TABLE tx (n INT); CREATE TABLE indexer_commit (batch TEXT NOT NULL);",
+    )
+    .unwrap();
+    let mut table_conns = vec![("tx", connect_again(schema))];
+    let batch = format!("{}test_1_", PREPARED_TRANSACTION_PREFIX);
+
+    commit_parallel_bulk_insert(
+        &mut conn,
+        &mut table_conns,
+        &batch,
+        vec![
+            ("tx", BatchQuery::Sql("INSERT INTO tx VALUES (1)".into())),
+            (
+                "block",
+                BatchQuery::Sql("INSERT INTO block VALUES (1)".into()),
+            ),
+        ],
+    )
+    .unwrap();
+    assert_eq!(numbers(&mut conn, "tx"), vec![1]);
+    assert_eq!(numbers(&mut conn, "block"), vec![1]);
+
+    // one of the writers fails: nothing is written
+    let batch = format!("{}test_2_", PREPARED_TRANSACTION_PREFIX);
+    assert!(commit_parallel_bulk_insert(
+        &mut conn,
+        &mut table_conns,
+        &batch,
+        vec![
+            ("tx", BatchQuery::Sql("INSERT INTO tx VALUES ('x')".into())),
+            (
+                "block",
+                BatchQuery::Sql("INSERT INTO block VALUES (2)".into())
+            ),
+        ],
+    )
+    .is_err());
+    assert_eq!(numbers(&mut conn, "tx"), vec![1]);
+    assert_eq!(numbers(&mut conn, "block"), vec![1]);
+    assert_eq!(prepared_transactions(&mut conn), Vec::<String>::new());
+
+    // and the connections are still usable
+    let batch = format!("{}test_3_", PREPARED_TRANSACTION_PREFIX);
+    commit_parallel_bulk_insert(
+        &mut conn,
+        &mut table_conns,
+        &batch,
+        vec![
+            ("tx", BatchQuery::Sql("INSERT INTO tx VALUES (3)".into())),
+            (
+                "block",
+                BatchQuery::Sql("INSERT INTO block VALUES (3)".into()),
+            ),
+        ],
+    )
+    .unwrap();
+    assert_eq!(numbers(&mut conn, "tx"), vec![1, 3]);
+    assert_eq!(numbers(&mut conn, "block"), vec![1, 3]);
+}
+
+#[test]
+fn pg_resolves_leftover_prepared_transactions() {
+    let _lock = PREPARED_TRANSACTIONS.lock().unwrap();
+    let schema = "test_prepared_recovery";
+    let mut conn = match connect(schema) {
+        Some(conn) => conn,
+        None => return,
+    };
+    conn.batch_execute(
+        "CREATE TABLE block (n INT); CREATE TABLE tx (n INT); CREATE TABLE indexer_commit (batch TEXT NOT NULL);",
+    )
+    .unwrap();
+
+    // a crash after all transactions of batch 1 were prepared (and that was
+    // recorded), but before they were committed; batch 2 wasn't fully prepared
+    let prepare = |gid: &str, sql: &str| {
+        connect_again(schema)
+            .batch_execute(&format!(
+                "BEGIN; {}; PREPARE TRANSACTION '{}{}'",
+                sql, PREPARED_TRANSACTION_PREFIX, gid
+            ))
+            .expect("needs max_prepared_transactions > 0");
+    };
+    prepare("test_1_tx", "INSERT INTO tx VALUES (1)");
+    prepare("test_1_chain", "INSERT INTO block VALUES (1)");
+    prepare("test_2_tx", "INSERT INTO tx VALUES (2)");
+    conn.execute(
+        "INSERT INTO indexer_commit (batch) VALUES ($1)",
+        &[&format!("{}test_1_", PREPARED_TRANSACTION_PREFIX)],
+    )
+    .unwrap();
+    assert_eq!(prepared_transactions(&mut conn).len(), 3);
+
+    resolve_prepared_transactions(&mut conn).unwrap();
+    assert_eq!(prepared_transactions(&mut conn), Vec::<String>::new());
+    assert_eq!(numbers(&mut conn, "tx"), vec![1]);
+    assert_eq!(numbers(&mut conn, "block"), vec![1]);
+    let committing = conn
+        .query("SELECT batch FROM indexer_commit", &[])
+        .unwrap()
+        .len();
+    assert_eq!(committing, 0);
+}
@@ -0,0 +1,203 @@
+use super::{
+    fixtures,
+    http_stub::{core_jsonrpc, HttpStub},
+};
+use crate::node::{any::AnyRpc, fetcher, pool::RpcPool};
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::util::uint::Uint256;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+/// A node with a fixed chain of `usize` ids; block data is the id itself
+#[derive(Default)]
+struct MockNode {
+    chain: Mutex<Vec<usize>>,
+    failing: AtomicBool,
+    blocks_served: AtomicUsize,
+    /// Chainwork to report, if any
+    work: Option<u64>,
+}
+
+impl MockNode {
+    fn new(chain: Vec<usize>) -> Self {
+        Self {
+            chain: Mutex::new(chain),
+            ..default()
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.failing.load(Ordering::SeqCst) {
+            bail!("node is down");
+        }
+        Ok(())
+    }
+}
+
+impl Rpc for Arc<MockNode> {
+    type Data = usize;
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        self.check()?;
+        Ok(self.chain.lock().unwrap().len() as BlockHeight - 1)
+    }
+
+    fn get_block_count_and_work(&self) -> Result<(BlockHeight, Option<Uint256>)> {
+        let work = self.work.map(|work| Uint256::from_u64(work).unwrap());
+        Ok((self.get_block_count()?, work))
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        self.check()?;
+        Ok(self.chain.lock().unwrap().get(height as usize).cloned())
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        self.check()?;
+        let chain = self.chain.lock().unwrap();
+        Ok(chain.iter().position(|i| i == id).map(|height| {
+            self.blocks_served.fetch_add(1, Ordering::SeqCst);
+            (*id, chain[height.saturating_sub(1)])
+        }))
+    }
+}
+
+fn pool(nodes: &[Arc<MockNode>]) -> Arc<RpcPool<Arc<MockNode>>> {
+    Arc::new(
+        RpcPool::new(
+            nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| (format!("node{}", i), node.clone()))
+                .collect(),
+        )
+        .unwrap(),
+    )
+}
+
+fn fetch_all(pool: Arc<RpcPool<Arc<MockNode>>>) -> Vec<usize> {
+    let end = pool.get_block_count().unwrap();
+    fetcher::Fetcher::new(pool, None, Some(end))
+        .unwrap()
+        .map(|item| {
+            assert_eq!(item.data, item.id);
+            item.id
+        })
+        .collect()
+}
+
+#[test]
+fn pool_spreads_block_fetches() {
+    let chain: Vec<usize> = (0..200).collect();
+    let nodes: Vec<_> = (0..3)
+        .map(|_| Arc::new(MockNode::new(chain.clone())))
+        .collect();
+
+    assert_eq!(fetch_all(pool(&nodes)), chain);
+    for node in &nodes {
+        assert!(node.blocks_served.load(Ordering::SeqCst) > 0);
+    }
+}
+
+#[test]
+fn pool_fails_over_to_healthy_nodes() {
+    let chain: Vec<usize> = (0..100).collect();
+    let nodes: Vec<_> = (0..2)
+        .map(|_| Arc::new(MockNode::new(chain.clone())))
+        .collect();
+    nodes[0].failing.store(true, Ordering::SeqCst);
+
+    let pool = pool(&nodes);
+    assert_eq!(fetch_all(pool.clone()), chain);
+    assert!(!pool.is_healthy(0));
+    assert!(pool.is_healthy(1));
+    assert_eq!(nodes[0].blocks_served.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn pool_follows_a_single_canonical_chain() {
+    // node1 is on a longer fork; node0 lacks its blocks entirely
+    let base: Vec<usize> = (0..50).collect();
+    let short: Vec<_> = base.iter().cloned().chain(50..60).collect();
+    let long: Vec<_> = base.iter().cloned().chain(1050..1070).collect();
+    let nodes = vec![
+        Arc::new(MockNode::new(short)),
+        Arc::new(MockNode::new(long.clone())),
+    ];
+
+    let pool = pool(&nodes);
+    assert_eq!(pool.get_block_count().unwrap(), 69);
+    assert_eq!(fetch_all(pool), long);
+}
+
+#[test]
+fn pool_follows_the_most_work_chain() {
+    // node1 is on a longer fork, but node0's has more work
+    let base: Vec<usize> = (0..50).collect();
+    let short: Vec<_> = base.iter().cloned().chain(50..60).collect();
+    let long: Vec<_> = base.iter().cloned().chain(1050..1070).collect();
+    let nodes = vec![
+        Arc::new(MockNode {
+            work: Some(2000),
+            ..MockNode::new(short.clone())
+        }),
+        Arc::new(MockNode {
+            work: Some(1000),
+            ..MockNode::new(long)
+        }),
+    ];
+
+    let pool = pool(&nodes);
+    assert_eq!(pool.get_block_count().unwrap(), 59);
+    assert_eq!(fetch_all(pool), short);
+}
+
+#[test]
+fn pool_keeps_lagging_node_healthy() {
+    // Core answers `getblock` for blocks it doesn't have with an error
+    let chain = fixtures::regtest_chain(30);
+    let stubs: Vec<_> = vec![chain.clone(), chain[..10].to_vec()]
+        .into_iter()
+        .map(|chain| HttpStub::start(move |_path, body| core_jsonrpc(&chain, body)))
+        .collect();
+    let pool = RpcPool::new(
+        stubs
+            .iter()
+            .enumerate()
+            .map(|(i, stub)| {
+                let rpc =
+                    bitcoincore_rpc::Client::new(stub.url(), bitcoincore_rpc::Auth::None).unwrap();
+                (format!("node{}", i), rpc)
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    assert_eq!(pool.get_block_count().unwrap(), 29);
+    // block fetches go round-robin, so node1 gets asked for the ones it lacks
+    for id in fixtures::chain_ids(&chain) {
+        let (block, _prev_id) = pool.get_block_by_id(&id).unwrap().unwrap();
+        assert_eq!(block.block_hash(), id);
+    }
+    assert!(pool.is_healthy(0));
+    assert!(pool.is_healthy(1));
+}
+
+#[test]
+fn pool_retry_delays_fit_every_member() {
+    let delays = |urls| {
+        let pool = RpcPool::<AnyRpc>::from_url_list(urls).unwrap();
+        (pool.head_retry_delay_ms(), pool.error_retry_delay_ms())
+    };
+    assert_eq!(delays("http://127.0.0.1:1"), (2000, 100));
+    // a public esplora instance is not to be polled as often as own node
+    assert_eq!(
+        delays("http://127.0.0.1:1,esplora+http://127.0.0.1:2"),
+        (10_000, 1000)
+    );
+}
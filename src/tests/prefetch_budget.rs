@@ -0,0 +1,47 @@
+use crate::node::prefetcher::Prefetcher;
+use crate::{prelude::*, BlockHeight, DataSize, Rpc};
+use std::{sync::Arc, time::Duration};
+
+/// A node with a fixed chain of `len` blocks
+struct MockNode {
+    len: usize,
+}
+
+impl Rpc for MockNode {
+    type Data = usize;
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.len as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(Some(height as usize).filter(|&h| h < self.len))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        Ok(Some((*id, id.saturating_sub(1))).filter(|_| *id < self.len))
+    }
+}
+
+#[test]
+fn prefetcher_stays_within_byte_budget() {
+    let block_size = 0usize.data_size();
+    let mut prefetcher = Prefetcher::new(Arc::new(MockNode { len: 2000 }), None).unwrap();
+    let budget = prefetcher.byte_budget();
+    budget.set_limit(10 * block_size);
+
+    // workers only overshoot by what they were fetching when the limit was hit
+    let max_overshoot = num_cpus::get() * 2 * MockNode::RECOMMENDED_BATCH_SIZE * block_size;
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(budget.used() <= budget.limit() + max_overshoot);
+
+    for height in 0..2000 {
+        let block = prefetcher.next().unwrap();
+        assert_eq!(block.height, height);
+        assert!(budget.used() <= budget.limit() + max_overshoot);
+    }
+    assert_eq!(budget.used(), 0);
+}
@@ -0,0 +1,159 @@
+use crate::node::prefetcher::{HashLookup, Prefetcher};
+use crate::{prelude::*, BlockHeight, Rpc, WithHeightAndId};
+use std::sync::{Arc, Mutex};
+
+/// A node with a chain of `usize` ids, switchable at will
+#[derive(Default)]
+struct MockNode {
+    chain: Mutex<Vec<usize>>,
+}
+
+impl MockNode {
+    /// Replace all blocks from `height` up with `len` new ones
+    fn reorg(&self, height: usize, len: usize) {
+        let mut chain = self.chain.lock().unwrap();
+        chain.truncate(height);
+        chain.extend((height..height + len).map(|h| 1_000_000 + h));
+    }
+}
+
+impl Rpc for MockNode {
+    type Data = ();
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.chain.lock().unwrap().len() as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(self.chain.lock().unwrap().get(height as usize).cloned())
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain
+            .iter()
+            .position(|i| i == id)
+            .map(|height| ((), chain[height.saturating_sub(1)])))
+    }
+}
+
+/// Ids of indexed blocks, standing in for `IndexerStore`
+type Indexed = Arc<Mutex<Vec<usize>>>;
+
+fn lookup(indexed: &Indexed) -> HashLookup<usize> {
+    let indexed = indexed.clone();
+    Box::new(move |height| Ok(indexed.lock().unwrap().get(height as usize).cloned()))
+}
+
+/// Index blocks until caught up with the `node`
+///
+/// Returns the lowest height returned by the `prefetcher`.
+fn index_until_synced(
+    prefetcher: &mut Prefetcher<MockNode>,
+    node: &MockNode,
+    indexed: &Indexed,
+) -> BlockHeight {
+    let mut lowest = BlockHeight::MAX;
+    while *indexed.lock().unwrap() != *node.chain.lock().unwrap() {
+        let item = prefetcher.next().unwrap();
+        lowest = lowest.min(item.height);
+        let mut indexed = indexed.lock().unwrap();
+        assert!(item.height as usize <= indexed.len());
+        indexed.truncate(item.height as usize);
+        indexed.push(item.id);
+    }
+    lowest
+}
+
+#[test]
+fn prefetcher_handles_reorg_deeper_than_window() {
+    let node = Arc::new(MockNode {
+        chain: Mutex::new((0..1500).collect()),
+    });
+    let indexed = Indexed::default();
+    let mut prefetcher =
+        Prefetcher::new_with_hash_lookup(node.clone(), None, Some(lookup(&indexed))).unwrap();
+    index_until_synced(&mut prefetcher, &node, &indexed);
+
+    node.reorg(200, 1400);
+    assert_eq!(index_until_synced(&mut prefetcher, &node, &indexed), 200);
+}
+
+#[test]
+fn prefetcher_finds_fork_point_on_restart() {
+    let node = Arc::new(MockNode {
+        chain: Mutex::new((0..1500).collect()),
+    });
+    let indexed: Indexed = Arc::new(Mutex::new(node.chain.lock().unwrap().clone()));
+
+    node.reorg(300, 1300);
+    let start = WithHeightAndId {
+        height: 1499,
+        id: 1499,
+        data: (),
+    };
+    let mut prefetcher =
+        Prefetcher::new_with_hash_lookup(node.clone(), Some(start), Some(lookup(&indexed)))
+            .unwrap();
+    assert_eq!(index_until_synced(&mut prefetcher, &node, &indexed), 300);
+}
+
+#[test]
+fn prefetcher_stops_on_reorg_deeper_than_window_without_lookup() {
+    let node = Arc::new(MockNode {
+        chain: Mutex::new((0..1500).collect()),
+    });
+    let indexed = Indexed::default();
+    let mut prefetcher = Prefetcher::new(node.clone(), None).unwrap();
+    index_until_synced(&mut prefetcher, &node, &indexed);
+
+    // the fork point is below the remembered blocks, and there's no way to check it
+    node.reorg(200, 1400);
+    for item in &mut prefetcher {
+        assert!(item.height >= 1500, "returned {}H", item.height);
+    }
+    let err = prefetcher.take_error().unwrap().to_string();
+    assert!(
+        err.contains("Reorg deeper than the known blocks"),
+        "{}",
+        err
+    );
+    assert!(prefetcher.next().is_none());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_prefetcher_ends_on_reorg_deeper_than_window() {
+    use crate::node::async_prefetcher::{AsyncPrefetcher, BlockingRpc};
+    use futures::StreamExt;
+
+    let node = Arc::new(MockNode {
+        chain: Mutex::new((0..1500).collect()),
+    });
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let rpc = Arc::new(BlockingRpc::new(node.clone()));
+        let mut prefetcher = AsyncPrefetcher::new(rpc, None).await.unwrap();
+        for height in 0..1500 {
+            assert_eq!(prefetcher.next().await.unwrap().height, height);
+        }
+
+        node.reorg(200, 1400);
+        while let Some(item) = prefetcher.next().await {
+            assert!(item.height >= 1500, "returned {}H", item.height);
+        }
+        let err = prefetcher.take_error().unwrap().to_string();
+        assert!(
+            err.contains("Reorg deeper than the known blocks"),
+            "{}",
+            err
+        );
+        assert!(prefetcher.next().await.is_none());
+    });
+}
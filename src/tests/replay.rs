@@ -0,0 +1,195 @@
+use super::fixtures;
+use crate::{
+    node::{
+        prefetcher::Prefetcher,
+        replay::{Recorder, RecordingRpc, ReplayRpc},
+    },
+    prelude::*,
+    BlockHeight, Rpc, RpcInfo, RpcKind,
+};
+use bitcoin::{Block, BlockHash, Network};
+use std::sync::{Arc, Mutex};
+
+/// A node with a chain of real blocks, switchable at will
+struct ChainNode {
+    chain: Mutex<Vec<Block>>,
+}
+
+impl Rpc for Arc<ChainNode> {
+    type Data = Box<Block>;
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 0;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.chain.lock().unwrap().len() as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(self
+            .chain
+            .lock()
+            .unwrap()
+            .get(height as usize)
+            .map(Block::block_hash))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        Ok(self
+            .chain
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|block| block.block_hash() == *id)
+            .map(|block| (Box::new(block.clone()), block.header.prev_blockhash)))
+    }
+}
+
+/// Index blocks from `prefetcher` until they match `chain`
+///
+/// Returns the lowest height returned by the `prefetcher`.
+fn index_until<R>(
+    prefetcher: &mut Prefetcher<R>,
+    indexed: &mut Vec<BlockHash>,
+    chain: &[Block],
+) -> BlockHeight
+where
+    R: Rpc<Id = BlockHash, Data = Box<Block>> + 'static,
+{
+    let chain = fixtures::chain_ids(chain);
+    let mut lowest = BlockHeight::MAX;
+    while *indexed != chain {
+        let item = prefetcher.next().unwrap();
+        assert_eq!(item.data.block_hash(), item.id);
+        lowest = lowest.min(item.height);
+        indexed.truncate(item.height as usize);
+        indexed.push(item.id);
+    }
+    lowest
+}
+
+/// Chain `a`, and chain `b` forking off it at `fork` height
+fn forked_chains(a_len: usize, fork: usize, b_len: usize) -> (Vec<Block>, Vec<Block>) {
+    let a = fixtures::regtest_chain(a_len);
+    let mut b = a[..fork].to_vec();
+    fixtures::extend_chain(&mut b, b_len - fork, 1);
+    (a, b)
+}
+
+#[test]
+fn replay_serves_recorded_reorg() {
+    let (chain_a, chain_b) = forked_chains(30, 22, 35);
+    let path = fixtures::temp_dir("record").join("node.rec");
+
+    let node = Arc::new(ChainNode {
+        chain: Mutex::new(chain_a.clone()),
+    });
+    {
+        let rpc = RecordingRpc::new(node.clone(), Network::Regtest, &path).unwrap();
+        let mut prefetcher = Prefetcher::new(Arc::new(rpc), None).unwrap();
+        let mut indexed = vec![];
+        index_until(&mut prefetcher, &mut indexed, &chain_a);
+        *node.chain.lock().unwrap() = chain_b.clone();
+        assert!(index_until(&mut prefetcher, &mut indexed, &chain_b) <= 22);
+    }
+
+    // the client sees a different interleaving of node's answers, but ends up
+    // with the same chain
+    let replay = Arc::new(ReplayRpc::open(&path).unwrap());
+    assert_eq!(replay.network(), Network::Regtest);
+    assert_eq!(replay.get_block_count().unwrap(), 29);
+    let mut prefetcher = Prefetcher::new(replay.clone(), None).unwrap();
+    index_until(&mut prefetcher, &mut vec![], &chain_b);
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn replay_follows_scripted_steps() {
+    // reorg to a shorter chain goes unnoticed until it grows past the old tip
+    let (chain_a, chain_b) = forked_chains(20, 12, 18);
+    let mut chain_c = chain_b.clone();
+    fixtures::extend_chain(&mut chain_c, 5, 1);
+
+    let path = fixtures::temp_dir("script").join("node.rec");
+    let mut recorder = Recorder::create(&path, Network::Regtest).unwrap();
+    recorder.record_chain(0, &chain_a).unwrap();
+    recorder.record_chain(12, &chain_b[12..]).unwrap();
+    recorder.record_chain(18, &chain_c[18..]).unwrap();
+    recorder.flush().unwrap();
+
+    let replay = ReplayRpc::open(&path).unwrap();
+    assert_eq!(replay.get_block_count().unwrap(), 19);
+    assert_eq!(
+        replay.get_block_id_by_height(19).unwrap(),
+        Some(chain_a[19].block_hash())
+    );
+    // the tip block wasn't fetched yet
+    assert_eq!(replay.get_block_id_by_height(20).unwrap(), None);
+    replay.get_block_by_id(&chain_a[19].block_hash()).unwrap();
+    // skips the shorter chain, which the client could not see
+    assert_eq!(
+        replay.get_block_id_by_height(20).unwrap(),
+        Some(chain_c[20].block_hash())
+    );
+    assert_eq!(replay.get_block_count().unwrap(), 22);
+    assert_eq!(
+        replay.get_block_id_by_height(19).unwrap(),
+        Some(chain_c[19].block_hash())
+    );
+
+    let replay = Arc::new(ReplayRpc::open(&path).unwrap());
+    let mut prefetcher = Prefetcher::new(replay.clone(), None).unwrap();
+    index_until(&mut prefetcher, &mut vec![], &chain_c);
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn replay_ignores_truncated_last_record() {
+    let chain = fixtures::regtest_chain(5);
+    let path = fixtures::temp_dir("truncated").join("node.rec");
+    let mut recorder = Recorder::create(&path, Network::Regtest).unwrap();
+    recorder.record_chain(0, &chain[..4]).unwrap();
+    recorder.record_chain(4, &chain[4..]).unwrap();
+    recorder.flush().unwrap();
+
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(len - 10)
+        .unwrap();
+
+    let replay = ReplayRpc::open(&path).unwrap();
+    assert_eq!(replay.get_block_count().unwrap(), 3);
+    let (block, prev) = replay
+        .get_block_by_id(&chain[3].block_hash())
+        .unwrap()
+        .unwrap();
+    assert_eq!(*block, chain[3]);
+    assert_eq!(prev, chain[2].block_hash());
+
+    let tip = replay.get_block_id_by_height(4).unwrap().unwrap();
+    assert_eq!(tip, chain[4].block_hash());
+    assert_eq!(replay.get_block_count().unwrap(), 4);
+    assert!(replay.get_block_by_id(&tip).is_err());
+}
+
+#[test]
+fn replay_url() {
+    let path = fixtures::temp_dir("replay-url").join("node.rec");
+    let mut recorder = Recorder::create(&path, Network::Testnet).unwrap();
+    recorder
+        .record_chain(0, &fixtures::regtest_chain(3))
+        .unwrap();
+    recorder.flush().unwrap();
+
+    let info = RpcInfo::from_url(&format!("replay:{}", path.display())).unwrap();
+    assert_eq!(info.kind, RpcKind::Replay);
+    let rpc = info.to_any_rpc().unwrap();
+    assert_eq!(rpc.get_network().unwrap(), Network::Testnet);
+    assert_eq!(rpc.get_block_count().unwrap(), 2);
+
+    assert!(RpcInfo::from_url(&format!("rest+replay:{}", path.display())).is_err());
+    assert!(RpcInfo::from_url(&format!("replay:{}?cookie=x", path.display())).is_err());
+}
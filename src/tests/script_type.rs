@@ -0,0 +1,126 @@
+use crate::util::bitcoin::ScriptType;
+use bitcoin::blockdata::{opcodes::all::*, script::Builder};
+use bitcoin::Script;
+
+fn pubkey(len: usize) -> Vec<u8> {
+    let mut key = vec![7; len];
+    key[0] = if len == 65 { 4 } else { 2 };
+    key
+}
+
+fn multisig(m: i64, keys: &[Vec<u8>], n: i64) -> Script {
+    keys.iter()
+        .fold(Builder::new().push_int(m), |b, key| b.push_slice(key))
+        .push_int(n)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script()
+}
+
+#[test]
+fn script_type_standard() {
+    let cases = vec![
+        (
+            Builder::new()
+                .push_slice(&pubkey(33))
+                .push_opcode(OP_CHECKSIG)
+                .into_script(),
+            ScriptType::P2pk,
+        ),
+        (
+            Builder::new()
+                .push_slice(&pubkey(65))
+                .push_opcode(OP_CHECKSIG)
+                .into_script(),
+            ScriptType::P2pk,
+        ),
+        (
+            Builder::new()
+                .push_opcode(OP_DUP)
+                .push_opcode(OP_HASH160)
+                .push_slice(&[1; 20])
+                .push_opcode(OP_EQUALVERIFY)
+                .push_opcode(OP_CHECKSIG)
+                .into_script(),
+            ScriptType::P2pkh,
+        ),
+        (
+            Builder::new()
+                .push_opcode(OP_HASH160)
+                .push_slice(&[1; 20])
+                .push_opcode(OP_EQUAL)
+                .into_script(),
+            ScriptType::P2sh,
+        ),
+        (
+            Builder::new()
+                .push_int(0)
+                .push_slice(&[1; 20])
+                .into_script(),
+            ScriptType::P2wpkh,
+        ),
+        (
+            Builder::new()
+                .push_int(0)
+                .push_slice(&[1; 32])
+                .into_script(),
+            ScriptType::P2wsh,
+        ),
+        (
+            Builder::new()
+                .push_int(1)
+                .push_slice(&[1; 32])
+                .into_script(),
+            ScriptType::P2tr,
+        ),
+        (
+            multisig(1, &[pubkey(33), pubkey(65)], 2),
+            ScriptType::Multisig,
+        ),
+        (
+            Builder::new()
+                .push_opcode(OP_RETURN)
+                .push_slice(b"hello")
+                .into_script(),
+            ScriptType::Nulldata,
+        ),
+        (
+            Builder::new().push_opcode(OP_RETURN).into_script(),
+            ScriptType::Nulldata,
+        ),
+    ];
+    for (script, expected) in cases {
+        assert_eq!(ScriptType::from_script(&script), expected, "{}", script);
+    }
+}
+
+#[test]
+fn script_type_nonstandard() {
+    let cases = vec![
+        Script::new(),
+        Builder::new().push_int(1).into_script(),
+        // witness v1 program of a length other than taproot's
+        Builder::new()
+            .push_int(1)
+            .push_slice(&[1; 20])
+            .into_script(),
+        // OP_RETURN followed by a non-push
+        Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_CHECKSIG)
+            .into_script(),
+        // m above n
+        multisig(3, &[pubkey(33), pubkey(33)], 2),
+        // n not matching the keys
+        multisig(1, &[pubkey(33), pubkey(33)], 3),
+        // not a key
+        multisig(1, &[vec![2; 20]], 1),
+    ];
+    for script in cases {
+        assert_eq!(
+            ScriptType::from_script(&script),
+            ScriptType::Nonstandard,
+            "{}",
+            script
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use crate::node::prefetcher::Prefetcher;
+use crate::{prelude::*, BlockHeight, Rpc};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A node with a fixed chain of `len` blocks, slow to poll at the tip
+struct MockNode {
+    len: usize,
+}
+
+impl Rpc for MockNode {
+    type Data = ();
+    type Id = usize;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 60_000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.len as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(Some(height as usize).filter(|&h| h < self.len))
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        Ok(Some(((), id.saturating_sub(1))).filter(|_| *id < self.len))
+    }
+}
+
+#[test]
+fn prefetcher_stops_waiting_at_tip_on_shutdown() {
+    let mut prefetcher = Prefetcher::new(Arc::new(MockNode { len: 10 }), None).unwrap();
+    for height in 0..10 {
+        assert_eq!(prefetcher.next().unwrap().height, height);
+    }
+
+    let shutdown = prefetcher.shutdown_handle();
+    let signal = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        shutdown.shutdown();
+    });
+    let start = Instant::now();
+    assert!(prefetcher.next().is_none());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(prefetcher.next().is_none());
+    signal.join().unwrap();
+}
+
+#[test]
+fn prefetcher_stops_in_the_middle_of_sync_on_shutdown() {
+    let mut prefetcher = Prefetcher::new(Arc::new(MockNode { len: 100_000 }), None).unwrap();
+    let shutdown = prefetcher.shutdown_handle();
+    for height in 0..100 {
+        assert_eq!(prefetcher.next().unwrap().height, height);
+    }
+    shutdown.shutdown();
+    assert!(prefetcher.next().is_none());
+}
@@ -0,0 +1,71 @@
+use super::fixtures::*;
+use crate::{verify::verify_block, BlockData, WithHeightAndId};
+use bitcoin::blockdata::{script::Builder, transaction::TxOut};
+use bitcoin::Block;
+
+fn block_data(block: Block) -> BlockData {
+    WithHeightAndId {
+        height: 1,
+        id: block.block_hash(),
+        data: Box::new(block),
+    }
+}
+
+fn verify(block: &BlockData) -> crate::prelude::Result<()> {
+    verify_block(block, block.data.txdata.iter().map(|tx| tx.txid()))
+}
+
+#[test]
+fn verify_accepts_valid_block() {
+    let chain = regtest_chain(3);
+    verify(&block_data(chain[2].clone())).unwrap();
+}
+
+#[test]
+fn verify_rejects_block_with_wrong_id() {
+    let chain = regtest_chain(3);
+    let mut block = block_data(chain[2].clone());
+    block.id = chain[1].block_hash();
+    let err = verify(&block).unwrap_err().to_string();
+    assert!(err.contains("header hashes to"), "{}", err);
+}
+
+#[test]
+fn verify_rejects_block_with_modified_txs() {
+    let chain = regtest_chain(3);
+    let mut block = block_data(chain[2].clone());
+    block.data.txdata[0].output[0].value += 1;
+    let err = verify(&block).unwrap_err().to_string();
+    assert!(err.contains("merkle root"), "{}", err);
+}
+
+#[test]
+fn verify_rejects_block_with_duplicated_txs() {
+    // CVE-2012-2459: [cb, a, b] and [cb, a, b, b] have the same merkle root
+    let chain = regtest_chain(2);
+    let txs = vec![coinbase(2, 0), coinbase(3, 1), coinbase(4, 2)];
+    let mut block = block_data(mine_block(&chain[1], txs));
+    verify(&block).unwrap();
+
+    let last = block.data.txdata[2].clone();
+    block.data.txdata.push(last);
+    assert_eq!(block.data.merkle_root(), block.data.header.merkle_root);
+    let err = verify(&block).unwrap_err().to_string();
+    assert!(err.contains("mutated"), "{}", err);
+}
+
+#[test]
+fn verify_rejects_block_with_wrong_witness_commitment() {
+    let chain = regtest_chain(2);
+    let mut coinbase = coinbase(2, 0);
+    coinbase.input[0].witness = vec![vec![0; 32]];
+    let mut commitment = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+    commitment.extend_from_slice(&[0xff; 32]);
+    coinbase.output.push(TxOut {
+        value: 0,
+        script_pubkey: Builder::from(commitment).into_script(),
+    });
+    let block = block_data(mine_block(&chain[1], vec![coinbase]));
+    let err = verify(&block).unwrap_err().to_string();
+    assert!(err.contains("witness commitment"), "{}", err);
+}
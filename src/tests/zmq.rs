@@ -0,0 +1,183 @@
+use super::fixtures;
+use crate::node::{
+    prefetcher::Prefetcher,
+    zmq::{
+        Notification, SequenceEvent, ZmqSubscriber, TOPIC_HASHBLOCK, TOPIC_RAWTX, TOPIC_SEQUENCE,
+    },
+};
+use crate::{prelude::*, BlockHeight, Rpc};
+use bitcoin::{consensus::serialize, hashes::Hash, BlockHash};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Local stand-in for Core's ZMQ publisher
+struct Publisher {
+    _ctx: ::zmq::Context,
+    socket: ::zmq::Socket,
+    endpoint: String,
+}
+
+impl Publisher {
+    fn new() -> Self {
+        let ctx = ::zmq::Context::new();
+        let socket = ctx.socket(::zmq::PUB).unwrap();
+        socket.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = socket.get_last_endpoint().unwrap().unwrap();
+        Self {
+            _ctx: ctx,
+            socket,
+            endpoint,
+        }
+    }
+
+    fn send(&self, topic: &str, body: &[u8], seq: u32) {
+        self.socket
+            .send_multipart([topic.as_bytes(), body, &seq.to_le_bytes()[..]].iter(), 0)
+            .unwrap();
+    }
+}
+
+/// Hash in the byte order Core uses in ZMQ messages
+fn zmq_hash<H: Hash>(hash: H) -> Vec<u8> {
+    let mut bytes = hash[..].to_vec();
+    bytes.reverse();
+    bytes
+}
+
+#[test]
+fn zmq_notifications_are_parsed() {
+    let publisher = Publisher::new();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let _subscriber = ZmqSubscriber::new(
+        &publisher.endpoint,
+        &[TOPIC_HASHBLOCK, TOPIC_RAWTX, TOPIC_SEQUENCE],
+        move |n| tx.send(n).unwrap(),
+    )
+    .unwrap();
+
+    let chain = fixtures::regtest_chain(2);
+    let block_hash = chain[1].block_hash();
+
+    // messages sent before the subscriber connects are lost, so keep
+    // publishing until one gets through
+    let mut seq = 0;
+    loop {
+        publisher.send(TOPIC_HASHBLOCK, &zmq_hash(block_hash), seq);
+        seq += 1;
+        if let Ok(n) = rx.recv_timeout(Duration::from_millis(100)) {
+            assert!(matches!(n, Notification::Block(h) if h == block_hash));
+            break;
+        }
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    while rx.try_recv().is_ok() {}
+
+    let coinbase = &chain[1].txdata[0];
+    publisher.send(TOPIC_RAWTX, &serialize(coinbase), 0);
+    let mut added = zmq_hash(coinbase.txid());
+    added.push(b'A');
+    added.extend_from_slice(&7u64.to_le_bytes());
+    publisher.send(TOPIC_SEQUENCE, &added, 0);
+    let mut connected = zmq_hash(block_hash);
+    connected.push(b'C');
+    publisher.send(TOPIC_SEQUENCE, &connected, 1);
+    // skip a couple of `hashblock` messages
+    publisher.send(TOPIC_HASHBLOCK, &zmq_hash(block_hash), seq + 2);
+
+    let recv = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(matches!(recv(), Notification::RawTx(tx) if *tx == *coinbase));
+    assert!(matches!(
+        recv(),
+        Notification::Sequence(SequenceEvent::TxAdded { txid, mempool_seq: 7 })
+            if txid == coinbase.txid()
+    ));
+    let n = recv();
+    assert!(n.is_block_related());
+    assert!(matches!(
+        n,
+        Notification::Sequence(SequenceEvent::BlockConnected(h)) if h == block_hash
+    ));
+    assert!(matches!(recv(), Notification::Missed { topic } if topic == TOPIC_HASHBLOCK));
+    assert!(matches!(recv(), Notification::Block(h) if h == block_hash));
+}
+
+/// Node that would make the prefetcher wait for a long time for new blocks
+#[derive(Default)]
+struct SlowTipNode {
+    chain: Mutex<Vec<BlockHash>>,
+}
+
+impl Rpc for SlowTipNode {
+    type Data = ();
+    type Id = BlockHash;
+    const RECOMMENDED_HEAD_RETRY_DELAY_MS: u64 = 60_000;
+    const RECOMMENDED_ERROR_RETRY_DELAY_MS: u64 = 0;
+
+    fn get_block_count(&self) -> Result<BlockHeight> {
+        Ok(self.chain.lock().unwrap().len() as BlockHeight - 1)
+    }
+
+    fn get_block_id_by_height(&self, height: BlockHeight) -> Result<Option<Self::Id>> {
+        Ok(self.chain.lock().unwrap().get(height as usize).cloned())
+    }
+
+    fn get_block_by_id(&self, id: &Self::Id) -> Result<Option<(Self::Data, Self::Id)>> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain
+            .iter()
+            .position(|i| i == id)
+            .map(|height| ((), chain[height.saturating_sub(1)])))
+    }
+}
+
+#[test]
+fn zmq_hashblock_wakes_up_prefetcher() {
+    let mut chain = fixtures::regtest_chain(2);
+    let node = Arc::new(SlowTipNode {
+        chain: Mutex::new(fixtures::chain_ids(&chain)),
+    });
+    let mut prefetcher = Prefetcher::new(node.clone(), None).unwrap();
+    assert_eq!(prefetcher.next().unwrap().height, 0);
+    assert_eq!(prefetcher.next().unwrap().height, 1);
+
+    let publisher = Publisher::new();
+    let tip_notify = prefetcher.tip_notify();
+    let _subscriber = ZmqSubscriber::new(&publisher.endpoint, &[TOPIC_HASHBLOCK], move |n| {
+        if n.is_block_related() {
+            tip_notify.notify()
+        }
+    })
+    .unwrap();
+
+    // give the workers time to go to sleep waiting for the next block
+    std::thread::sleep(Duration::from_millis(500));
+    fixtures::extend_chain(&mut chain, 1, 1);
+    let new_hash = chain[2].block_hash();
+    node.chain.lock().unwrap().push(new_hash);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let publishing = std::thread::spawn({
+        let done = done.clone();
+        move || {
+            let mut seq = 0;
+            while !done.load(Ordering::SeqCst) {
+                publisher.send(TOPIC_HASHBLOCK, &zmq_hash(new_hash), seq);
+                seq += 1;
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let item = prefetcher.next().unwrap();
+    done.store(true, Ordering::SeqCst);
+    publishing.join().unwrap();
+    assert_eq!(item.height, 2);
+    assert_eq!(item.id, new_hash);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
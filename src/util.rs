@@ -7,6 +7,7 @@ pub struct BottleCheck {
     accumulated: std::time::Duration,
     last: std::time::Instant,
     name: String,
+    status: Option<Box<dyn Fn() -> String + Send>>,
 }
 
 impl BottleCheck {
@@ -15,8 +16,15 @@ impl BottleCheck {
             name,
             accumulated: Duration::default(),
             last: Instant::now(),
+            status: None,
         }
     }
+
+    /// Extra information to log along with the bottleneck
+    pub fn set_status(&mut self, status: impl Fn() -> String + Send + 'static) {
+        self.status = Some(Box::new(status));
+    }
+
     pub fn check<T>(&mut self, f: impl FnOnce() -> T) -> T {
         let start = Instant::now();
         let res = f();
@@ -30,7 +38,10 @@ impl BottleCheck {
             self.accumulated += duration;
         }
         if self.accumulated > Duration::from_secs(30) {
-            info!("Bottleneck: {}", self.name);
+            match &self.status {
+                Some(status) => info!("Bottleneck: {}; {}", self.name, status()),
+                None => info!("Bottleneck: {}", self.name),
+            }
             self.accumulated = Duration::default();
         }
         self.last = end;
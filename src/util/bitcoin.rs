@@ -1,5 +1,8 @@
 use crate::prelude::*;
-use bitcoin::util::address;
+use bitcoin::{
+    blockdata::{opcodes, script::Instruction},
+    util::{address, uint::Uint256},
+};
 
 pub fn address_from_script(
     script: &bitcoin::blockdata::script::Script,
@@ -8,10 +11,139 @@ pub fn address_from_script(
     address::Payload::from_script(script).map(|payload| address::Address { payload, network })
 }
 
+/// Kind of an output script, as Bitcoin Core's `Solver` tells them apart
+///
+/// Unlike `address_from_script`, it can tell apart outputs that have no address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Multisig,
+    Nulldata,
+    Nonstandard,
+}
+
+impl ScriptType {
+    pub fn from_script(script: &bitcoin::Script) -> Self {
+        let bytes = script.as_bytes();
+        if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_v0_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_v0_p2wsh() {
+            ScriptType::P2wsh
+        } else if bytes.len() == 34
+            && bytes[0] == opcodes::all::OP_PUSHNUM_1.into_u8()
+            && bytes[1] == opcodes::all::OP_PUSHBYTES_32.into_u8()
+        {
+            ScriptType::P2tr
+        } else if script.is_op_return() && is_push_only(&bytes[1..]) {
+            ScriptType::Nulldata
+        } else if script.is_p2pk() {
+            ScriptType::P2pk
+        } else if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if is_multisig(script) {
+            ScriptType::Multisig
+        } else {
+            ScriptType::Nonstandard
+        }
+    }
+
+    /// Name, as in `script_type` db enum
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::Multisig => "multisig",
+            ScriptType::Nulldata => "nulldata",
+            ScriptType::Nonstandard => "nonstandard",
+        }
+    }
+}
+
+impl std::fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// `OP_1`..`OP_16` as a number
+fn small_int(instruction: &Instruction) -> Option<u8> {
+    match instruction {
+        Instruction::Op(op)
+            if (opcodes::all::OP_PUSHNUM_1.into_u8()..=opcodes::all::OP_PUSHNUM_16.into_u8())
+                .contains(&op.into_u8()) =>
+        {
+            Some(op.into_u8() - opcodes::all::OP_PUSHNUM_1.into_u8() + 1)
+        }
+        _ => None,
+    }
+}
+
+/// Only pushes (including `OP_0`..`OP_16` and `OP_1NEGATE`)
+fn is_push_only(bytes: &[u8]) -> bool {
+    bitcoin::Script::from(bytes.to_vec())
+        .instructions()
+        .all(|instruction| match instruction {
+            Ok(Instruction::PushBytes(_)) => true,
+            Ok(Instruction::Op(op)) => op.into_u8() <= opcodes::all::OP_PUSHNUM_16.into_u8(),
+            Err(_) => false,
+        })
+}
+
+/// `OP_m <pubkey>... OP_n OP_CHECKMULTISIG`
+fn is_multisig(script: &bitcoin::Script) -> bool {
+    let instructions: Vec<_> = match script.instructions().collect::<std::result::Result<_, _>>() {
+        Ok(instructions) => instructions,
+        Err(_) => return false,
+    };
+    if instructions.len() < 4
+        || instructions.last() != Some(&Instruction::Op(opcodes::all::OP_CHECKMULTISIG))
+    {
+        return false;
+    }
+    let keys = &instructions[1..instructions.len() - 2];
+    match (
+        small_int(&instructions[0]),
+        small_int(&instructions[instructions.len() - 2]),
+    ) {
+        (Some(m), Some(n)) => {
+            m <= n
+                && usize::from(n) == keys.len()
+                && keys.iter().all(|key| match key {
+                    Instruction::PushBytes(key) => key.len() == 33 || key.len() == 65,
+                    _ => false,
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Parse chainwork as reported by Core (big-endian hex)
+pub fn chainwork_from_hex(s: &str) -> Result<Uint256> {
+    let bytes = hex::decode(s)?;
+    if bytes.len() != 32 {
+        bail!("Invalid chainwork {}", s);
+    }
+    let mut be = [0u8; 32];
+    be.copy_from_slice(&bytes);
+    Ok(Uint256::from_be_bytes(be))
+}
+
 pub fn network_from_str(s: &str) -> Result<bitcoin::Network> {
     Ok(match s {
         "main" => bitcoin::Network::Bitcoin,
         "test" => bitcoin::Network::Testnet,
+        "signet" => bitcoin::Network::Signet,
         "regtest" => bitcoin::Network::Regtest,
         _ => bail!("Unknown bitcoin chain {}", s),
     })
@@ -0,0 +1,84 @@
+//! Block integrity checks
+use crate::{prelude::*, BlockData, Txid};
+use bitcoin::{
+    hash_types::TxMerkleNode,
+    hashes::{sha256d, Hash, HashEngine},
+};
+
+/// Check that the `block` is what its id claims it is
+///
+/// Checks the header hash against the block id, the merkle root against the txs
+/// (rejecting mutated merkle trees, CVE-2012-2459), and the witness commitment,
+/// if any txs have witness data.
+///
+/// `txids` are ids of all the txs in the `block`, in order, so they don't
+/// need to be calculated again.
+pub fn verify_block(block: &BlockData, txids: impl ExactSizeIterator<Item = Txid>) -> Result<()> {
+    let header_hash = block.data.block_hash();
+    if header_hash != block.id {
+        bail!(
+            "Block {}H {}: header hashes to {}",
+            block.height,
+            block.id,
+            header_hash
+        );
+    }
+
+    if block.data.txdata.is_empty() {
+        bail!("Block {}H {}: no txs", block.height, block.id);
+    }
+    assert_eq!(txids.len(), block.data.txdata.len());
+
+    let (merkle_root, mutated) = merkle_root(txids.map(|txid| txid.as_hash()).collect());
+    if merkle_root != block.data.header.merkle_root {
+        bail!(
+            "Block {}H {}: txs merkle root {} doesn't match {} in the header",
+            block.height,
+            block.id,
+            merkle_root,
+            block.data.header.merkle_root
+        );
+    }
+    if mutated {
+        bail!(
+            "Block {}H {}: merkle tree is mutated (duplicate txs)",
+            block.height,
+            block.id
+        );
+    }
+
+    if !block.data.check_witness_commitment() {
+        bail!(
+            "Block {}H {}: witness commitment doesn't match txs",
+            block.height,
+            block.id
+        );
+    }
+
+    Ok(())
+}
+
+/// Merkle root of `hashes`, and whether the tree is mutated
+///
+/// Port of Core's `ComputeMerkleRoot`. The last hash of an odd level is paired
+/// with itself, so repeating the tail txs of a block gives the same root as
+/// the real block. Any two identical siblings mark such a tree.
+fn merkle_root(mut hashes: Vec<sha256d::Hash>) -> (TxMerkleNode, bool) {
+    let mut mutated = false;
+    while hashes.len() > 1 {
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| {
+                let (left, right) = (pair[0], *pair.last().expect("chunks aren't empty"));
+                if pair.len() == 2 && left == right {
+                    mutated = true;
+                }
+                let mut engine = sha256d::Hash::engine();
+                engine.input(&left[..]);
+                engine.input(&right[..]);
+                sha256d::Hash::from_engine(engine)
+            })
+            .collect();
+    }
+    (hashes[0].into(), mutated)
+}